@@ -16,10 +16,11 @@ let b:current_syntax = "jack"
 " syn keyword jackKeyword fun nextgroup=jackIdent skipwhite
 " syn keyword jackKeyword #define nextgroup=jackIdent skipwhite
 " syn keyword jackKeyword const nextgroup=jackType,jackArray,jackCustomType skipwhite
-syn keyword jackIntrinsic print printc println readln drop push pop get set len
-syn keyword jackKeyword macro if else switch typeswitch while default dowhile times do done loop for cmperr
+syn keyword jackIntrinsic print printc println readln drop push pop get set len put has remove keys
+syn keyword jackIntrinsic concat strlen substr charat split trim upper lower contains startswith indexof
+syn keyword jackKeyword macro outer fn record extern virtual new as if else switch typeswitch while default dowhile times do done loop for try catch throw break continue cmperr
 syn keyword jackStackOp swap drop dup dupx1
-syn keyword jackType    list anylist int string any
+syn keyword jackType    list map anylist int bool long double string any
 syn keyword jackBool    true false
 
 " taken from https://github.com/vim/vim/blob/master/runtime/syntax/c.vim
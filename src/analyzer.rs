@@ -1,19 +1,29 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::Display,
+    rc::Rc,
+};
 
 use klex::Loc;
 use thiserror::Error;
 
 use crate::{
-    ast::{AstBase, AstItem, AstNode, MatchInType, MatchOutType},
-    opcodes, parser,
+    ast::{AstBase, AstItem, AstNode, ExternKind, ExternType, MatchInType, MatchOutType},
+    diagnostics::{Diagnostic, Note, Span},
+    ir::Opcode,
+    opcodes, optimizer, parser,
 };
 
 #[derive(Error, Debug)]
 pub enum AnalyzerErr {
     #[error("type error: {0} at {1}")]
-    TypeErr(String, Loc),
-    #[error("compiler error: {0}")]
-    CompilerErr(String, Loc),
+    TypeErr(String, Span),
+    #[error("compiler error: {0} at {1}")]
+    CompilerErr(String, Span),
+    /// An error with secondary information about other places in the source
+    #[error("{0}")]
+    WithNotes(Box<AnalyzerErr>, Vec<Note>),
 }
 
 #[derive(Clone, Debug)]
@@ -22,12 +32,55 @@ pub struct AstAnalysis {
     pub vars: HashMap<String, LocalVar>,
     pub max_stack_size: usize,
     pub max_vars_count: usize,
+    /// Signatures of all user-defined functions
+    pub functions: HashMap<String, Signature>,
+    /// The fields of all records, in the order they are declared in
+    pub records: HashMap<String, Vec<(String, Type)>>,
+    /// Counts how often the stack became unknown: when a node failed to analyze, its stack is
+    /// cleared, and popping from the cleared stack yields `Type::Unknown`. Stacks are only
+    /// compared if they were poisoned equally often, so that errors don't cascade.
+    pub poison: usize,
+    /// All errors so far, shared between an analysis and the sub-analyses cloned from it
+    pub errors: Rc<RefCell<Vec<AnalyzerErr>>>,
+    /// All warnings so far, shared like `errors`
+    pub warnings: Rc<RefCell<Vec<Diagnostic>>>,
+    pub reachability: Reachability,
+    /// The loops around the code that is analyzed, innermost last
+    pub loops: Vec<LoopExits>,
+}
+
+/// What `break` and `continue` have to leave on the stack in a loop
+#[derive(Clone, Debug)]
+pub struct LoopExits {
+    /// The stack after the loop
+    pub break_types: Vec<Type>,
+    /// The stack at the end of the body
+    pub continue_types: Vec<Type>,
+    /// How often the stack was poisoned when the loop started, see [`AstAnalysis::has_types`]
+    pub poison: usize,
+    /// Whether a `break` leaves the loop, shared with the sub-analyses of its branches
+    pub broken: Rc<Cell<bool>>,
+    /// Whether a `continue` skips the rest of the body, shared like `broken`
+    pub continued: Rc<Cell<bool>>,
+}
+
+/// Whether the code that is analyzed next can ever run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reachability {
+    Reachable,
+    /// After a loop that never ends, `break`, `continue` or `throw`; the reason is given in the
+    /// warning
+    Unreachable(&'static str),
+    /// Unreachable, and a warning has already been issued
+    Reported,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct LocalVar {
     pub index: usize,
     pub elem: StackElement,
+    /// Where the variable was stored to first
+    pub defined_at: Span,
 }
 
 #[derive(Clone, PartialEq)]
@@ -36,74 +89,155 @@ pub struct StackElement {
     pub value: Option<Value>,
 }
 
+/// The stack effect of a user-defined function
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub input: Vec<Type>,
+    pub output: Vec<Type>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     Int,
+    /// Produced by comparisons and required by conditions. Like in Java, it is an `int` on the JVM.
+    Bool,
+    /// A 64-bit integer. Takes up two slots on the JVM's stack and in its local variables
+    Long,
+    /// Takes up two slots on the JVM's stack and in its local variables
+    Double,
     String,
     List(Box<Type>),
+    /// A `HashMap` from keys of the first type to values of the second
+    Map(Box<Type>, Box<Type>),
     Object(String),
+    /// An instance of a record, compiled to a class with the record's name
+    Record(String),
+    /// The type of values that come out of a node that failed to analyze. It matches every other
+    /// type, so that one error doesn't cause a lot of follow-up errors.
+    Unknown,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Int(i32),
+    Bool(bool),
+    Long(i64),
+    Double(f64),
     String(String),
     List(Vec<Option<Value>>),
 }
 
 impl AstBase {
-    pub fn analyze(&mut self, debug: bool) -> Result<AstAnalysis, AnalyzerErr> {
+    /// Analyzes all nodes, even after errors. Returns every error that was found.
+    pub fn analyze(&mut self, debug: bool) -> Result<AstAnalysis, Vec<AnalyzerErr>> {
         let mut analyzer = AstAnalysis::new();
-        for node in &mut self.nodes {
-            node.analyze(&mut analyzer, debug)?;
-        }
-        if analyzer.stack.is_empty() {
-            Ok(analyzer)
-        } else {
-            Err(AnalyzerErr::TypeErr(
+        self.analyze_with(&mut analyzer, debug)?;
+        if !analyzer.stack.is_empty() && analyzer.poison == 0 {
+            return Err(vec![AnalyzerErr::TypeErr(
                 format!(
                     "stack is not empty when programm finishes but has {:?}!",
                     analyzer.stack
                 ),
                 self.nodes
                     .last()
-                    .map(|n| n.loc)
-                    .unwrap_or(Loc::start_of_file(self.file_index))
-                    .into(),
-            ))
+                    .map(|n| n.span())
+                    .unwrap_or(Loc::start_of_file(self.file_index).into()),
+            )]);
         }
+        Ok(analyzer)
     }
-}
 
-macro_rules! analyse_head {
-    ($self:expr, $head:expr, $sub_analysis:expr, $debug:expr) => {
-        if let Some(head) = $head {
-            // `head` is getting its own `stack` and `vars` here
-            head.analyze(&mut $sub_analysis, $debug)?;
-            // but we have to update `self.stack` and `self.vars`
-            $self.stack = Some($sub_analysis.stack.clone());
-            $self.vars = Some($sub_analysis.vars.clone());
+    /// Analyzes all nodes on top of the stack, variables and functions of `analyzer`, which may
+    /// come from earlier code, like previous lines in the REPL
+    pub fn analyze_with(&mut self, analyzer: &mut AstAnalysis, debug: bool) -> Result<(), Vec<AnalyzerErr>> {
+        // register all records and functions first so that they may be used before their
+        // definition
+        for node in &self.nodes {
+            if let AstItem::Record { name, fields } = &node.inner {
+                if analyzer.records.insert(name.clone(), fields.clone()).is_some() {
+                    analyzer.error(AnalyzerErr::TypeErr(
+                        format!("record {name} is defined more than once"),
+                        node.span(),
+                    ));
+                }
+            }
+        }
+        for node in &self.nodes {
+            // externs are called like functions
+            let (name, signature) = match &node.inner {
+                AstItem::Function { name, signature, .. } => (name, signature.clone()),
+                AstItem::Extern { name, input, output, .. } => (name, Signature::of_extern(input, output)),
+                _ => continue,
+            };
+            if analyzer.functions.insert(name.clone(), signature).is_some() {
+                analyzer.error(AnalyzerErr::TypeErr(
+                    format!("function {name} is defined more than once"),
+                    node.span(),
+                ));
+            } else if analyzer.records.contains_key(name) {
+                analyzer.error(AnalyzerErr::TypeErr(
+                    format!("function {name} has the same name as a record"),
+                    node.span(),
+                ));
+            }
+        }
+        for node in &mut self.nodes {
+            analyzer.check_reachable(node);
+            node.analyze(analyzer, debug);
         }
-    };
+        let errors = analyzer.errors.take();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
 }
 
 impl AstNode {
+    /// Errors are collected in `analysis`. After an error, analysis carries on with a poisoned
+    /// stack.
     /// TODO: a lot of const analysis can be done here
-    pub fn analyze(&mut self, analysis: &mut AstAnalysis, debug: bool) -> Result<(), AnalyzerErr> {
+    pub fn analyze(&mut self, analysis: &mut AstAnalysis, debug: bool) {
         self.stack = Some(analysis.stack.clone());
         self.vars = Some(analysis.vars.clone());
+        if let Err(e) = self.analyze_item(analysis, debug) {
+            analysis.error(e);
+            analysis.poison();
+        }
+        self.stack_after = Some(analysis.stack.clone());
+        self.vars_after = Some(analysis.vars.clone());
+        if debug {
+            println!(
+                "stack after `{}` at {}:\n\t{:?}",
+                self.inner.short_spelling(),
+                self.span(),
+                analysis.types().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    fn analyze_item(&mut self, analysis: &mut AstAnalysis, debug: bool) -> Result<(), AnalyzerErr> {
         match &mut self.inner {
             AstItem::PushInt(n) => analysis.push(Type::Int, Some(Value::Int(*n))),
+            AstItem::PushBool(b) => analysis.push(Type::Bool, Some(Value::Bool(*b))),
+            AstItem::PushLong(n) => analysis.push(Type::Long, Some(Value::Long(*n))),
+            AstItem::PushDouble(d) => analysis.push(Type::Double, Some(Value::Double(*d))),
             AstItem::PushString(s) => analysis.push(Type::String, Some(Value::String(s.clone()))),
-            AstItem::List(_) => self.item_list(analysis),
+            AstItem::List(_) => self.item_list(analysis)?,
+            AstItem::Map(..) => self.item_map(analysis)?,
             AstItem::ListLiteral(_) => self.item_list_literal(analysis, debug)?,
             AstItem::If { .. } => self.item_if(analysis, debug)?,
             AstItem::Switch { .. } => self.item_switch(analysis, debug)?,
             AstItem::While { .. } => self.item_while(analysis, debug)?,
             AstItem::For { .. } => self.item_for(analysis, debug)?,
+            AstItem::Try { .. } => self.item_try(analysis, debug)?,
+            AstItem::Throw => self.item_throw(analysis)?,
+            AstItem::Break | AstItem::Continue => self.item_loop_exit(analysis)?,
             AstItem::Block(children) => {
                 for c in children {
-                    c.analyze(analysis, debug)?;
+                    analysis.check_reachable(c);
+                    c.analyze(analysis, debug);
                 }
             }
             AstItem::Store { .. } => self.item_store(analysis, debug)?,
@@ -111,29 +245,39 @@ impl AstNode {
             AstItem::Jasmin { .. } => self.item_jasmin(analysis)?,
             AstItem::TypeSwitch { .. } => self.item_type_switch(analysis)?,
             AstItem::CmpErr(msg) => {
-                return Err(AnalyzerErr::CompilerErr(format!("{msg} at {}\n\tstack: {:?}", self.loc, analysis.types().collect::<Vec<_>>()), self.loc))
+                return Err(AnalyzerErr::CompilerErr(format!("{msg}\n\tstack: {:?}", analysis.types().collect::<Vec<_>>()), self.span()))
             }
-        }
-        if debug {
-            println!(
-                "stack after `{}` at {}:\n\t{:?}",
-                self.inner.short_spelling(),
-                self.loc,
-                analysis.types().collect::<Vec<_>>()
-            );
+            AstItem::Function { .. } => self.item_function(analysis, debug)?,
+            AstItem::Call { .. } => self.item_call(analysis)?,
+            AstItem::Record { .. } => self.item_record(analysis)?,
+            AstItem::New { .. } => self.item_new(analysis)?,
+            AstItem::GetField { .. } => self.item_get_field(analysis)?,
+            AstItem::SetField { .. } => self.item_set_field(analysis, debug)?,
+            AstItem::Extern { .. } => self.item_extern(analysis)?,
         }
         Ok(())
     }
 
-    fn item_list(&self, analysis: &mut AstAnalysis) {
+    fn item_list(&self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
         let AstItem::List(ref ty) = self.inner else {
             unreachable!();
         };
-        analysis.push(
-            Type::List(Box::new(ty.clone())),
-            Some(Value::List(Vec::new())),
-        );
+        let ty = Type::List(Box::new(ty.clone()));
+        analysis.check_type(&ty, self.span())?;
+        analysis.push(ty, Some(Value::List(Vec::new())));
         analysis.require_additional_stack_size(1);
+        Ok(())
+    }
+
+    fn item_map(&self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+        let AstItem::Map(ref key, ref value) = self.inner else {
+            unreachable!();
+        };
+        let ty = Type::Map(Box::new(key.clone()), Box::new(value.clone()));
+        analysis.check_type(&ty, self.span())?;
+        analysis.push(ty, None);
+        analysis.require_additional_stack_size(1);
+        Ok(())
     }
 
     fn item_list_literal(
@@ -141,78 +285,102 @@ impl AstNode {
         analysis: &mut AstAnalysis,
         debug: bool,
     ) -> Result<(), AnalyzerErr> {
+        let span = self.span();
         let AstItem::ListLiteral(ref mut nodes) = self.inner else {
             unreachable!();
         };
         if nodes.is_empty() {
             return Err(AnalyzerErr::TypeErr(
                 "empty `List` literal has unknown type, use `list<type>`!".into(),
-                self.loc,
+                span,
             ));
         }
         let mut sub_analysis = analysis.clone();
-        nodes[0].analyze(&mut sub_analysis, debug)?;
+        nodes[0].analyze(&mut sub_analysis, debug);
         let ty = sub_analysis
             .expect_any(
                 "item 0 doesn't result in anything in list literal",
-                self.loc,
+                span,
             )?
             .ty;
-        if analysis.types().ne(sub_analysis.types()) {
-            return Err(AnalyzerErr::TypeErr("items in list literal may not alter the stack except pushing their element. (Error in elemtent 0)".into(), self.loc));
+        if !analysis.same_types(&sub_analysis) {
+            return Err(AnalyzerErr::TypeErr("items in list literal may not alter the stack except pushing their element. (Error in elemtent 0)".into(), span));
         }
         analysis.max_max_values_with(&sub_analysis);
 
         for (i, node) in nodes.iter_mut().skip(1).enumerate() {
-            node.analyze(&mut sub_analysis, debug)?;
+            node.analyze(&mut sub_analysis, debug);
             analysis.max_max_values_with(&sub_analysis);
             sub_analysis.expect(
                 &ty,
                 format!("expected {ty:?} in element {i} of list literal (inferred type {ty:?})"),
-                self.loc,
+                span,
             )?;
-            if sub_analysis.types().ne(analysis.types()) {
-                return Err(AnalyzerErr::TypeErr("items in list literal may not alter the stack except pushing their element. (Error in elemtent {i})".into(), self.loc));
+            if !sub_analysis.same_types(analysis) {
+                return Err(AnalyzerErr::TypeErr("items in list literal may not alter the stack except pushing their element. (Error in elemtent {i})".into(), span));
             }
         }
 
         analysis.push(Type::List(Box::new(ty)), None);
-        // code gen has to know the type of list
-        self.stack = Some(analysis.stack.clone());
         // FIXME: this has to be incremented for each further nesting, how come???
         analysis.require_additional_stack_size(4);
         Ok(())
     }
 
     fn item_if(&mut self, analysis: &mut AstAnalysis, debug: bool) -> Result<(), AnalyzerErr> {
+        let span = self.span();
         let AstItem::If {head, body, else_body} = &mut self.inner else {
             unreachable!();
         };
         // don't leak local variables into outer scope
         let mut sub_analysis = analysis.clone();
-        analyse_head!(self, head, sub_analysis, debug);
-        sub_analysis.expect(
-            &Type::Int,
-            "expected Int (implicit boolean) on stack for `If`-condition",
-            self.loc,
-        )?;
+        if let Some(head) = head {
+            head.analyze(&mut sub_analysis, debug);
+        }
+        let condition = sub_analysis.expect(
+            &Type::Bool,
+            "expected Bool on stack for `If`-condition",
+            span,
+        )?.value;
+        match (&condition, &else_body) {
+            (Some(Value::Bool(false)), _) => analysis.warn("unreachable code: this `if` body never runs, its condition is always false", body.span()),
+            (Some(Value::Bool(true)), Some(else_body)) => analysis.warn("unreachable code: this `else` never runs, the condition of its `if` is always true", else_body.span()),
+            _ => {}
+        }
         analysis.stack = sub_analysis.stack.clone();
-        body.analyze(&mut sub_analysis, debug)?;
+        analysis.poison = sub_analysis.poison;
+        body.analyze(&mut sub_analysis, debug);
+        // without `else`, the code after the `if` is reached if the condition is false
+        let mut else_reachability = analysis.reachability;
         if let Some(else_body) = else_body {
             let mut else_body_analysis = analysis.clone();
-            else_body.analyze(&mut else_body_analysis, debug)?;
-            if sub_analysis.types().ne(else_body_analysis.types()) {
+            else_body.analyze(&mut else_body_analysis, debug);
+            else_reachability = else_body_analysis.reachability;
+            if !sub_analysis.same_types(&else_body_analysis) {
                 return Err(AnalyzerErr::TypeErr(format!(
                     "`if` and `else` don't alter the stack the same way:\n\t`if` results in {:?}({})\n\t`else` results in {:?}({})",
                     sub_analysis.types().collect::<Vec<_>>(),
                     sub_analysis.types().len(),
                     else_body_analysis.types().collect::<Vec<_>>(),
                     else_body_analysis.types().len(),
-                ), self.loc));
+                ), span)
+                .with_note(
+                    format!("the `if` branch leaves this stack: {:?}", sub_analysis.types().collect::<Vec<_>>()),
+                    body.span(),
+                )
+                .with_note(
+                    format!("the `else` branch leaves this stack: {:?}", else_body_analysis.types().collect::<Vec<_>>()),
+                    else_body.span(),
+                ));
             }
             analysis.max_max_values_with(&else_body_analysis);
+            if sub_analysis.poison > else_body_analysis.poison {
+                // the `else` branch knows more about the stack
+                sub_analysis.stack = else_body_analysis.stack;
+                sub_analysis.poison = else_body_analysis.poison;
+            }
         } else {
-            if sub_analysis.types().ne(analysis.types()) {
+            if !sub_analysis.same_types(analysis) {
                 return Err(AnalyzerErr::TypeErr(
                     format!(
                         "`if` alters the stack but has no `else`\n\tstack before `if`: {:?}({})\n\tstack after `if`-body: {:?}({})",
@@ -221,33 +389,55 @@ impl AstNode {
                         sub_analysis.types().collect::<Vec<_>>(),
                         sub_analysis.types().len(),
                     ),
-                    self.loc,
+                    span,
+                )
+                .with_note(
+                    format!("the `if` branch leaves this stack: {:?}", sub_analysis.types().collect::<Vec<_>>()),
+                    body.span(),
                 ));
             }
         }
         analysis.max_max_values_with(&sub_analysis);
-        analysis.stack = sub_analysis.stack;
+        // without `else`, the stack stays the same, whatever went wrong in the body
+        if else_body.is_some() {
+            analysis.stack = sub_analysis.stack;
+            analysis.poison = sub_analysis.poison;
+        }
+        analysis.reachability = match condition {
+            Some(Value::Bool(false)) => else_reachability,
+            Some(Value::Bool(true)) => sub_analysis.reachability,
+            _ => sub_analysis.reachability.min(else_reachability),
+        };
         analysis.forget_const_values();
+        analysis.forget_var_values();
         Ok(())
     }
 
     fn item_switch(&mut self, analysis: &mut AstAnalysis, debug: bool) -> Result<(), AnalyzerErr> {
+        let span = self.span();
         let AstItem::Switch {arms, default} = &mut self.inner else {
             unreachable!();
         };
 
         arms.sort_by_key(|(n, _)| *n);
-        analysis.expect(&Type::Int, "`switch` requires an `Int` on stack!", self.loc)?;
+        analysis.expect(&Type::Int, "`switch` requires an `Int` on stack!", span)?;
         let mut sub_analysis = analysis.clone();
         let stack_before_arms = analysis.stack.clone();
         let vars_before_arms = analysis.vars.clone();
-        default.analyze(&mut sub_analysis, debug)?;
+        let reachability_before_arms = analysis.reachability;
+        default.analyze(&mut sub_analysis, debug);
         let mut expected_stack = sub_analysis.clone();
+        let mut reachability = sub_analysis.reachability;
         for (_, body) in arms {
             sub_analysis.stack = stack_before_arms.clone();
             sub_analysis.vars = vars_before_arms.clone();
-            body.analyze(&mut sub_analysis, debug)?;
-            if sub_analysis.types().ne(expected_stack.types()) {
+            sub_analysis.reachability = reachability_before_arms;
+            body.analyze(&mut sub_analysis, debug);
+            reachability = reachability.min(sub_analysis.reachability);
+            if expected_stack.poison > sub_analysis.poison {
+                // compare with the arm that knows more about the stack instead
+                expected_stack = sub_analysis.clone();
+            } else if !sub_analysis.same_types(&expected_stack) {
                 return Err(AnalyzerErr::TypeErr(format!(
                     "`switch`-arms don't alter the stack the same way\n\tdefault branch results in {:?}({})\n\tarm at {} results in {:?}({})",
                     expected_stack.types().collect::<Vec<_>>(),
@@ -255,55 +445,101 @@ impl AstNode {
                     body.loc,
                     sub_analysis.types().collect::<Vec<_>>(),
                     sub_analysis.types().len(),
-                ), self.loc));
+                ), span)
+                .with_note(
+                    format!("this arm leaves this stack: {:?}", sub_analysis.types().collect::<Vec<_>>()),
+                    body.span(),
+                ));
             }
         }
         expected_stack.vars = vars_before_arms;
+        expected_stack.reachability = reachability;
+        // `sub_analysis` went through all arms
+        expected_stack.max_max_values_with(&sub_analysis);
         *analysis = expected_stack;
+        analysis.forget_const_values();
+        analysis.forget_var_values();
         Ok(())
     }
 
     fn item_while(&mut self, analysis: &mut AstAnalysis, debug: bool) -> Result<(), AnalyzerErr> {
+        let span = self.span();
         let AstItem::While { head, body } = &mut self.inner else {
             unreachable!();
         };
         // don't leak local variables into outer scope
         let vars = analysis.vars.clone();
+        let reachability = analysis.reachability;
+        // the body may change what the head sees in later iterations
+        analysis.forget_const_values();
+        analysis.forget_var_values();
         if let Some(head) = head {
-            // `head` is getting its own `stack` and `vars` here
-            head.analyze(analysis, debug)?;
-            // but we have to update `self.stack` and `self.vars`
-            self.stack = Some(analysis.stack.clone());
-            self.vars = Some(analysis.vars.clone());
-        }
-        analysis.expect(
-            &Type::Int,
-            "expected Int (implicit boolean) on stack before `while`-condition",
-            self.loc,
-        )?;
-        let mut expected_types = analysis.types().cloned().collect::<Vec<_>>();
-        expected_types.push(Type::Int);
-        body.analyze(analysis, debug)?;
+            head.analyze(analysis, debug);
+        }
+        let condition = analysis.expect(
+            &Type::Bool,
+            "expected Bool on stack before `while`-condition",
+            span,
+        )?.value;
+        let infinite = match (condition, &head) {
+            (Some(Value::Bool(false)), Some(_)) => {
+                analysis.warn("unreachable code: this `while` body never runs, its condition is always false", body.span());
+                false
+            }
+            (Some(Value::Bool(true)), Some(_)) => true,
+            _ => false,
+        };
+        let stack_in_body = analysis.stack.clone();
+        let poison = analysis.poison;
+        let loop_types = analysis.types().cloned().collect::<Vec<_>>();
+        let mut expected_types = loop_types.clone();
+        expected_types.push(Type::Bool);
+        let vars_in_body = analysis.vars.clone();
+        // without a head, `continue` has to leave the condition like the end of the body
+        let continue_types = match head {
+            Some(_) => loop_types.clone(),
+            None => expected_types.clone(),
+        };
+        let exits = LoopExits::new(loop_types, continue_types, poison);
+        let broken = exits.broken.clone();
+        analysis.loops.push(exits);
+        body.analyze(analysis, debug);
+        analysis.loops.pop();
+        if analysis.reachability != reachability {
+            // the end of the body is never reached, the head only runs again after `continue`
+            analysis.stack = stack_in_body.clone();
+            analysis.poison = poison;
+            analysis.reachability = reachability;
+        }
+        // variables defined in the body don't exist yet when the head runs for the first time
+        analysis.vars.retain(|name, _| vars_in_body.contains_key(name));
+        // code gen reads the head's values from this second pass, which only knows the last
+        // iteration
+        analysis.forget_const_values();
+        analysis.forget_var_values();
         if let Some(head) = head {
-            // `head` is getting its own `stack` and `vars` here
-            head.analyze(analysis, debug)?;
-            // but we have to update `self.stack` and `self.vars`
-            self.stack = Some(analysis.stack.clone());
-            self.vars = Some(analysis.vars.clone());
+            head.analyze(analysis, debug);
         }
-        if analysis.types().ne(expected_types.iter()) {
+        if !analysis.has_types(&expected_types, poison) {
             return Err(AnalyzerErr::TypeErr(
                 format!("`while` loop may not alter the stack beyond pushing the condition!\n\tfound (after 1st iteration): {:?}({})",
                     analysis.types().collect::<Vec<_>>(),
                     analysis.types().len(),
                 ),
-                self.loc,
+                span,
             ));
         }
         // pop the expected while condition
         analysis.pop();
+        // the loop doesn't alter the stack, whatever went wrong inside of it
+        analysis.stack = stack_in_body;
+        analysis.poison = poison;
         analysis.vars = vars;
-        analysis.forget_const_values();
+        analysis.forget_var_values();
+        analysis.reachability = reachability;
+        if infinite && !broken.get() {
+            analysis.diverge("the loop before it never ends");
+        }
         Ok(())
     }
 
@@ -313,13 +549,16 @@ impl AstNode {
         };
         let mut sub_analysis = analysis.clone();
         // don't leak local variables to outer scope
-        init.analyze(&mut sub_analysis, debug)?;
+        init.analyze(&mut sub_analysis, debug);
+        // the body and the modifier may change what the condition sees in later iterations
+        sub_analysis.forget_const_values();
+        sub_analysis.forget_var_values();
         // the expected bool
-        analysis.push(Type::Int, None);
+        analysis.push(Type::Bool, None);
         let mut expected_types = analysis.types().cloned().collect::<Vec<_>>();
         macro_rules! type_check {
             ($err_fmt_str:literal) => {
-                if sub_analysis.types().ne(expected_types.iter()) {
+                if !sub_analysis.has_types(&expected_types, analysis.poison) {
                     return Err(AnalyzerErr::TypeErr(
                         format!(
                             $err_fmt_str,
@@ -327,41 +566,187 @@ impl AstNode {
                             sub_analysis.types().collect::<Vec<_>>(),
                             sub_analysis.types().len()
                         ),
-                        condition.loc,
+                        condition.span(),
                     ));
                 }
             };
         }
-        condition.analyze(&mut sub_analysis, debug)?;
-        type_check!("`for` condition may only push a single Int\n\texpected {expected_types:?}({})\n\tbut found {:?}({})");
-        sub_analysis.pop();
-        analysis.stack = sub_analysis.stack.clone();
+        condition.analyze(&mut sub_analysis, debug);
+        type_check!("`for` condition may only push a single Bool\n\texpected {expected_types:?}({})\n\tbut found {:?}({})");
+        let infinite = match sub_analysis.pop().and_then(|e| e.value) {
+            Some(Value::Bool(false)) => {
+                analysis.warn("unreachable code: this `for` body never runs, its condition is always false", body.span());
+                false
+            }
+            Some(Value::Bool(true)) => true,
+            _ => false,
+        };
+        if sub_analysis.poison == analysis.poison {
+            analysis.stack = sub_analysis.stack.clone();
+        } else {
+            // the loop doesn't alter the stack, whatever went wrong in its head
+            analysis.pop();
+        }
         expected_types.pop();
-        modifier.analyze(&mut sub_analysis, debug)?;
-        type_check!("`for` modifier may not alter the stack\n\texpected {expected_types:?}({})\n\tbut found {:?}({})");
-        body.analyze(&mut sub_analysis, debug)?;
+        // analyzed in the order they run in
+        let exits = LoopExits::new(expected_types.clone(), expected_types.clone(), analysis.poison);
+        let (broken, continued) = (exits.broken.clone(), exits.continued.clone());
+        let vars_in_body = sub_analysis.vars.clone();
+        let stack_in_body = sub_analysis.stack.clone();
+        let (poison, reachability) = (sub_analysis.poison, sub_analysis.reachability);
+        sub_analysis.loops.push(exits);
+        body.analyze(&mut sub_analysis, debug);
+        sub_analysis.loops.pop();
+        if sub_analysis.reachability != reachability {
+            // the end of the body is never reached, the modifier only runs after `continue`
+            sub_analysis.stack = stack_in_body;
+            sub_analysis.poison = poison;
+            sub_analysis.reachability = reachability;
+        }
+        if continued.get() {
+            // `continue` may have skipped the variables defined in the body and what the body
+            // stored in the others
+            sub_analysis.vars.retain(|name, _| vars_in_body.contains_key(name));
+            sub_analysis.forget_var_values();
+            sub_analysis.forget_const_values();
+        }
         type_check!("`for` loop may not alter the stack\n\texpected {expected_types:?}({}) from the before the loop\n\tbut found {:?}({})");
+        modifier.analyze(&mut sub_analysis, debug);
+        type_check!("`for` modifier may not alter the stack\n\texpected {expected_types:?}({})\n\tbut found {:?}({})");
+        analysis.max_max_values_with(&sub_analysis);
+        analysis.forget_var_values();
+        if infinite && !broken.get() {
+            analysis.diverge("the loop before it never ends");
+        }
+
+        Ok(())
+    }
+
+    fn item_try(&mut self, analysis: &mut AstAnalysis, debug: bool) -> Result<(), AnalyzerErr> {
+        let span = self.span();
+        let AstItem::Try { body, catches, spill } = &mut self.inner else {
+            unreachable!();
+        };
+        // the JVM clears the stack before it runs a handler, so the stack before `try` is kept in
+        // hidden local variables, which don't leak into the outer scope
+        let mut sub_analysis = analysis.clone();
+        let mut indices = Vec::with_capacity(analysis.stack.len());
+        for elem in &analysis.stack {
+            let index = sub_analysis.next_local();
+            sub_analysis.max_vars_count = sub_analysis.max_vars_count.max(index + elem.ty.slots());
+            sub_analysis.vars.insert(
+                format!("$try{index}"),
+                LocalVar {
+                    index,
+                    elem: elem.clone(),
+                    defined_at: span,
+                },
+            );
+            indices.push(index);
+        }
+        *spill = Some(indices);
+        // the handler starts out with only the exception on the stack
+        sub_analysis.max_stack_size = sub_analysis.max_stack_size.max(1);
+        let before_body = sub_analysis.clone();
+        body.analyze(&mut sub_analysis, debug);
+        let mut reachability = sub_analysis.reachability;
+        for (class, catch_body) in catches {
+            let mut catch_analysis = before_body.clone();
+            // the body may have changed variables before it threw
+            catch_analysis.forget_var_values();
+            catch_body.analyze(&mut catch_analysis, debug);
+            reachability = reachability.min(catch_analysis.reachability);
+            analysis.max_max_values_with(&catch_analysis);
+            if sub_analysis.poison > catch_analysis.poison {
+                // the `catch` knows more about the stack
+                sub_analysis.stack = catch_analysis.stack;
+                sub_analysis.poison = catch_analysis.poison;
+            } else if !sub_analysis.same_types(&catch_analysis) {
+                return Err(AnalyzerErr::TypeErr(format!(
+                    "`try` and `catch` don't alter the stack the same way:\n\t`try` results in {:?}({})\n\t`catch \"{class}\"` results in {:?}({})",
+                    sub_analysis.types().collect::<Vec<_>>(),
+                    sub_analysis.types().len(),
+                    catch_analysis.types().collect::<Vec<_>>(),
+                    catch_analysis.types().len(),
+                ), span)
+                .with_note(
+                    format!("the `try` body leaves this stack: {:?}", sub_analysis.types().collect::<Vec<_>>()),
+                    body.span(),
+                )
+                .with_note(
+                    format!("the `catch` body leaves this stack: {:?}", catch_analysis.types().collect::<Vec<_>>()),
+                    catch_body.span(),
+                ));
+            }
+        }
         analysis.max_max_values_with(&sub_analysis);
+        analysis.stack = sub_analysis.stack;
+        analysis.poison = sub_analysis.poison;
+        analysis.reachability = reachability;
+        analysis.forget_const_values();
+        analysis.forget_var_values();
+        Ok(())
+    }
 
+    fn item_throw(&mut self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+        // the new exception is duplicated below the message for its constructor
+        analysis.require_additional_stack_size(2);
+        analysis.expect(&Type::String, "`throw` requires a String message on stack", self.span())?;
+        // like after `break`, the rest of the stack doesn't matter
+        analysis.poison();
+        analysis.diverge("it comes after a `throw`");
+        Ok(())
+    }
+
+    fn item_loop_exit(&mut self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+        let keyword = self.inner.short_spelling();
+        let Some(exits) = analysis.loops.last() else {
+            return Err(AnalyzerErr::TypeErr(format!("`{keyword}` outside of a loop"), self.span()));
+        };
+        let (expected, target) = match self.inner {
+            AstItem::Break => {
+                exits.broken.set(true);
+                (&exits.break_types, "after the loop")
+            }
+            _ => {
+                exits.continued.set(true);
+                (&exits.continue_types, "at the end of the loop's body")
+            }
+        };
+        if !analysis.has_types(expected, exits.poison) {
+            return Err(AnalyzerErr::TypeErr(
+                format!(
+                    "`{keyword}` has to leave the stack like it is {target}\n\texpected: {expected:?}({})\n\tfound: {:?}({})",
+                    expected.len(),
+                    analysis.types().collect::<Vec<_>>(),
+                    analysis.types().len(),
+                ),
+                self.span(),
+            ));
+        }
+        // the stack after the jump doesn't matter, like after an error
+        analysis.poison();
+        analysis.diverge(match self.inner {
+            AstItem::Break => "it comes after a `break`",
+            _ => "it comes after a `continue`",
+        });
         Ok(())
     }
 
     fn item_store(&mut self, analysis: &mut AstAnalysis, debug: bool) -> Result<(), AnalyzerErr> {
+        let span = self.span();
         let AstItem::Store { initializer, name } = &mut self.inner else {
             unreachable!();
         };
         if let Some(init) = initializer {
-            init.analyze(analysis, debug)?;
-            self.stack = Some(analysis.stack.clone());
-            self.vars = Some(analysis.vars.clone());
+            init.analyze(analysis, debug);
         }
-        analysis.max_vars_count += 1;
         let elem = analysis.expect_any(
             format!("stack is empty when `= {name}` is reached"),
-            self.loc,
+            span,
         )?;
         if let Some(var) = analysis.vars.get_mut(name) {
-            if elem.ty == var.elem.ty {
+            if elem.ty.matches(&var.elem.ty) {
                 var.elem.value = elem.value;
             } else {
                 return Err(AnalyzerErr::TypeErr(
@@ -369,61 +754,278 @@ impl AstNode {
                         "cannot override type {:?} of variable {name} to {:?}",
                         var.elem.ty, elem.ty
                     ),
-                    self.loc,
-                ));
+                    span,
+                )
+                .with_note("variable first defined here", var.defined_at));
             }
         } else {
-            let index = analysis.vars.len();
-            analysis
-                .vars
-                .insert(name.to_owned(), LocalVar { index, elem });
+            let index = analysis.next_local();
+            analysis.max_vars_count = analysis.max_vars_count.max(index + elem.ty.slots());
+            analysis.vars.insert(
+                name.to_owned(),
+                LocalVar {
+                    index,
+                    elem,
+                    defined_at: span,
+                },
+            );
         }
-        self.vars = Some(analysis.vars.clone());
         Ok(())
     }
 
-    fn item_load(&self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+    fn item_load(&mut self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
         let AstItem::Load(name) = &self.inner else {
             unreachable!();
         };
         if let Some(var) = analysis.vars.get(name) {
             analysis.push(var.elem.ty.clone(), var.elem.value.clone());
             Ok(())
+        } else if analysis.functions.contains_key(name) {
+            // variables shadow functions, so only now do we know that this is a call
+            self.inner = AstItem::Call {
+                name: name.clone(),
+                signature: None,
+            };
+            self.item_call(analysis)
+        } else if analysis.records.contains_key(name) {
+            // a record's name constructs it
+            self.inner = AstItem::New {
+                record: name.clone(),
+                fields: None,
+            };
+            self.item_new(analysis)
         } else {
             Err(AnalyzerErr::TypeErr(
                 format!("unknown variable {name}"),
-                self.loc,
+                self.span(),
             ))
         }
     }
 
+    fn item_function(&mut self, analysis: &mut AstAnalysis, debug: bool) -> Result<(), AnalyzerErr> {
+        let span = self.span();
+        let AstItem::Function { name, signature, body, limits } = &mut self.inner else {
+            unreachable!();
+        };
+        for ty in signature.input.iter().chain(&signature.output) {
+            analysis.check_type(ty, span)?;
+        }
+        // the function body neither sees the caller's stack nor its local variables
+        let mut fn_analysis = AstAnalysis::new();
+        fn_analysis.functions = analysis.functions.clone();
+        fn_analysis.records = analysis.records.clone();
+        fn_analysis.errors = analysis.errors.clone();
+        fn_analysis.warnings = analysis.warnings.clone();
+        for (i, (index, ty)) in signature.arguments().enumerate() {
+            fn_analysis.push(ty.clone(), None);
+            // reserve the local variable slots the JVM passes the arguments in
+            fn_analysis.vars.insert(
+                format!("${i}"),
+                LocalVar {
+                    index,
+                    elem: StackElement {
+                        ty: ty.clone(),
+                        value: None,
+                    },
+                    defined_at: span,
+                },
+            );
+        }
+        fn_analysis.max_vars_count = signature.input.iter().map(Type::slots).sum();
+        body.analyze(&mut fn_analysis, debug);
+        if !fn_analysis.has_types(&signature.output, 0) {
+            return Err(AnalyzerErr::TypeErr(
+                format!(
+                    "function {name} doesn't leave its declared output on the stack\n\tdeclared: {:?}({})\n\tfound: {:?}({})",
+                    signature.output,
+                    signature.output.len(),
+                    fn_analysis.types().collect::<Vec<_>>(),
+                    fn_analysis.types().len(),
+                ),
+                span,
+            ));
+        }
+        *limits = Some((fn_analysis.max_stack_size, fn_analysis.max_vars_count));
+        Ok(())
+    }
+
+    fn item_call(&mut self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+        let span = self.span();
+        let AstItem::Call { name, signature } = &mut self.inner else {
+            unreachable!();
+        };
+        let Some(sig) = analysis.functions.get(name).cloned() else {
+            return Err(AnalyzerErr::TypeErr(
+                format!("unknown function {name}"),
+                span,
+            ));
+        };
+        for ty in sig.input.iter().rev() {
+            analysis.expect(ty, format!("function {name} expected {ty:?} on stack"), span)?;
+        }
+        for ty in &sig.output {
+            analysis.push(ty.clone(), None);
+        }
+        *signature = Some(sig);
+        Ok(())
+    }
+
+    fn item_record(&self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+        let span = self.span();
+        let AstItem::Record { name, fields } = &self.inner else {
+            unreachable!();
+        };
+        for (i, (field, ty)) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|(other, _)| other == field) {
+                return Err(AnalyzerErr::TypeErr(
+                    format!("record {name} has more than one field {field}"),
+                    span,
+                ));
+            }
+            analysis.check_type(ty, span)?;
+        }
+        Ok(())
+    }
+
+    fn item_new(&mut self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+        let span = self.span();
+        let AstItem::New { record, fields } = &mut self.inner else {
+            unreachable!();
+        };
+        let Some(record_fields) = analysis.records.get(record).cloned() else {
+            return Err(AnalyzerErr::TypeErr(format!("unknown record {record}"), span));
+        };
+        for (field, ty) in record_fields.iter().rev() {
+            analysis.expect(
+                ty,
+                format!("record {record} expected {ty:?} on stack for field {field}"),
+                span,
+            )?;
+        }
+        analysis.push(Type::Record(record.clone()), None);
+        *fields = Some(record_fields);
+        Ok(())
+    }
+
+    fn item_get_field(&mut self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+        let span = self.span();
+        let AstItem::GetField { name, resolved } = &mut self.inner else {
+            unreachable!();
+        };
+        let record = analysis.expect_record(format!("`.{name}` expected a record on stack"), span)?;
+        let field = analysis.field(record.as_deref(), name, span)?;
+        analysis.push(field.1.clone(), None);
+        *resolved = Some(field);
+        Ok(())
+    }
+
+    fn item_set_field(&mut self, analysis: &mut AstAnalysis, debug: bool) -> Result<(), AnalyzerErr> {
+        let span = self.span();
+        let AstItem::SetField { initializer, name, resolved } = &mut self.inner else {
+            unreachable!();
+        };
+        if let Some(init) = initializer {
+            init.analyze(analysis, debug);
+        }
+        let value = analysis.expect_any(
+            format!("stack is empty when `= .{name}` is reached"),
+            span,
+        )?;
+        let record = analysis.expect_record(
+            format!("`= .{name}` expected a record below the value"),
+            span,
+        )?;
+        let field = analysis.field(record.as_deref(), name, span)?;
+        if !value.ty.matches(&field.1) {
+            return Err(AnalyzerErr::TypeErr(
+                format!("cannot store {:?} in field {name} of type {:?}", value.ty, field.1),
+                span,
+            ));
+        }
+        *resolved = Some(field);
+        Ok(())
+    }
+
+    fn item_extern(&self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+        let span = self.span();
+        let AstItem::Extern { name, kind, input, output, .. } = &self.inner else {
+            unreachable!();
+        };
+        for ty in input.iter().chain(output) {
+            analysis.check_type(&ty.ty, span)?;
+        }
+        match kind {
+            ExternKind::Static => {}
+            ExternKind::Virtual => match input.first() {
+                None => {
+                    return Err(AnalyzerErr::TypeErr(
+                        format!("virtual extern {name} needs the object it is called on as its first input"),
+                        span,
+                    ))
+                }
+                Some(receiver) if receiver.erased || !receiver.ty.is_reference() => {
+                    return Err(AnalyzerErr::TypeErr(
+                        format!("virtual extern {name} cannot be called on {:?}", receiver.ty),
+                        span,
+                    ))
+                }
+                Some(_) => {}
+            },
+            ExternKind::Constructor => {
+                if !matches!(output.as_slice(), [ty] if !ty.erased && ty.ty.is_reference()) {
+                    return Err(AnalyzerErr::TypeErr(
+                        format!("constructor extern {name} has to output the object it creates"),
+                        span,
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn item_jasmin(&self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
         let AstItem::Jasmin { input, output, extra_stack, name, .. } = &self.inner else {
             unreachable!();
         };
         analysis.require_additional_stack_size(*extra_stack);
         let mut generics = HashMap::new();
+        let mut values = Vec::with_capacity(input.len());
         for t in input.iter().rev() {
-            let ty = analysis.expect_any(format!("{name} expected some {t:?} on stack, found nothing\n\tcaptured generics: {generics:?}"), self.loc)?.ty;
-            if !t.matches_and_capture_generics(&ty, &mut generics) {
-                return Err(AnalyzerErr::TypeErr(format!("{name} expected some {t:?} on stack, {ty:?} doesn't match!\n\tcaptured generics: {generics:?}"), self.loc));
+            let elem = analysis.expect_any(format!("{name} expected some {t:?} on stack, found nothing\n\tcaptured generics: {generics:?}"), self.span())?;
+            if !t.matches_and_capture_generics(&elem.ty, &mut generics) {
+                return Err(AnalyzerErr::TypeErr(format!("{name} expected some {t:?} on stack, {:?} doesn't match!\n\tcaptured generics: {generics:?}", elem.ty), self.span()));
             }
+            values.push(elem.value);
         }
+        values.reverse();
+        // the results of pure intrinsics are known if their inputs are
+        let mut folded = values
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .and_then(|values| optimizer::fold_intrinsic(name, &values))
+            .filter(|results| results.len() == output.len())
+            .map(Vec::into_iter);
         for t in output {
-            let ty = t.try_resolve(&mut generics).map_err(|_| {
-                AnalyzerErr::TypeErr(
-                    format!(
-                        "cannot resolve type {t:?} in {name}\n\tcaptured generics: {generics:?}"
-                    ),
-                    self.loc,
-                )
-            })?;
-            analysis.push(ty.clone(), None);
+            let ty = match t.try_resolve(&mut generics) {
+                Ok(ty) => ty,
+                // the generic was matched against an unknown type
+                Err(_) if analysis.poison > 0 => Type::Unknown,
+                Err(_) => {
+                    return Err(AnalyzerErr::TypeErr(
+                        format!(
+                            "cannot resolve type {t:?} in {name}\n\tcaptured generics: {generics:?}"
+                        ),
+                        self.span(),
+                    ))
+                }
+            };
+            analysis.push(ty, folded.as_mut().and_then(Iterator::next));
         }
         Ok(())
     }
 
     fn item_type_switch(&mut self, analysis: &mut AstAnalysis) -> Result<(), AnalyzerErr> {
+        let span = self.span();
         let AstItem::TypeSwitch { arms, chosen_index } = &mut self.inner else {
             unreachable!();
         };
@@ -432,7 +1034,7 @@ impl AstNode {
         'arms_loop: for (i, arm) in arms.iter().enumerate() {
             *analysis = prev_analysis.clone();
             for ty in arm.0.iter().rev() {
-                if let Ok(stack_ty) = analysis.expect_any("", self.loc).map(|e| e.ty) {
+                if let Ok(stack_ty) = analysis.expect_any("", span).map(|e| e.ty) {
                     if !ty.matches_and_capture_generics(&stack_ty, &mut generics) {
                         continue 'arms_loop;
                     }
@@ -446,7 +1048,7 @@ impl AstNode {
         if let Some(index) = chosen_index {
             *analysis = prev_analysis;
             // FIXME: doesn't respect `debug` flag
-            arms[*index].1.analyze(analysis, false)?;
+            arms[*index].1.analyze(analysis, false);
             Ok(())
         } else {
             Err(AnalyzerErr::TypeErr(
@@ -454,7 +1056,7 @@ impl AstNode {
                     "no arm in `typeswitch` matches on {:?}",
                     prev_analysis.types().collect::<Vec<_>>()
                 ),
-                self.loc,
+                span,
             ))
         }
     }
@@ -467,81 +1069,211 @@ impl AstAnalysis {
             max_stack_size: 0,
             vars: HashMap::new(),
             max_vars_count: 0,
+            functions: HashMap::new(),
+            records: HashMap::new(),
+            poison: 0,
+            errors: Rc::new(RefCell::new(Vec::new())),
+            warnings: Rc::new(RefCell::new(Vec::new())),
+            reachability: Reachability::Reachable,
+            loops: Vec::new(),
         }
     }
 
+    pub fn error(&self, e: AnalyzerErr) {
+        self.errors.borrow_mut().push(e);
+    }
+
+    pub fn warn(&self, message: impl Into<String>, span: Span) {
+        self.warnings
+            .borrow_mut()
+            .push(Diagnostic::warning(message, Some(span)));
+    }
+
+    /// Warns once if `node` is the first node that can never run, and marks it and the nodes
+    /// after it so that they aren't compiled. Definitions are always compiled, wherever they are.
+    fn check_reachable(&mut self, node: &mut AstNode) {
+        if matches!(
+            node.inner,
+            AstItem::Function { .. } | AstItem::Record { .. } | AstItem::Extern { .. }
+        ) {
+            return;
+        }
+        if let Reachability::Unreachable(reason) = self.reachability {
+            self.warn(format!("unreachable code: {reason}"), node.span());
+            self.reachability = Reachability::Reported;
+        }
+        node.reachable = self.reachability == Reachability::Reachable;
+    }
+
+    /// Marks the code that follows as unreachable, unless it already is
+    pub fn diverge(&mut self, reason: &'static str) {
+        if self.reachability == Reachability::Reachable {
+            self.reachability = Reachability::Unreachable(reason);
+        }
+    }
+
+    /// Forgets what is on the stack after an error
+    pub fn poison(&mut self) {
+        self.stack.clear();
+        self.poison += 1;
+    }
+
+    /// Whether both stacks have the same types; always true if they were poisoned differently
+    pub fn same_types(&self, other: &AstAnalysis) -> bool {
+        self.poison != other.poison || self.types().eq(other.types())
+    }
+
+    /// Whether the stack has exactly `types`, which were on the stack when it was poisoned
+    /// `poison` times; always true if it was poisoned since
+    pub fn has_types(&self, types: &[Type], poison: usize) -> bool {
+        self.poison != poison || self.types().eq(types.iter())
+    }
+
     /// Used for debug-printing the stack
     pub fn types(&self) -> impl ExactSizeIterator<Item = &Type> + std::fmt::Debug {
         self.stack.iter().map(|e| &e.ty)
     }
 
+    /// How many slots the values on the stack take up on the JVM
+    pub fn stack_slots(&self) -> usize {
+        self.types().map(Type::slots).sum()
+    }
+
+    /// The index of the first local variable slot after all variables
+    pub fn next_local(&self) -> usize {
+        self.vars
+            .values()
+            .map(|var| var.index + var.elem.ty.slots())
+            .max()
+            .unwrap_or(0)
+    }
+
     pub fn require_additional_stack_size(&mut self, x: usize) {
-        if self.stack.len() + x > self.max_stack_size {
-            self.max_stack_size = self.stack.len() + x;
+        if self.stack_slots() + x > self.max_stack_size {
+            self.max_stack_size = self.stack_slots() + x;
         }
     }
 
     pub fn push(&mut self, ty: Type, value: Option<Value>) {
         self.stack.push(StackElement { ty, value });
-        if self.stack.len() > self.max_stack_size {
-            self.max_stack_size = self.stack.len();
+        if self.stack_slots() > self.max_stack_size {
+            self.max_stack_size = self.stack_slots();
         }
     }
 
     pub fn pop(&mut self) -> Option<StackElement> {
+        if self.stack.is_empty() && self.poison > 0 {
+            self.poison += 1;
+            return Some(StackElement {
+                ty: Type::Unknown,
+                value: None,
+            });
+        }
         self.stack.pop()
     }
 
     pub fn expect_any(
         &mut self,
         reason: impl Display,
-        loc: Loc,
+        span: Span,
     ) -> Result<StackElement, AnalyzerErr> {
         if let Some(t) = self.pop() {
             Ok(t)
         } else {
-            Err(AnalyzerErr::TypeErr(format!("{reason}"), loc))
+            Err(AnalyzerErr::TypeErr(format!("{reason}"), span))
         }
     }
 
     pub fn expect_list(
         &mut self,
         reason: impl Display,
-        loc: Loc,
+        span: Span,
     ) -> Result<StackElement, AnalyzerErr> {
         if let Some(e) = self.pop() {
-            if let Type::List(_) = e.ty {
+            if let Type::List(_) | Type::Unknown = e.ty {
                 Ok(e)
             } else {
-                Err(AnalyzerErr::TypeErr(format!("{reason}, found {e:?}!"), loc))
+                Err(AnalyzerErr::TypeErr(format!("{reason}, found {e:?}!"), span))
             }
         } else {
             Err(AnalyzerErr::TypeErr(
                 format!("{reason}, found an empty stack!"),
-                loc,
+                span,
             ))
         }
     }
 
+    /// Pops a record and returns its name; `None` if it comes from a poisoned stack
+    pub fn expect_record(
+        &mut self,
+        reason: impl Display,
+        span: Span,
+    ) -> Result<Option<String>, AnalyzerErr> {
+        match self.pop() {
+            Some(StackElement { ty: Type::Record(name), .. }) => Ok(Some(name)),
+            Some(StackElement { ty: Type::Unknown, .. }) => Ok(None),
+            Some(e) => Err(AnalyzerErr::TypeErr(format!("{reason}, found {:?}!", e.ty), span)),
+            None => Err(AnalyzerErr::TypeErr(
+                format!("{reason}, found an empty stack!"),
+                span,
+            )),
+        }
+    }
+
+    /// The record and the type of its field `name`. The field of an unknown record is unknown.
+    pub fn field(
+        &self,
+        record: Option<&str>,
+        name: &str,
+        span: Span,
+    ) -> Result<(String, Type), AnalyzerErr> {
+        let Some(record) = record else {
+            return Ok((String::new(), Type::Unknown));
+        };
+        self.records
+            .get(record)
+            .and_then(|fields| fields.iter().find(|(field, _)| field == name))
+            .map(|(_, ty)| (record.to_owned(), ty.clone()))
+            .ok_or_else(|| {
+                AnalyzerErr::TypeErr(format!("record {record} has no field {name}"), span)
+            })
+    }
+
+    /// Makes sure that all records `ty` refers to exist
+    pub fn check_type(&self, ty: &Type, span: Span) -> Result<(), AnalyzerErr> {
+        match ty {
+            Type::Record(name) if !self.records.contains_key(name) => Err(AnalyzerErr::TypeErr(
+                format!("unknown type {name}"),
+                span,
+            )),
+            Type::List(ty) => self.check_type(ty, span),
+            Type::Map(key, value) => {
+                self.check_type(key, span)?;
+                self.check_type(value, span)
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn expect(
         &mut self,
         ty: &Type,
         reason: impl Display,
-        loc: Loc,
+        span: Span,
     ) -> Result<StackElement, AnalyzerErr> {
         if let Some(e) = self.pop() {
-            if e.ty == *ty {
+            if e.ty.matches(ty) {
                 Ok(e)
             } else {
                 Err(AnalyzerErr::TypeErr(
                     format!("{reason}, found {:?}!", e.ty),
-                    loc,
+                    span,
                 ))
             }
         } else {
             Err(AnalyzerErr::TypeErr(
                 format!("{reason}, found an empty stack!"),
-                loc,
+                span,
             ))
         }
     }
@@ -551,6 +1283,14 @@ impl AstAnalysis {
         self.max_vars_count = self.max_vars_count.max(other.max_vars_count);
     }
 
+    /// Forgets the values of all variables, e.g. because a loop or a branch may have changed
+    /// them
+    pub fn forget_var_values(&mut self) {
+        for var in self.vars.values_mut() {
+            var.elem.value = None;
+        }
+    }
+
     pub fn forget_const_values(&mut self) {
         for e in &mut self.stack {
             e.value = None;
@@ -558,26 +1298,152 @@ impl AstAnalysis {
     }
 }
 
+impl LoopExits {
+    pub fn new(break_types: Vec<Type>, continue_types: Vec<Type>, poison: usize) -> Self {
+        Self {
+            break_types,
+            continue_types,
+            poison,
+            broken: Rc::new(Cell::new(false)),
+            continued: Rc::new(Cell::new(false)),
+        }
+    }
+}
+
 impl Type {
     pub fn is_number(&self) -> bool {
         match self {
             Self::Int => true,
+            Self::Bool => false,
+            Self::Long => true,
+            Self::Double => true,
             Self::String => false,
             Self::List(_) => false,
+            Self::Map(..) => false,
             Self::Object(_) => false,
+            Self::Record(_) => false,
+            Self::Unknown => false,
+        }
+    }
+
+    /// Whether values of this type are objects on the JVM
+    pub fn is_reference(&self) -> bool {
+        !matches!(self, Self::Int | Self::Bool | Self::Long | Self::Double)
+    }
+
+    /// How many slots of the JVM's stack or local variables a value of this type takes up
+    pub fn slots(&self) -> usize {
+        match self {
+            Self::Long | Self::Double => 2,
+            _ => 1,
+        }
+    }
+
+    /// Equality, except that `Unknown` matches everything
+    pub fn matches(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Self::Unknown, _) | (_, Self::Unknown) => true,
+            (Self::List(a), Self::List(b)) => a.matches(b),
+            (Self::Map(a, b), Self::Map(c, d)) => a.matches(c) && b.matches(d),
+            (a, b) => a == b,
         }
     }
 
     pub fn to_opcode(&self) -> String {
         match self {
             Self::Int => opcodes::TYPE_INT.into(),
+            Self::Bool => opcodes::TYPE_BOOL.into(),
+            Self::Long => opcodes::TYPE_LONG.into(),
+            Self::Double => opcodes::TYPE_DOUBLE.into(),
             Self::String => opcodes::TYPE_STRING.into(),
-            Self::List(_) => opcodes::TYPE_OBJECT.into(),
-            Self::Object(name) => format!("L{name};"),
+            Self::List(_) => opcodes::TYPE_ARRAY_LIST.into(),
+            Self::Map(..) => opcodes::TYPE_HASH_MAP.into(),
+            Self::Object(name) | Self::Record(name) => format!("L{name};"),
+            // never reaches code gen, as analysis fails
+            Self::Unknown => opcodes::TYPE_OBJECT.into(),
+        }
+    }
+
+    /// The verification type of a stack map frame, as written in a `.stack` directive
+    pub fn verification_type(&self) -> String {
+        match self {
+            Self::Int | Self::Bool => "Integer".into(),
+            Self::Long => "Long".into(),
+            Self::Double => "Double".into(),
+            Self::String => format!("Object {}", opcodes::CLASS_STRING),
+            Self::List(_) => format!("Object {}", opcodes::CLASS_ARRAY_LIST),
+            Self::Map(..) => format!("Object {}", opcodes::CLASS_HASH_MAP),
+            Self::Object(name) | Self::Record(name) => format!("Object {name}"),
+            Self::Unknown => format!("Object {}", opcodes::CLASS_OBJECT),
+        }
+    }
+
+    pub fn load_opcode(&self) -> Opcode {
+        match self {
+            Self::Int | Self::Bool => Opcode::ILoad,
+            Self::Long => Opcode::LLoad,
+            Self::Double => Opcode::DLoad,
+            Self::String | Self::List(_) | Self::Map(..) | Self::Object(_) | Self::Record(_) | Self::Unknown => Opcode::ALoad,
+        }
+    }
+
+    pub fn store_opcode(&self) -> Opcode {
+        match self {
+            Self::Int | Self::Bool => Opcode::IStore,
+            Self::Long => Opcode::LStore,
+            Self::Double => Opcode::DStore,
+            Self::String | Self::List(_) | Self::Map(..) | Self::Object(_) | Self::Record(_) | Self::Unknown => Opcode::AStore,
+        }
+    }
+
+    pub fn return_opcode(&self) -> Opcode {
+        match self {
+            Self::Int | Self::Bool => Opcode::IReturn,
+            Self::Long => Opcode::LReturn,
+            Self::Double => Opcode::DReturn,
+            Self::String | Self::List(_) | Self::Map(..) | Self::Object(_) | Self::Record(_) | Self::Unknown => Opcode::AReturn,
         }
     }
 }
 
+impl Signature {
+    /// The JVM method descriptor, e.g. `(ILjava/lang/String;)I`
+    pub fn descriptor(&self) -> String {
+        format!(
+            "({}){}",
+            self.input.iter().map(Type::to_opcode).collect::<String>(),
+            self.output
+                .first()
+                .map(Type::to_opcode)
+                .unwrap_or_else(|| opcodes::TYPE_VOID.into())
+        )
+    }
+
+    /// The stack effect of an extern, as Jack sees it
+    pub fn of_extern(input: &[ExternType], output: &[ExternType]) -> Self {
+        Self {
+            input: input.iter().map(|ty| ty.ty.clone()).collect(),
+            output: output.iter().map(|ty| ty.ty.clone()).collect(),
+        }
+    }
+
+    /// The arguments with the index of the local variable the JVM passes them in
+    pub fn arguments(&self) -> impl Iterator<Item = (usize, &Type)> {
+        self.input.iter().scan(0, |index, ty| {
+            let arg = (*index, ty);
+            *index += ty.slots();
+            Some(arg)
+        })
+    }
+
+    pub fn return_opcode(&self) -> Opcode {
+        self.output
+            .first()
+            .map(Type::return_opcode)
+            .unwrap_or(Opcode::Return)
+    }
+}
+
 impl std::fmt::Debug for StackElement {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         if let Some(ref const_val) = self.value {
@@ -589,9 +1455,178 @@ impl std::fmt::Debug for StackElement {
 }
 
 impl AnalyzerErr {
-    pub fn loc(&self) -> Option<Loc> {
-        Some(match self {
-            Self::TypeErr(_, loc) | Self::CompilerErr(_, loc) => *loc,
-        })
+    pub fn span(&self) -> Span {
+        match self {
+            Self::TypeErr(_, span) | Self::CompilerErr(_, span) => *span,
+            Self::WithNotes(e, _) => e.span(),
+        }
+    }
+
+    /// Attaches a note pointing to another place in the source
+    pub fn with_note(self, message: impl Into<String>, span: Span) -> Self {
+        let note = Note::new(message, Some(span));
+        match self {
+            Self::WithNotes(e, mut notes) => {
+                notes.push(note);
+                Self::WithNotes(e, notes)
+            }
+            e => Self::WithNotes(Box::new(e), vec![note]),
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            Self::TypeErr(msg, span) => Diagnostic::error(format!("type error: {msg}"), Some(*span)),
+            Self::CompilerErr(msg, span) => {
+                Diagnostic::error(format!("compiler error: {msg}"), Some(*span))
+            }
+            Self::WithNotes(e, notes) => Diagnostic {
+                notes: notes.clone(),
+                ..e.diagnostic()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{macr::Preprocessor, parser::parse};
+
+    /// Analyzes `src` after the standard library; its warnings, or the errors
+    fn analyze(src: &str) -> Result<Vec<Diagnostic>, Vec<AnalyzerErr>> {
+        let include_dirs = [PathBuf::from(env!("CARGO_MANIFEST_DIR"))];
+        let mut preprocessor = Preprocessor::new(&include_dirs);
+        let tokens = preprocessor
+            .process("test.jack".into(), format!("#include std.jack\n{src}"), false)
+            .unwrap();
+        let mut ast = parse(tokens.into_iter().map(Ok), 0).unwrap();
+        Ok(ast.analyze(false)?.warnings.take())
+    }
+
+    fn assert_unreachable(warnings: &[Diagnostic], reason: &str) {
+        assert!(
+            warnings.iter().any(|w| w.message == format!("unreachable code: {reason}")),
+            "{warnings:?}"
+        );
+    }
+
+    #[test]
+    fn break_ends_its_branch() {
+        analyze(
+            "i = 0
+            while (i 10 <) {
+                i 100 +
+                if (i 3 ==) { drop break }
+                drop
+                i = { i 1 + }
+            }",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn continue_ends_its_branch() {
+        analyze(
+            "k = 0
+            dowhile {
+                k = { k 1 + }
+                if (k 2 ==) { true continue }
+                k 4 <
+            }",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn branch_that_exits_takes_the_stack_of_the_other() {
+        analyze(
+            "j = 0
+            while (j 5 <) {
+                j = { j 1 + }
+                if (j 2 ==) { continue } else { j }
+                print
+            }",
+        )
+        .unwrap();
+        // the `else` decides the stack after the `if`
+        assert!(analyze("c = true loop { if (c) { break } else { 1 } }").is_err());
+    }
+
+    #[test]
+    fn try_that_exits_takes_the_stack_of_the_catch() {
+        analyze(r#"loop { 1 try { drop break } catch "java/lang/Exception" { } drop }"#).unwrap();
+    }
+
+    #[test]
+    fn loop_exits_still_check_the_stack() {
+        assert!(analyze("while (true) { 1 break }").is_err());
+        assert!(analyze("1 while (true) { drop continue }").is_err());
+        assert!(analyze("break").is_err());
+    }
+
+    #[test]
+    fn throw_ends_its_branch() {
+        analyze(r#"c = true 1 if (c) { "bad" throw } print"#).unwrap();
+        analyze(r#"c = true if (c) { "bad" throw } else { 1 } print"#).unwrap();
+        analyze(r#"fn check [int] -> [int] { dup if (0 ==) { drop "zero" throw } }"#).unwrap();
+        analyze(r#"fn f [] -> [string] { "no result" throw }"#).unwrap();
+        analyze(r#"try { 1 "bad" throw } catch "java/lang/Exception" { 2 } print"#).unwrap();
+        let warnings = analyze(r#""bad" throw "never" println"#).unwrap();
+        assert_unreachable(&warnings, "it comes after a `throw`");
+    }
+
+    #[test]
+    fn code_after_loop_exits_is_unreachable() {
+        let warnings = analyze(r#"loop { break "never" println }"#).unwrap();
+        assert_unreachable(&warnings, "it comes after a `break`");
+        let warnings = analyze(r#"while (false) { continue "never" println }"#).unwrap();
+        assert_unreachable(&warnings, "it comes after a `continue`");
+        // the code after the loop runs after `break`
+        let warnings = analyze(r#"loop { break } "after" println"#).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    #[test]
+    fn functions_check_their_signature() {
+        analyze("fn add [int int] -> [int] { + } 1 2 add print").unwrap();
+        // called before its definition
+        analyze("7 square print fn square [int] -> [int] { dup * }").unwrap();
+        // too few arguments, or ones of the wrong type
+        assert!(analyze("fn add [int int] -> [int] { + } 1 add print").is_err());
+        assert!(analyze(r#"fn add [int int] -> [int] { + } 1 "2" add print"#).is_err());
+        // the body has to leave exactly the declared output
+        assert!(analyze("fn f [int] -> [string] { }").is_err());
+        assert!(analyze("fn f [int] -> [] { }").is_err());
+        assert!(analyze("fn f [int] -> [int] { dup }").is_err());
+        // and doesn't see the caller's variables
+        assert!(analyze("x = 1 fn f [] -> [int] { x }").is_err());
+    }
+
+    #[test]
+    fn numbers_of_different_types_dont_mix() {
+        analyze("1L 2L + 3L < print 1.5 2.5 * 4.0 >= print").unwrap();
+        assert!(analyze("1 2L + print").is_err());
+        assert!(analyze("1L 2.0 < print").is_err());
+        assert!(analyze("1.0 2 == print").is_err());
+    }
+
+    #[test]
+    fn independent_errors_are_all_reported() {
+        let errs = analyze("1 \"a\" + print\nx print\n1 2L + print").unwrap_err();
+        assert_eq!(errs.len(), 3, "{errs:?}");
+        // the unknown stack after an error doesn't cause more of them
+        let errs = analyze("\"a\" 1 + 2 * 3 - print").unwrap_err();
+        assert_eq!(errs.len(), 1, "{errs:?}");
+    }
+
+    #[test]
+    fn maps_check_their_keys_and_values() {
+        analyze(r#"m = map[string, int] m "a" 1 put m "a" get 1 + print m "a" has print"#).unwrap();
+        assert!(analyze(r#"m = map[string, int] m 1 1 put"#).is_err());
+        assert!(analyze(r#"m = map[string, int] m "a" "b" put"#).is_err());
+        assert!(analyze(r#"m = map[string, int] m "a" get "b" concat print"#).is_err());
     }
 }
@@ -2,7 +2,11 @@ use std::collections::HashMap;
 
 use klex::Loc;
 
-use crate::analyzer::{AstAnalysis, LocalVar, StackElement, Type};
+use crate::{
+    analyzer::{AstAnalysis, LocalVar, Signature, StackElement, Type},
+    diagnostics::Span,
+    opcodes,
+};
 
 #[derive(Clone, Debug)]
 pub struct AstBase {
@@ -14,29 +18,55 @@ pub struct AstBase {
 pub struct AstNode {
     pub(crate) inner: AstItem,
     pub(crate) loc: Loc,
+    /// The length of the token this node starts with
+    pub(crate) len: usize,
+    /// The stack before this node, set by the analyzer
     pub(crate) stack: Option<Vec<StackElement>>,
+    /// The local variables before this node, set by the analyzer
     pub(crate) vars: Option<HashMap<String, LocalVar>>,
+    /// The stack after this node, set by the analyzer
+    pub(crate) stack_after: Option<Vec<StackElement>>,
+    /// The local variables after this node, set by the analyzer
+    pub(crate) vars_after: Option<HashMap<String, LocalVar>>,
+    /// Whether the node can ever run, set by the analyzer. Code generation skips it otherwise.
+    pub(crate) reachable: bool,
 }
 
 impl AstNode {
-    pub fn new(inner: AstItem, loc: Loc) -> Self {
+    pub fn new(inner: AstItem, span: Span) -> Self {
         Self {
             inner,
-            loc,
+            loc: span.loc,
+            len: span.len,
             stack: None,
             vars: None,
+            stack_after: None,
+            vars_after: None,
+            reachable: true,
         }
     }
+
+    pub fn span(&self) -> Span {
+        Span::new(self.loc, self.len)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum AstItem {
     /// Pushes an int onto the stack
     PushInt(i32),
+    /// Pushes a bool onto the stack
+    PushBool(bool),
+    /// Pushes a long onto the stack
+    PushLong(i64),
+    /// Pushes a double onto the stack
+    PushDouble(f64),
     /// Pushes a string onto the stack
     PushString(String),
     /// Initializes a new list
     List(Type),
+    /// Initializes a new map from keys of the first type to values of the second
+    Map(Type, Type),
     ListLiteral(Vec<AstNode>),
     If {
         head: Option<Box<AstNode>>,
@@ -57,6 +87,23 @@ pub enum AstItem {
         modifier: Box<AstNode>,
         body: Box<AstNode>,
     },
+    /// Runs `body`; if it throws an exception of one of the caught classes (or a subclass), the
+    /// stack is reset to how it was before `try` and the body of the first matching `catch` runs
+    Try {
+        body: Box<AstNode>,
+        /// The classes of the caught exceptions, written like `java/lang/Exception`, and the
+        /// bodies that handle them
+        catches: Vec<(String, AstNode)>,
+        /// The local variables that the stack before `try` is kept in, bottom first; set by the
+        /// analyzer
+        spill: Option<Vec<usize>>,
+    },
+    /// Pops a string and throws a `RuntimeException` with it as the message
+    Throw,
+    /// Leaves the innermost loop
+    Break,
+    /// Skips the rest of the body of the innermost loop; `for` runs its modifier next
+    Continue,
     Block(Vec<AstNode>),
     Store {
         initializer: Option<Box<AstNode>>,
@@ -75,12 +122,79 @@ pub enum AstItem {
         chosen_index: Option<usize>,
     },
     CmpErr(String),
+    /// A user-defined function, compiled to its own static method
+    Function {
+        name: String,
+        signature: Signature,
+        body: Box<AstNode>,
+        /// `max_stack_size` and `max_vars_count` of the body, set by the analyzer
+        limits: Option<(usize, usize)>,
+    },
+    /// Calls a user-defined function; the signature is resolved by the analyzer
+    Call {
+        name: String,
+        signature: Option<Signature>,
+    },
+    /// A record type with named fields, compiled to a class of its own
+    Record {
+        name: String,
+        fields: Vec<(String, Type)>,
+    },
+    /// Pops the fields of a record in the order they are declared in and pushes a new record;
+    /// the fields are resolved by the analyzer
+    New {
+        record: String,
+        fields: Option<Vec<(String, Type)>>,
+    },
+    /// `.name`: pops a record and pushes the value of its field; the record and the type of the
+    /// field are resolved by the analyzer
+    GetField {
+        name: String,
+        resolved: Option<(String, Type)>,
+    },
+    /// `.name = ...` or `:= .name`: pops a value and a record and stores the value in the
+    /// record's field, resolved like [`AstItem::GetField`]
+    SetField {
+        initializer: Option<Box<AstNode>>,
+        name: String,
+        resolved: Option<(String, Type)>,
+    },
+    /// A Java method declared with `extern`. It is compiled to a static method of the class that
+    /// calls the Java method, and using `name` calls it like a function.
+    Extern {
+        name: String,
+        kind: ExternKind,
+        /// `owner/method`, or just the class for constructors
+        target: String,
+        input: Vec<ExternType>,
+        output: Vec<ExternType>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExternKind {
+    /// `extern "owner/method"`: `invokestatic`
+    Static,
+    /// `extern virtual "owner/method"`: `invokevirtual` on the first input
+    Virtual,
+    /// `extern new "class"`: creates an instance of the class, which is the output
+    Constructor,
+}
+
+/// An input or output of an `extern` method
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExternType {
+    pub ty: Type,
+    /// Written `object[T]`: the JVM passes an `Object`, which is boxed from `T` on the way in and
+    /// cast to `T` on the way out
+    pub erased: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum MatchInType {
     Any,
     List(Box<MatchInType>),
+    Map(Box<MatchInType>, Box<MatchInType>),
     Type(Type),
     Generic(String),
 }
@@ -89,6 +203,7 @@ pub enum MatchInType {
 pub enum MatchOutType {
     Type(Type),
     List(Box<MatchOutType>),
+    Map(Box<MatchOutType>, Box<MatchOutType>),
     Generic(String),
 }
 
@@ -96,19 +211,67 @@ impl AstItem {
     pub fn short_spelling(&self) -> String {
         match self {
             Self::PushInt(i) => format!("push({i})"),
+            Self::PushBool(b) => format!("push({b})"),
+            Self::PushLong(n) => format!("push({n}L)"),
+            Self::PushDouble(d) => format!("push({d:?})"),
             Self::PushString(s) => format!("push{s:?}"),
             Self::List(t) => format!("list<{t:?}>"),
+            Self::Map(k, v) => format!("map<{k:?}, {v:?}>"),
             Self::ListLiteral(xs) => format!("{:?}", xs.iter().map(|n| n.inner.short_spelling()).collect::<Vec<_>>()),
             Self::If { .. } => "if".into(),
             Self::Switch { .. } => "switch".into(),
             Self::While { .. } => "while".into(),
             Self::For { .. } => "for".into(),
+            Self::Try { .. } => "try".into(),
+            Self::Throw => "throw".into(),
+            Self::Break => "break".into(),
+            Self::Continue => "continue".into(),
             Self::Block(_) => "block".into(),
             Self::Store { name, .. } => format!("store({name})"),
             Self::Load(s) => s.into(),
             Self::Jasmin { name, .. } => format!("{name}"),
             Self::TypeSwitch { .. } => "typeswitch".into(),
             Self::CmpErr(_) => "cmperr".into(),
+            Self::Function { name, .. } => format!("fn {name}"),
+            Self::Call { name, .. } => format!("call({name})"),
+            Self::Record { name, .. } => format!("record {name}"),
+            Self::New { record, .. } => format!("new({record})"),
+            Self::GetField { name, .. } => format!(".{name}"),
+            Self::SetField { name, .. } => format!("store(.{name})"),
+            Self::Extern { name, .. } => format!("extern {name}"),
+        }
+    }
+}
+
+impl ExternKind {
+    /// The Java method an extern calls, written like in Jasmin: `owner/name(arguments)result`
+    pub fn java_method(self, target: &str, input: &[ExternType], output: &[ExternType]) -> String {
+        // the object a virtual method is called on isn't part of its descriptor
+        let arguments = match self {
+            Self::Virtual => &input[1..],
+            _ => input,
+        };
+        let arguments = arguments.iter().map(ExternType::java_type).collect::<String>();
+        match self {
+            Self::Static | Self::Virtual => {
+                let result = output
+                    .first()
+                    .map(ExternType::java_type)
+                    .unwrap_or_else(|| opcodes::TYPE_VOID.into());
+                format!("{target}({arguments}){result}")
+            }
+            Self::Constructor => format!("{target}/<init>({arguments}){}", opcodes::TYPE_VOID),
+        }
+    }
+}
+
+impl ExternType {
+    /// The type in the Java method's descriptor
+    pub fn java_type(&self) -> String {
+        if self.erased {
+            opcodes::TYPE_OBJECT.into()
+        } else {
+            self.ty.to_opcode()
         }
     }
 }
@@ -121,15 +284,23 @@ impl MatchInType {
                 if let Type::List(inner) = ty {
                     xs.matches_and_capture_generics(inner, generics)
                 } else {
-                    false
+                    *ty == Type::Unknown
+                }
+            }
+            Self::Map(keys, values) => {
+                if let Type::Map(key, value) = ty {
+                    keys.matches_and_capture_generics(key, generics)
+                        && values.matches_and_capture_generics(value, generics)
+                } else {
+                    *ty == Type::Unknown
                 }
             }
             Self::Type(x) => {
-                x == ty
+                x.matches(ty)
             }
             Self::Generic(name) => {
                 if let Some(already_captured) = generics.get(name) {
-                    already_captured == ty
+                    already_captured.matches(ty)
                 } else {
                     generics.insert(name.to_owned(), ty.clone());
                     true
@@ -144,7 +315,11 @@ impl MatchOutType {
         Ok(match self {
             Self::Type(x) => x.clone(),
             Self::Generic(name) => generics.get(name).cloned().ok_or(())?,
-            Self::List(xs) => Type::List(Box::new(xs.try_resolve(generics)?))
+            Self::List(xs) => Type::List(Box::new(xs.try_resolve(generics)?)),
+            Self::Map(k, v) => Type::Map(
+                Box::new(k.try_resolve(generics)?),
+                Box::new(v.try_resolve(generics)?),
+            ),
         })
     }
 }
@@ -0,0 +1,1161 @@
+//! Assembles the [IR](crate::ir) generated by [`ClassWriter`](crate::codegen::ClassWriter) into
+//! the bytes of a `.class` file, so that no external assembler (and no JVM) is needed to compile.
+//! Intrinsics whose Jasmin the IR doesn't understand are assembled from their text, which
+//! supports single-line instructions and labels.
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::ir;
+
+pub const MAGIC: u32 = 0xCAFE_BABE;
+/// Java 8, which requires stack map frames at branch targets
+pub const MAJOR_VERSION: u16 = 52;
+pub const MINOR_VERSION: u16 = 0;
+
+pub const ACC_PUBLIC: u16 = 0x0001;
+pub const ACC_STATIC: u16 = 0x0008;
+pub const ACC_SUPER: u16 = 0x0020;
+
+const TAG_UTF8: u8 = 1;
+const TAG_INTEGER: u8 = 3;
+const TAG_LONG: u8 = 5;
+const TAG_DOUBLE: u8 = 6;
+const TAG_CLASS: u8 = 7;
+const TAG_STRING: u8 = 8;
+const TAG_FIELD_REF: u8 = 9;
+const TAG_METHOD_REF: u8 = 10;
+const TAG_INTERFACE_METHOD_REF: u8 = 11;
+const TAG_NAME_AND_TYPE: u8 = 12;
+
+const FRAME_FULL: u8 = 255;
+
+const ITEM_TOP: u8 = 0;
+const ITEM_INTEGER: u8 = 1;
+const ITEM_FLOAT: u8 = 2;
+const ITEM_DOUBLE: u8 = 3;
+const ITEM_LONG: u8 = 4;
+const ITEM_NULL: u8 = 5;
+const ITEM_OBJECT: u8 = 7;
+
+const OP_LDC: u8 = 0x12;
+const OP_LDC_W: u8 = 0x13;
+const OP_LDC2_W: u8 = 0x14;
+const OP_WIDE: u8 = 0xc4;
+const OP_IINC: u8 = 0x84;
+const OP_LOOKUP_SWITCH: u8 = 0xab;
+const OP_INVOKE_INTERFACE: u8 = 0xb9;
+
+/// The `usize`s are the index of the offending instruction in the code of its method
+#[derive(Error, Debug)]
+pub enum AssemblerErr {
+    #[error("instruction {1}: unknown instruction `{0}`")]
+    UnknownInstruction(String, usize),
+    #[error("instruction {1}: unknown label `{0}`")]
+    UnknownLabel(String, usize),
+    #[error("instruction {1}: jump offset {0} is out of range")]
+    JumpOutOfRange(i64, usize),
+    #[error("instruction {1}: branch target at offset {0} has no stack map frame")]
+    MissingFrame(usize, usize),
+    #[error("instruction {1}: {0}")]
+    Syntax(String, usize),
+    #[error("assembler error in method `{0}`: {1}")]
+    InMethod(String, Box<AssemblerErr>),
+}
+
+/// What follows an opcode
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OperandKind {
+    None,
+    /// `bipush`
+    Byte,
+    /// `sipush`
+    Short,
+    /// `iload 4`, widened if necessary
+    Local,
+    /// `iinc 4 1`
+    Iinc,
+    /// `ldc 42` or `ldc "hello"`
+    Ldc,
+    /// `ldc2_w 42` for a long or `ldc2_w 4.2` for a double
+    Ldc2,
+    /// `new java/lang/Object`
+    Class,
+    /// `getstatic java/lang/System/out Ljava/io/PrintStream;`
+    Field,
+    /// `invokevirtual java/lang/Object/toString()Ljava/lang/String;`
+    Method,
+    /// `invokeinterface java/util/List/size()I 1`
+    InterfaceMethod,
+    /// `goto Label` or `goto $+4`
+    Jump,
+    LookupSwitch,
+}
+
+use OperandKind as K;
+
+#[rustfmt::skip]
+const INSTRUCTIONS: &[(&str, u8, OperandKind)] = &[
+    ("nop", 0x00, K::None), ("aconst_null", 0x01, K::None),
+    ("iconst_m1", 0x02, K::None), ("iconst_0", 0x03, K::None), ("iconst_1", 0x04, K::None),
+    ("iconst_2", 0x05, K::None), ("iconst_3", 0x06, K::None), ("iconst_4", 0x07, K::None),
+    ("iconst_5", 0x08, K::None), ("lconst_0", 0x09, K::None), ("lconst_1", 0x0a, K::None),
+    ("fconst_0", 0x0b, K::None), ("fconst_1", 0x0c, K::None), ("fconst_2", 0x0d, K::None),
+    ("dconst_0", 0x0e, K::None), ("dconst_1", 0x0f, K::None),
+    ("bipush", 0x10, K::Byte), ("sipush", 0x11, K::Short),
+    ("ldc", OP_LDC, K::Ldc), ("ldc_w", OP_LDC_W, K::Ldc), ("ldc2_w", OP_LDC2_W, K::Ldc2),
+    ("iload", 0x15, K::Local), ("lload", 0x16, K::Local), ("fload", 0x17, K::Local),
+    ("dload", 0x18, K::Local), ("aload", 0x19, K::Local),
+    ("iload_0", 0x1a, K::None), ("iload_1", 0x1b, K::None), ("iload_2", 0x1c, K::None), ("iload_3", 0x1d, K::None),
+    ("lload_0", 0x1e, K::None), ("lload_1", 0x1f, K::None), ("lload_2", 0x20, K::None), ("lload_3", 0x21, K::None),
+    ("fload_0", 0x22, K::None), ("fload_1", 0x23, K::None), ("fload_2", 0x24, K::None), ("fload_3", 0x25, K::None),
+    ("dload_0", 0x26, K::None), ("dload_1", 0x27, K::None), ("dload_2", 0x28, K::None), ("dload_3", 0x29, K::None),
+    ("aload_0", 0x2a, K::None), ("aload_1", 0x2b, K::None), ("aload_2", 0x2c, K::None), ("aload_3", 0x2d, K::None),
+    ("iaload", 0x2e, K::None), ("laload", 0x2f, K::None), ("faload", 0x30, K::None), ("daload", 0x31, K::None),
+    ("aaload", 0x32, K::None), ("baload", 0x33, K::None), ("caload", 0x34, K::None), ("saload", 0x35, K::None),
+    ("istore", 0x36, K::Local), ("lstore", 0x37, K::Local), ("fstore", 0x38, K::Local),
+    ("dstore", 0x39, K::Local), ("astore", 0x3a, K::Local),
+    ("istore_0", 0x3b, K::None), ("istore_1", 0x3c, K::None), ("istore_2", 0x3d, K::None), ("istore_3", 0x3e, K::None),
+    ("lstore_0", 0x3f, K::None), ("lstore_1", 0x40, K::None), ("lstore_2", 0x41, K::None), ("lstore_3", 0x42, K::None),
+    ("fstore_0", 0x43, K::None), ("fstore_1", 0x44, K::None), ("fstore_2", 0x45, K::None), ("fstore_3", 0x46, K::None),
+    ("dstore_0", 0x47, K::None), ("dstore_1", 0x48, K::None), ("dstore_2", 0x49, K::None), ("dstore_3", 0x4a, K::None),
+    ("astore_0", 0x4b, K::None), ("astore_1", 0x4c, K::None), ("astore_2", 0x4d, K::None), ("astore_3", 0x4e, K::None),
+    ("iastore", 0x4f, K::None), ("lastore", 0x50, K::None), ("fastore", 0x51, K::None), ("dastore", 0x52, K::None),
+    ("aastore", 0x53, K::None), ("bastore", 0x54, K::None), ("castore", 0x55, K::None), ("sastore", 0x56, K::None),
+    ("pop", 0x57, K::None), ("pop2", 0x58, K::None), ("dup", 0x59, K::None), ("dup_x1", 0x5a, K::None),
+    ("dup_x2", 0x5b, K::None), ("dup2", 0x5c, K::None), ("dup2_x1", 0x5d, K::None), ("dup2_x2", 0x5e, K::None),
+    ("swap", 0x5f, K::None),
+    ("iadd", 0x60, K::None), ("ladd", 0x61, K::None), ("fadd", 0x62, K::None), ("dadd", 0x63, K::None),
+    ("isub", 0x64, K::None), ("lsub", 0x65, K::None), ("fsub", 0x66, K::None), ("dsub", 0x67, K::None),
+    ("imul", 0x68, K::None), ("lmul", 0x69, K::None), ("fmul", 0x6a, K::None), ("dmul", 0x6b, K::None),
+    ("idiv", 0x6c, K::None), ("ldiv", 0x6d, K::None), ("fdiv", 0x6e, K::None), ("ddiv", 0x6f, K::None),
+    ("irem", 0x70, K::None), ("lrem", 0x71, K::None), ("frem", 0x72, K::None), ("drem", 0x73, K::None),
+    ("ineg", 0x74, K::None), ("lneg", 0x75, K::None), ("fneg", 0x76, K::None), ("dneg", 0x77, K::None),
+    ("ishl", 0x78, K::None), ("lshl", 0x79, K::None), ("ishr", 0x7a, K::None), ("lshr", 0x7b, K::None),
+    ("iushr", 0x7c, K::None), ("lushr", 0x7d, K::None), ("iand", 0x7e, K::None), ("land", 0x7f, K::None),
+    ("ior", 0x80, K::None), ("lor", 0x81, K::None), ("ixor", 0x82, K::None), ("lxor", 0x83, K::None),
+    ("iinc", OP_IINC, K::Iinc),
+    ("i2l", 0x85, K::None), ("i2f", 0x86, K::None), ("i2d", 0x87, K::None), ("l2i", 0x88, K::None),
+    ("l2f", 0x89, K::None), ("l2d", 0x8a, K::None), ("f2i", 0x8b, K::None), ("f2l", 0x8c, K::None),
+    ("f2d", 0x8d, K::None), ("d2i", 0x8e, K::None), ("d2l", 0x8f, K::None), ("d2f", 0x90, K::None),
+    ("i2b", 0x91, K::None), ("i2c", 0x92, K::None), ("i2s", 0x93, K::None),
+    ("lcmp", 0x94, K::None), ("fcmpl", 0x95, K::None), ("fcmpg", 0x96, K::None),
+    ("dcmpl", 0x97, K::None), ("dcmpg", 0x98, K::None),
+    ("ifeq", 0x99, K::Jump), ("ifne", 0x9a, K::Jump), ("iflt", 0x9b, K::Jump), ("ifge", 0x9c, K::Jump),
+    ("ifgt", 0x9d, K::Jump), ("ifle", 0x9e, K::Jump), ("if_icmpeq", 0x9f, K::Jump),
+    ("if_icmpne", 0xa0, K::Jump), ("if_icmplt", 0xa1, K::Jump), ("if_icmpge", 0xa2, K::Jump),
+    ("if_icmpgt", 0xa3, K::Jump), ("if_icmple", 0xa4, K::Jump), ("if_acmpeq", 0xa5, K::Jump),
+    ("if_acmpne", 0xa6, K::Jump), ("goto", 0xa7, K::Jump),
+    ("lookupswitch", OP_LOOKUP_SWITCH, K::LookupSwitch),
+    ("ireturn", 0xac, K::None), ("lreturn", 0xad, K::None), ("freturn", 0xae, K::None),
+    ("dreturn", 0xaf, K::None), ("areturn", 0xb0, K::None), ("return", 0xb1, K::None),
+    ("getstatic", 0xb2, K::Field), ("putstatic", 0xb3, K::Field),
+    ("getfield", 0xb4, K::Field), ("putfield", 0xb5, K::Field),
+    ("invokevirtual", 0xb6, K::Method), ("invokespecial", 0xb7, K::Method),
+    ("invokenonvirtual", 0xb7, K::Method), ("invokestatic", 0xb8, K::Method),
+    ("invokeinterface", OP_INVOKE_INTERFACE, K::InterfaceMethod),
+    ("new", 0xbb, K::Class), ("anewarray", 0xbd, K::Class), ("arraylength", 0xbe, K::None),
+    ("athrow", 0xbf, K::None), ("checkcast", 0xc0, K::Class), ("instanceof", 0xc1, K::Class),
+    ("monitorenter", 0xc2, K::None), ("monitorexit", 0xc3, K::None),
+    ("ifnull", 0xc6, K::Jump), ("ifnonnull", 0xc7, K::Jump),
+];
+
+/// A jump target
+#[derive(Clone, Debug)]
+enum Target {
+    Label(String),
+    /// `$+n`, relative to the start of the jumping instruction
+    Relative(i32),
+}
+
+#[derive(Clone, Debug)]
+enum Operands {
+    None,
+    Byte(i8),
+    Short(i16),
+    Local(u16),
+    Iinc(u16, i16),
+    Ldc(u16),
+    Pool(u16),
+    InterfaceMethod(u16, u8),
+    Jump(Target),
+    LookupSwitch(Vec<(i32, Target)>, Target),
+}
+
+/// A type in a stack map frame
+#[derive(Clone, Debug, PartialEq)]
+enum VerificationType {
+    Top,
+    Integer,
+    Float,
+    Long,
+    Double,
+    Null,
+    Object(String),
+}
+
+/// The types of the local variables and of the stack at some instruction
+#[derive(Clone, Debug, PartialEq)]
+struct Frame {
+    locals: Vec<VerificationType>,
+    stack: Vec<VerificationType>,
+    /// The index of the frame in the IR, for error messages
+    index: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Instruction {
+    opcode: u8,
+    operands: Operands,
+    /// The index in the IR, for error messages
+    index: usize,
+}
+
+/// The interned entries of a class' constant pool
+struct ConstantPool {
+    bytes: Vec<u8>,
+    /// The index the next entry gets; entries start at 1
+    next_index: u16,
+    indices: HashMap<(u8, String), u16>,
+}
+
+/// A method, lowered from the IR
+#[derive(Default)]
+struct Method {
+    access_flags: u16,
+    name: String,
+    descriptor: String,
+    max_stack: u16,
+    max_locals: u16,
+    instructions: Vec<Instruction>,
+    /// Maps labels to the index of the instruction that follows them
+    labels: HashMap<String, usize>,
+    /// `(instruction index, source line)`
+    lines: Vec<(usize, u16)>,
+    /// Stack map frames, with the index of the instruction they describe
+    frames: Vec<(usize, Frame)>,
+    /// The exception table, in the order of the [`ir::Instruction::Catch`]es
+    catches: Vec<Catch>,
+}
+
+/// An entry of the exception table
+struct Catch {
+    class: String,
+    from: String,
+    to: String,
+    handler: String,
+    index: usize,
+}
+
+/// Assembles the class into the bytes of a `.class` file
+pub fn assemble(class: &ir::Class) -> Result<Vec<u8>, AssemblerErr> {
+    let mut pool = ConstantPool::default();
+    let this_class = pool.class(class.name);
+    let super_class = pool.class(class.extends);
+    let mut fields = Vec::new();
+    for field in class.fields {
+        write_u16(&mut fields, ACC_PUBLIC);
+        write_u16(&mut fields, pool.utf8(&field.name));
+        write_u16(&mut fields, pool.utf8(&field.descriptor));
+        // no attributes
+        write_u16(&mut fields, 0);
+    }
+    let mut methods = Vec::new();
+    for method in &class.methods {
+        let in_method = |e| AssemblerErr::InMethod(method.name.clone(), Box::new(e));
+        let lowered = Method::lower(method, &mut pool).map_err(in_method)?;
+        methods.extend(lowered.write(&mut pool).map_err(in_method)?);
+    }
+    let mut attributes = Vec::new();
+    write_u16(&mut attributes, pool.utf8("SourceFile"));
+    write_u32(&mut attributes, 2);
+    write_u16(&mut attributes, pool.utf8(class.source));
+
+    let mut bytes = Vec::new();
+    write_u32(&mut bytes, MAGIC);
+    write_u16(&mut bytes, MINOR_VERSION);
+    write_u16(&mut bytes, MAJOR_VERSION);
+    write_u16(&mut bytes, pool.next_index);
+    bytes.extend(&pool.bytes);
+    write_u16(&mut bytes, ACC_PUBLIC | ACC_SUPER);
+    write_u16(&mut bytes, this_class);
+    write_u16(&mut bytes, super_class);
+    // no interfaces
+    write_u16(&mut bytes, 0);
+    write_u16(&mut bytes, class.fields.len() as u16);
+    bytes.extend(fields);
+    write_u16(&mut bytes, class.methods.len() as u16);
+    bytes.extend(methods);
+    // the only attribute is `SourceFile`
+    write_u16(&mut bytes, 1);
+    bytes.extend(attributes);
+    Ok(bytes)
+}
+
+/// Splits a line into whitespace-separated tokens; double-quoted strings are unescaped and kept
+/// in one token, including the quotes. Comments start with a `;` at the beginning of a token.
+fn tokenize(text: &str, index: usize) -> Result<Vec<String>, AssemblerErr> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::from("\"");
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => s.push(
+                        ir::unescape(&mut chars)
+                            .ok_or_else(|| AssemblerErr::Syntax("invalid escape".into(), index))?,
+                    ),
+                    Some(c) => s.push(c),
+                    None => return Err(AssemblerErr::Syntax("unterminated string".into(), index)),
+                }
+            }
+            s.push('"');
+            tokens.push(s);
+        } else {
+            let mut s = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+            }
+            tokens.push(s);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses a type of a [`ir::Instruction::Frame`], like `Integer` or `Object java/lang/String`
+fn parse_verification_type(ty: &str, index: usize) -> Result<VerificationType, AssemblerErr> {
+    Ok(match ty.split_whitespace().collect::<Vec<_>>()[..] {
+        ["Top"] => VerificationType::Top,
+        ["Integer"] => VerificationType::Integer,
+        ["Float"] => VerificationType::Float,
+        ["Long"] => VerificationType::Long,
+        ["Double"] => VerificationType::Double,
+        ["Null"] => VerificationType::Null,
+        ["Object", class] => VerificationType::Object(class.to_owned()),
+        _ => {
+            return Err(AssemblerErr::Syntax(
+                format!("unsupported verification type `{ty}`"),
+                index,
+            ))
+        }
+    })
+}
+
+fn label_definition(tokens: &[String]) -> Option<&str> {
+    match tokens {
+        [label, colon] if colon == ":" => Some(label),
+        [label] => label.strip_suffix(':'),
+        _ => None,
+    }
+}
+
+fn expect_operand(tokens: &[String], position: usize, index: usize) -> Result<&str, AssemblerErr> {
+    tokens.get(position).map(String::as_str).ok_or_else(|| {
+        AssemblerErr::Syntax(format!("`{}` is missing an operand", tokens[0]), index)
+    })
+}
+
+fn parse_num<T: std::str::FromStr>(s: &str, index: usize) -> Result<T, AssemblerErr> {
+    s.parse()
+        .map_err(|_| AssemblerErr::Syntax(format!("`{s}` is not a valid number here"), index))
+}
+
+/// Splits `owner/name` at its last `/`
+fn split_member(s: &str, index: usize) -> Result<(&str, &str), AssemblerErr> {
+    s.rsplit_once('/')
+        .ok_or_else(|| AssemblerErr::Syntax(format!("expected `class/member`, found `{s}`"), index))
+}
+
+/// Splits `owner/name(arguments)return` into the owner, the name and the descriptor
+fn split_method(s: &str, index: usize) -> Result<(&str, &str, &str), AssemblerErr> {
+    let Some(paren) = s.find('(') else {
+        return Err(AssemblerErr::Syntax(format!("expected a method descriptor in `{s}`"), index));
+    };
+    let (owner, name) = split_member(&s[..paren], index)?;
+    Ok((owner, name, &s[paren..]))
+}
+
+fn parse_target(s: &str, index: usize) -> Result<Target, AssemblerErr> {
+    if let Some(offset) = s.strip_prefix('$') {
+        let offset = offset.strip_prefix('+').unwrap_or(offset);
+        Ok(Target::Relative(parse_num(offset, index)?))
+    } else {
+        Ok(Target::Label(s.to_owned()))
+    }
+}
+
+/// The opcode and the kind of operands of the instruction with the mnemonic `name`
+fn lookup(name: &str, index: usize) -> Result<(u8, OperandKind), AssemblerErr> {
+    INSTRUCTIONS
+        .iter()
+        .find(|(n, ..)| *n == name)
+        .map(|&(_, opcode, kind)| (opcode, kind))
+        .ok_or_else(|| AssemblerErr::UnknownInstruction(name.to_owned(), index))
+}
+
+/// Assembles one line of Jasmin that the IR didn't understand
+fn parse_instruction(tokens: &[String], index: usize, pool: &mut ConstantPool) -> Result<Instruction, AssemblerErr> {
+    let name = tokens[0].as_str();
+    let (opcode, kind) = lookup(name, index)?;
+    let operands = match kind {
+        K::None => Operands::None,
+        K::Byte => Operands::Byte(parse_num(expect_operand(tokens, 1, index)?, index)?),
+        K::Short => Operands::Short(parse_num(expect_operand(tokens, 1, index)?, index)?),
+        K::Local => Operands::Local(parse_num(expect_operand(tokens, 1, index)?, index)?),
+        K::Iinc => Operands::Iinc(
+            parse_num(expect_operand(tokens, 1, index)?, index)?,
+            parse_num(expect_operand(tokens, 2, index)?, index)?,
+        ),
+        K::Ldc => {
+            let value = expect_operand(tokens, 1, index)?;
+            if let Some(s) = value.strip_prefix('"') {
+                Operands::Ldc(pool.string(s.strip_suffix('"').unwrap_or(s)))
+            } else {
+                Operands::Ldc(pool.integer(parse_num(value, index)?))
+            }
+        }
+        K::Ldc2 => Operands::Pool(pool.wide_constant(expect_operand(tokens, 1, index)?, index)?),
+        K::Class => Operands::Pool(pool.class(expect_operand(tokens, 1, index)?)),
+        K::Field => {
+            let (owner, name) = split_member(expect_operand(tokens, 1, index)?, index)?;
+            let descriptor = expect_operand(tokens, 2, index)?;
+            Operands::Pool(pool.member(TAG_FIELD_REF, owner, name, descriptor))
+        }
+        K::Method => {
+            let (owner, name, descriptor) = split_method(expect_operand(tokens, 1, index)?, index)?;
+            Operands::Pool(pool.member(TAG_METHOD_REF, owner, name, descriptor))
+        }
+        K::InterfaceMethod => {
+            let (owner, name, descriptor) = split_method(expect_operand(tokens, 1, index)?, index)?;
+            Operands::InterfaceMethod(
+                pool.member(TAG_INTERFACE_METHOD_REF, owner, name, descriptor),
+                parse_num(expect_operand(tokens, 2, index)?, index)?,
+            )
+        }
+        K::Jump => Operands::Jump(parse_target(expect_operand(tokens, 1, index)?, index)?),
+        K::LookupSwitch => {
+            return Err(AssemblerErr::Syntax(
+                "`lookupswitch` spans several lines, which intrinsics can't use".into(),
+                index,
+            ))
+        }
+    };
+    Ok(Instruction {
+        opcode,
+        operands,
+        index,
+    })
+}
+
+/// Lowers the operands of an [`ir::Instruction::Op`], interning its constants
+fn lower_operands(
+    opcode: ir::Opcode,
+    kind: OperandKind,
+    operands: &[ir::Operand],
+    index: usize,
+    pool: &mut ConstantPool,
+) -> Result<Operands, AssemblerErr> {
+    use ir::Operand as O;
+    let out_of_range = |n: &dyn std::fmt::Display| {
+        AssemblerErr::Syntax(format!("`{n}` is out of range for `{}`", opcode.mnemonic()), index)
+    };
+    Ok(match (kind, operands) {
+        (K::None, []) => Operands::None,
+        (K::Byte, [O::Int(n)]) => Operands::Byte(i8::try_from(*n).map_err(|_| out_of_range(n))?),
+        (K::Short, [O::Int(n)]) => Operands::Short(i16::try_from(*n).map_err(|_| out_of_range(n))?),
+        (K::Local, [O::Local(n)]) => Operands::Local(u16::try_from(*n).map_err(|_| out_of_range(n))?),
+        (K::Ldc, [O::Int(n)]) => Operands::Ldc(pool.integer(*n)),
+        (K::Ldc, [O::String(s)]) => Operands::Ldc(pool.string(s)),
+        (K::Ldc2, [O::Long(n)]) => Operands::Pool(pool.long(*n)),
+        (K::Ldc2, [O::Double(d)]) => Operands::Pool(pool.double(*d)),
+        // written in an intrinsic
+        (K::Ldc2, [O::Int(n)]) => Operands::Pool(pool.long(*n as i64)),
+        (K::Ldc2, [O::Symbol(s)]) => Operands::Pool(pool.wide_constant(s, index)?),
+        (K::Class, [O::Symbol(class)]) => Operands::Pool(pool.class(class)),
+        (K::Field, [O::Symbol(field), O::Symbol(descriptor)]) => {
+            let (owner, name) = split_member(field, index)?;
+            Operands::Pool(pool.member(TAG_FIELD_REF, owner, name, descriptor))
+        }
+        (K::Method, [O::Symbol(method)]) => {
+            let (owner, name, descriptor) = split_method(method, index)?;
+            Operands::Pool(pool.member(TAG_METHOD_REF, owner, name, descriptor))
+        }
+        (K::InterfaceMethod, [O::Symbol(method), O::Int(count)]) => {
+            let (owner, name, descriptor) = split_method(method, index)?;
+            Operands::InterfaceMethod(
+                pool.member(TAG_INTERFACE_METHOD_REF, owner, name, descriptor),
+                u8::try_from(*count).map_err(|_| out_of_range(count))?,
+            )
+        }
+        (K::Jump, [O::Label(label)]) => Operands::Jump(Target::Label(label.to_string())),
+        // written in an intrinsic
+        (K::Jump, [O::Symbol(target)]) => Operands::Jump(parse_target(target, index)?),
+        _ => {
+            return Err(AssemblerErr::Syntax(
+                format!("unexpected operands for `{}`: {operands:?}", opcode.mnemonic()),
+                index,
+            ))
+        }
+    })
+}
+
+impl Instruction {
+    /// The size in bytes, when placed at `offset`
+    fn size(&self, offset: usize) -> usize {
+        match &self.operands {
+            Operands::None => 1,
+            Operands::Byte(_) => 2,
+            Operands::Short(_) | Operands::Pool(_) | Operands::Jump(_) => 3,
+            Operands::Local(n) if *n > u8::MAX as u16 => 4,
+            Operands::Local(_) => 2,
+            Operands::Iinc(n, c) if *n > u8::MAX as u16 || i8::try_from(*c).is_err() => 6,
+            Operands::Iinc(..) => 3,
+            Operands::Ldc(i) if *i > u8::MAX as u16 || self.opcode == OP_LDC_W => 3,
+            Operands::Ldc(_) => 2,
+            Operands::InterfaceMethod(..) => 5,
+            Operands::LookupSwitch(pairs, _) => 1 + padding(offset) + 8 + 8 * pairs.len(),
+        }
+    }
+
+    fn targets(&self) -> Vec<&Target> {
+        match &self.operands {
+            Operands::Jump(t) => vec![t],
+            Operands::LookupSwitch(pairs, default) => {
+                pairs.iter().map(|(_, t)| t).chain([default]).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn write(
+        &self,
+        code: &mut Vec<u8>,
+        offset: usize,
+        label_offsets: &HashMap<&str, usize>,
+    ) -> Result<(), AssemblerErr> {
+        let target = |t: &Target| -> Result<i64, AssemblerErr> {
+            Ok(match t {
+                Target::Label(label) => {
+                    let Some(target) = label_offsets.get(label.as_str()) else {
+                        return Err(AssemblerErr::UnknownLabel(label.clone(), self.index));
+                    };
+                    *target as i64 - offset as i64
+                }
+                Target::Relative(n) => *n as i64,
+            })
+        };
+        match &self.operands {
+            Operands::None => code.push(self.opcode),
+            Operands::Byte(b) => code.extend([self.opcode, *b as u8]),
+            Operands::Short(s) => {
+                code.push(self.opcode);
+                code.extend(s.to_be_bytes());
+            }
+            Operands::Local(n) => {
+                if *n > u8::MAX as u16 {
+                    code.extend([OP_WIDE, self.opcode]);
+                    code.extend(n.to_be_bytes());
+                } else {
+                    code.extend([self.opcode, *n as u8]);
+                }
+            }
+            Operands::Iinc(n, c) => {
+                if self.size(offset) == 6 {
+                    code.extend([OP_WIDE, self.opcode]);
+                    code.extend(n.to_be_bytes());
+                    code.extend(c.to_be_bytes());
+                } else {
+                    code.extend([self.opcode, *n as u8, *c as i8 as u8]);
+                }
+            }
+            Operands::Ldc(i) => {
+                if self.size(offset) == 3 {
+                    code.push(OP_LDC_W);
+                    code.extend(i.to_be_bytes());
+                } else {
+                    code.extend([OP_LDC, *i as u8]);
+                }
+            }
+            Operands::Pool(i) => {
+                code.push(self.opcode);
+                code.extend(i.to_be_bytes());
+            }
+            Operands::InterfaceMethod(i, count) => {
+                code.push(self.opcode);
+                code.extend(i.to_be_bytes());
+                code.extend([*count, 0]);
+            }
+            Operands::Jump(t) => {
+                let jump = target(t)?;
+                let jump = i16::try_from(jump).map_err(|_| AssemblerErr::JumpOutOfRange(jump, self.index))?;
+                code.push(self.opcode);
+                code.extend(jump.to_be_bytes());
+            }
+            Operands::LookupSwitch(pairs, default) => {
+                code.push(self.opcode);
+                code.extend(std::iter::repeat_n(0, padding(offset)));
+                let mut pairs = pairs
+                    .iter()
+                    .map(|(key, t)| Ok((*key, target(t)?)))
+                    .collect::<Result<Vec<_>, AssemblerErr>>()?;
+                pairs.sort_by_key(|(key, _)| *key);
+                code.extend((target(default)? as i32).to_be_bytes());
+                code.extend((pairs.len() as i32).to_be_bytes());
+                for (key, jump) in pairs {
+                    code.extend(key.to_be_bytes());
+                    code.extend((jump as i32).to_be_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `lookupswitch` operands are aligned to four bytes from the start of the code
+fn padding(offset: usize) -> usize {
+    (4 - (offset + 1) % 4) % 4
+}
+
+impl Default for ConstantPool {
+    fn default() -> Self {
+        Self {
+            bytes: Vec::new(),
+            next_index: 1,
+            indices: HashMap::new(),
+        }
+    }
+}
+
+impl ConstantPool {
+    fn intern(&mut self, tag: u8, key: String, entry: &[u8]) -> u16 {
+        if let Some(index) = self.indices.get(&(tag, key.clone())) {
+            return *index;
+        }
+        let index = self.next_index;
+        // longs and doubles take up two entries
+        self.next_index += if tag == TAG_LONG || tag == TAG_DOUBLE { 2 } else { 1 };
+        self.bytes.push(tag);
+        self.bytes.extend(entry);
+        self.indices.insert((tag, key), index);
+        index
+    }
+
+    fn utf8(&mut self, s: &str) -> u16 {
+        let encoded = modified_utf8(s);
+        let mut entry = (encoded.len() as u16).to_be_bytes().to_vec();
+        entry.extend(encoded);
+        self.intern(TAG_UTF8, s.to_owned(), &entry)
+    }
+
+    fn integer(&mut self, n: i32) -> u16 {
+        self.intern(TAG_INTEGER, n.to_string(), &n.to_be_bytes())
+    }
+
+    fn long(&mut self, n: i64) -> u16 {
+        self.intern(TAG_LONG, n.to_string(), &n.to_be_bytes())
+    }
+
+    fn double(&mut self, d: f64) -> u16 {
+        self.intern(TAG_DOUBLE, d.to_bits().to_string(), &d.to_be_bytes())
+    }
+
+    /// The operand of `ldc2_w` written in Jasmin: a long, or a double if it doesn't parse as one
+    fn wide_constant(&mut self, value: &str, index: usize) -> Result<u16, AssemblerErr> {
+        match value.parse() {
+            Ok(n) => Ok(self.long(n)),
+            Err(_) => Ok(self.double(parse_num(value, index)?)),
+        }
+    }
+
+    fn string(&mut self, s: &str) -> u16 {
+        let utf8 = self.utf8(s);
+        self.intern(TAG_STRING, s.to_owned(), &utf8.to_be_bytes())
+    }
+
+    fn class(&mut self, name: &str) -> u16 {
+        let utf8 = self.utf8(name);
+        self.intern(TAG_CLASS, name.to_owned(), &utf8.to_be_bytes())
+    }
+
+    fn name_and_type(&mut self, name: &str, descriptor: &str) -> u16 {
+        let name_index = self.utf8(name);
+        let descriptor_index = self.utf8(descriptor);
+        let mut entry = name_index.to_be_bytes().to_vec();
+        entry.extend(descriptor_index.to_be_bytes());
+        self.intern(TAG_NAME_AND_TYPE, format!("{name}:{descriptor}"), &entry)
+    }
+
+    /// A field, method or interface method reference, depending on `tag`
+    fn member(&mut self, tag: u8, owner: &str, name: &str, descriptor: &str) -> u16 {
+        let class_index = self.class(owner);
+        let name_and_type_index = self.name_and_type(name, descriptor);
+        let mut entry = class_index.to_be_bytes().to_vec();
+        entry.extend(name_and_type_index.to_be_bytes());
+        self.intern(tag, format!("{owner}/{name}:{descriptor}"), &entry)
+    }
+}
+
+/// The JVM's variant of UTF-8: `\0` takes two bytes and supplementary characters are encoded as
+/// surrogate pairs
+fn modified_utf8(s: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(s.len());
+    for unit in s.encode_utf16() {
+        match unit {
+            0x0001..=0x007f => bytes.push(unit as u8),
+            0x0000 | 0x0080..=0x07ff => {
+                bytes.push(0xc0 | (unit >> 6) as u8);
+                bytes.push(0x80 | (unit & 0x3f) as u8);
+            }
+            _ => {
+                bytes.push(0xe0 | (unit >> 12) as u8);
+                bytes.push(0x80 | ((unit >> 6) & 0x3f) as u8);
+                bytes.push(0x80 | (unit & 0x3f) as u8);
+            }
+        }
+    }
+    bytes
+}
+
+/// The number of local variable slots taken by the arguments of a method
+fn argument_slots(descriptor: &str, is_static: bool) -> u16 {
+    let mut slots = if is_static { 0 } else { 1 };
+    let mut chars = descriptor.trim_start_matches('(').chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ')' => break,
+            'J' | 'D' => slots += 2,
+            'L' => {
+                chars.by_ref().find(|c| *c == ';');
+                slots += 1;
+            }
+            '[' => {
+                // the element type belongs to this one slot
+                let mut element = chars.next();
+                while element == Some('[') {
+                    element = chars.next();
+                }
+                if element == Some('L') {
+                    chars.by_ref().find(|c| *c == ';');
+                }
+                slots += 1;
+            }
+            _ => slots += 1,
+        }
+    }
+    slots
+}
+
+fn write_u16(bytes: &mut Vec<u8>, n: u16) {
+    bytes.extend(n.to_be_bytes());
+}
+
+fn write_u32(bytes: &mut Vec<u8>, n: u32) {
+    bytes.extend(n.to_be_bytes());
+}
+
+impl VerificationType {
+    fn write(&self, bytes: &mut Vec<u8>, pool: &mut ConstantPool) {
+        match self {
+            Self::Top => bytes.push(ITEM_TOP),
+            Self::Integer => bytes.push(ITEM_INTEGER),
+            Self::Float => bytes.push(ITEM_FLOAT),
+            Self::Long => bytes.push(ITEM_LONG),
+            Self::Double => bytes.push(ITEM_DOUBLE),
+            Self::Null => bytes.push(ITEM_NULL),
+            Self::Object(class) => {
+                bytes.push(ITEM_OBJECT);
+                write_u16(bytes, pool.class(class));
+            }
+        }
+    }
+}
+
+impl Method {
+    /// Lowers the IR of `method`, interning its constants into `pool`
+    fn lower(method: &ir::Method, pool: &mut ConstantPool) -> Result<Self, AssemblerErr> {
+        let limit = |n: usize| {
+            u16::try_from(n).map_err(|_| AssemblerErr::Syntax(format!("limit {n} is out of range"), 0))
+        };
+        let mut lowered = Method {
+            access_flags: if method.is_static { ACC_PUBLIC | ACC_STATIC } else { ACC_PUBLIC },
+            name: method.name.clone(),
+            descriptor: method.descriptor.clone(),
+            max_stack: limit(method.max_stack)?,
+            max_locals: limit(method.max_locals)?,
+            ..Default::default()
+        };
+        for (index, instruction) in method.code.iter().enumerate() {
+            let next = lowered.instructions.len();
+            match instruction {
+                ir::Instruction::Op(opcode, operands) => {
+                    let (byte, kind) = lookup(opcode.mnemonic(), index)?;
+                    lowered.instructions.push(Instruction {
+                        opcode: byte,
+                        operands: lower_operands(*opcode, kind, operands, index, pool)?,
+                        index,
+                    });
+                }
+                ir::Instruction::LookupSwitch { arms, default } => {
+                    let arms = arms
+                        .iter()
+                        .map(|(key, label)| (*key, Target::Label(label.to_string())))
+                        .collect();
+                    lowered.instructions.push(Instruction {
+                        opcode: OP_LOOKUP_SWITCH,
+                        operands: Operands::LookupSwitch(arms, Target::Label(default.to_string())),
+                        index,
+                    });
+                }
+                ir::Instruction::Label(label) => {
+                    lowered.labels.insert(label.to_string(), next);
+                }
+                ir::Instruction::Frame { locals, stack } => {
+                    let parse = |types: &[String]| {
+                        types
+                            .iter()
+                            .map(|ty| parse_verification_type(ty, index))
+                            .collect::<Result<Vec<_>, _>>()
+                    };
+                    let frame = Frame {
+                        locals: parse(locals)?,
+                        stack: parse(stack)?,
+                        index,
+                    };
+                    lowered.frames.push((next, frame));
+                }
+                ir::Instruction::Line(row) => {
+                    // the `LineNumberTable` can't point beyond this
+                    if let Ok(row) = u16::try_from(*row) {
+                        lowered.lines.push((next, row));
+                    }
+                }
+                ir::Instruction::Catch { class, from, to, handler } => lowered.catches.push(Catch {
+                    class: class.clone(),
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    handler: handler.to_string(),
+                    index,
+                }),
+                ir::Instruction::Raw(text) => {
+                    let tokens = tokenize(text, index)?;
+                    if tokens.is_empty() {
+                        continue;
+                    } else if let Some(label) = label_definition(&tokens) {
+                        lowered.labels.insert(label.to_owned(), next);
+                    } else {
+                        lowered.instructions.push(parse_instruction(&tokens, index, pool)?);
+                    }
+                }
+            }
+        }
+        Ok(lowered)
+    }
+
+    /// Sorts the frames by their offset. Several frames at one offset (like the end labels of
+    /// nested blocks) are merged, keeping only the local variables all of them agree on.
+    fn frames_by_offset(&self, offsets: &[usize]) -> Result<Vec<(usize, Frame)>, AssemblerErr> {
+        let mut frames = self
+            .frames
+            .iter()
+            .map(|(i, frame)| (offsets[*i], frame.clone()))
+            .collect::<Vec<_>>();
+        frames.sort_by_key(|(offset, _)| *offset);
+        let mut merged: Vec<(usize, Frame)> = Vec::with_capacity(frames.len());
+        for (offset, frame) in frames {
+            match merged.last_mut() {
+                Some((last_offset, last)) if *last_offset == offset => {
+                    if last.stack != frame.stack {
+                        return Err(AssemblerErr::Syntax(
+                            format!("stack map frame conflicts with the one at instruction {}", last.index),
+                            frame.index,
+                        ));
+                    }
+                    let common = last
+                        .locals
+                        .iter()
+                        .zip(&frame.locals)
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    last.locals.truncate(common);
+                }
+                _ => merged.push((offset, frame)),
+            }
+        }
+        Ok(merged)
+    }
+
+    fn write(&self, pool: &mut ConstantPool) -> Result<Vec<u8>, AssemblerErr> {
+        let mut offsets = Vec::with_capacity(self.instructions.len() + 1);
+        let mut offset = 0;
+        for instruction in &self.instructions {
+            offsets.push(offset);
+            offset += instruction.size(offset);
+        }
+        offsets.push(offset);
+        let label_offsets = self
+            .labels
+            .iter()
+            .map(|(label, i)| (label.as_str(), offsets[*i]))
+            .collect::<HashMap<_, _>>();
+
+        let mut code = Vec::with_capacity(offset);
+        for (instruction, offset) in self.instructions.iter().zip(&offsets) {
+            instruction.write(&mut code, *offset, &label_offsets)?;
+        }
+
+        let mut exception_table = Vec::new();
+        for catch in &self.catches {
+            let offset_of = |label: &String| {
+                label_offsets
+                    .get(label.as_str())
+                    .copied()
+                    .ok_or_else(|| AssemblerErr::UnknownLabel(label.clone(), catch.index))
+            };
+            let (from, to, handler) = (offset_of(&catch.from)?, offset_of(&catch.to)?, offset_of(&catch.handler)?);
+            // the JVM rejects empty ranges, which nothing could throw in anyway
+            if from < to {
+                exception_table.push((from, to, handler, catch));
+            }
+        }
+
+        let frames = self.frames_by_offset(&offsets)?;
+        // the JVM would only reject the class when loading it
+        for (instruction, offset) in self.instructions.iter().zip(&offsets) {
+            for t in instruction.targets() {
+                let target = match t {
+                    Target::Label(label) => label_offsets[label.as_str()],
+                    Target::Relative(n) => (*offset as i64 + *n as i64) as usize,
+                };
+                if frames.binary_search_by_key(&target, |(o, _)| *o).is_err() {
+                    return Err(AssemblerErr::MissingFrame(target, instruction.index));
+                }
+            }
+        }
+        for (_, _, handler, catch) in &exception_table {
+            if frames.binary_search_by_key(handler, |(o, _)| *o).is_err() {
+                return Err(AssemblerErr::MissingFrame(*handler, catch.index));
+            }
+        }
+
+        let mut line_numbers: Vec<(u16, u16)> = Vec::new();
+        for (i, row) in &self.lines {
+            let pc = offsets[*i] as u16;
+            match line_numbers.last_mut() {
+                Some(last) if last.0 == pc => last.1 = *row,
+                _ => line_numbers.push((pc, *row)),
+            }
+        }
+        // a line after the last instruction doesn't belong to any code
+        line_numbers.retain(|(pc, _)| (*pc as usize) < code.len());
+
+        let mut attributes = Vec::new();
+        let mut attributes_count = 0;
+        if !frames.is_empty() {
+            attributes_count += 1;
+            let mut table = Vec::new();
+            write_u16(&mut table, frames.len() as u16);
+            let mut previous: Option<usize> = None;
+            for (offset, frame) in &frames {
+                // every frame but the first describes the instruction `offset_delta + 1` bytes
+                // after the previous one
+                let delta = previous.map_or(*offset, |previous| offset - previous - 1);
+                previous = Some(*offset);
+                table.push(FRAME_FULL);
+                write_u16(&mut table, delta as u16);
+                write_u16(&mut table, frame.locals.len() as u16);
+                for ty in &frame.locals {
+                    ty.write(&mut table, pool);
+                }
+                write_u16(&mut table, frame.stack.len() as u16);
+                for ty in &frame.stack {
+                    ty.write(&mut table, pool);
+                }
+            }
+            write_u16(&mut attributes, pool.utf8("StackMapTable"));
+            write_u32(&mut attributes, table.len() as u32);
+            attributes.extend(table);
+        }
+        if !line_numbers.is_empty() {
+            attributes_count += 1;
+            write_u16(&mut attributes, pool.utf8("LineNumberTable"));
+            write_u32(&mut attributes, 2 + 4 * line_numbers.len() as u32);
+            write_u16(&mut attributes, line_numbers.len() as u16);
+            for (pc, row) in line_numbers {
+                write_u16(&mut attributes, pc);
+                write_u16(&mut attributes, row);
+            }
+        }
+
+        let is_static = self.access_flags & ACC_STATIC != 0;
+        let mut code_attribute = Vec::new();
+        write_u16(&mut code_attribute, self.max_stack);
+        // never fewer than the arguments take up
+        write_u16(&mut code_attribute, self.max_locals.max(argument_slots(&self.descriptor, is_static)));
+        write_u32(&mut code_attribute, code.len() as u32);
+        code_attribute.extend(code);
+        write_u16(&mut code_attribute, exception_table.len() as u16);
+        for (from, to, handler, catch) in exception_table {
+            write_u16(&mut code_attribute, from as u16);
+            write_u16(&mut code_attribute, to as u16);
+            write_u16(&mut code_attribute, handler as u16);
+            write_u16(&mut code_attribute, pool.class(&catch.class));
+        }
+        write_u16(&mut code_attribute, attributes_count);
+        code_attribute.extend(attributes);
+
+        let mut bytes = Vec::new();
+        write_u16(&mut bytes, self.access_flags);
+        write_u16(&mut bytes, pool.utf8(&self.name));
+        write_u16(&mut bytes, pool.utf8(&self.descriptor));
+        // the only attribute is `Code`
+        write_u16(&mut bytes, 1);
+        write_u16(&mut bytes, pool.utf8("Code"));
+        write_u32(&mut bytes, code_attribute.len() as u32);
+        bytes.extend(code_attribute);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{Label, Opcode, Operand};
+
+    fn label(id: usize) -> Label {
+        Label { id, name: "L" }
+    }
+
+    /// The `code` of the `Code` attribute in the bytes of a method
+    fn code(method: &[u8]) -> &[u8] {
+        // flags, name, descriptor, attribute count, attribute name and length, max stack and locals
+        let start = 2 + 2 + 2 + 2 + 2 + 4 + 2 + 2;
+        let len = u32::from_be_bytes(method[start..start + 4].try_into().unwrap()) as usize;
+        &method[start + 4..start + 4 + len]
+    }
+
+    #[test]
+    fn constant_pool_dedup() {
+        let mut pool = ConstantPool::default();
+        let s = pool.string("a");
+        assert_eq!(pool.string("a"), s);
+        // the string shares its utf8 entry with the class name
+        let class = pool.class("a");
+        assert_eq!(pool.next_index, 4);
+        assert_eq!(pool.class("a"), class);
+        let member = pool.member(TAG_METHOD_REF, "a", "b", "()V");
+        assert_eq!(pool.member(TAG_METHOD_REF, "a", "b", "()V"), member);
+        assert_ne!(pool.member(TAG_INTERFACE_METHOD_REF, "a", "b", "()V"), member);
+        // an int and a string with the same spelling are different constants
+        assert_ne!(pool.integer(1), pool.string("1"));
+    }
+
+    #[test]
+    fn wide_constants_take_two_entries() {
+        let mut pool = ConstantPool::default();
+        assert_eq!(pool.long(1), 1);
+        assert_eq!(pool.double(1.0), 3);
+        assert_eq!(pool.integer(1), 5);
+        assert_eq!(pool.long(1), 1);
+    }
+
+    #[test]
+    fn ldc_and_ldc_w_sizes() {
+        let mut pool = ConstantPool::default();
+        let narrow = Instruction {
+            opcode: OP_LDC,
+            operands: Operands::Ldc(pool.integer(0)),
+            index: 0,
+        };
+        (1..300).for_each(|n| {
+            pool.integer(n);
+        });
+        let wide = Instruction {
+            opcode: OP_LDC,
+            operands: Operands::Ldc(pool.integer(299)),
+            index: 1,
+        };
+        assert_eq!(narrow.size(0), 2);
+        assert_eq!(wide.size(0), 3);
+
+        let mut code = Vec::new();
+        narrow.write(&mut code, 0, &HashMap::new()).unwrap();
+        wide.write(&mut code, 2, &HashMap::new()).unwrap();
+        assert_eq!(code, [OP_LDC, 1, OP_LDC_W, 1, 44]);
+    }
+
+    #[test]
+    fn branch_offsets() {
+        let frame = || ir::Instruction::Frame {
+            locals: Vec::new(),
+            stack: Vec::new(),
+        };
+        let mut method = ir::Method::new("f".into(), "()V".into());
+        method.max_stack = 1;
+        method.code = vec![
+            ir::Instruction::Label(label(0)),
+            frame(),
+            ir::Instruction::op(Opcode::IConst0),
+            ir::Instruction::Op(Opcode::IfEq, vec![Operand::Label(label(1))]),
+            ir::Instruction::Op(Opcode::Goto, vec![Operand::Label(label(0))]),
+            ir::Instruction::Label(label(1)),
+            frame(),
+            ir::Instruction::op(Opcode::Return),
+        ];
+        let mut pool = ConstantPool::default();
+        let bytes = Method::lower(&method, &mut pool).unwrap().write(&mut pool).unwrap();
+        // `ifeq` at 1 jumps forward to 7, `goto` at 4 back to 0
+        assert_eq!(code(&bytes), [0x03, 0x99, 0, 6, 0xa7, 0xff, 0xfc, 0xb1]);
+    }
+
+    #[test]
+    fn frames_at_the_same_offset_are_merged() {
+        let frame = |locals, index| Frame {
+            locals,
+            stack: vec![VerificationType::Integer],
+            index,
+        };
+        let method = Method {
+            frames: vec![
+                (1, frame(vec![VerificationType::Integer], 2)),
+                (0, frame(vec![VerificationType::Integer, VerificationType::Long], 0)),
+                (0, frame(vec![VerificationType::Integer, VerificationType::Double], 1)),
+            ],
+            ..Default::default()
+        };
+        let frames = method.frames_by_offset(&[0, 1]).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], (0, frame(vec![VerificationType::Integer], 0)));
+        assert_eq!(frames[1].0, 1);
+
+        let conflicting = Method {
+            frames: vec![
+                (0, frame(Vec::new(), 0)),
+                (0, Frame { stack: Vec::new(), ..frame(Vec::new(), 1) }),
+            ],
+            ..Default::default()
+        };
+        assert!(conflicting.frames_by_offset(&[0]).is_err());
+    }
+
+    #[test]
+    fn modified_utf8_encoding() {
+        assert_eq!(modified_utf8("a"), b"a");
+        assert_eq!(modified_utf8("\0"), [0xc0, 0x80]);
+        assert_eq!(modified_utf8("é"), "é".as_bytes());
+        assert_eq!(modified_utf8("€"), "€".as_bytes());
+        // U+1F600 is the surrogate pair D83D DE00, each encoded on its own
+        assert_eq!(modified_utf8("😀"), [0xed, 0xa0, 0xbd, 0xed, 0xb8, 0x80]);
+    }
+}
@@ -0,0 +1,723 @@
+//! Checks the Java classes, methods and fields a program refers to against `.class` files and
+//! `.jar` or `.jmod` archives on the local filesystem, so that a typo in a descriptor is reported
+//! at compile time instead of as a `NoSuchMethodError` when the program runs.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use thiserror::Error;
+
+use crate::{
+    analyzer::Type,
+    ast::{AstBase, AstItem, AstNode, MatchInType, MatchOutType},
+    diagnostics::{Diagnostic, Span},
+};
+
+#[derive(Error, Debug)]
+pub enum ClassPathErr {
+    #[error("cannot read `{0}`: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("cannot read `{0}`: {1}")]
+    Malformed(PathBuf, String),
+    #[error("class `{0}` is not on the classpath")]
+    UnknownClass(String, Span),
+    #[error("method `{0}` is not on the classpath")]
+    UnknownMethod(String, Span),
+    #[error("field `{0}` is not on the classpath")]
+    UnknownField(String, Span),
+}
+
+impl ClassPathErr {
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            Self::Io(..) | Self::Malformed(..) => Diagnostic::error(self.to_string(), None),
+            Self::UnknownClass(_, span)
+            | Self::UnknownMethod(_, span)
+            | Self::UnknownField(_, span) => Diagnostic::error(self.to_string(), Some(*span)),
+        }
+    }
+}
+
+/// Where the bytes of a class are
+#[derive(Clone, Debug)]
+enum ClassSource {
+    File(PathBuf),
+    /// An entry of the archive at the index into [`ClassPath::archives`]
+    Entry(usize, ArchiveEntry),
+}
+
+/// What the check needs to know about a class
+#[derive(Debug, Default)]
+struct ClassInfo {
+    super_class: Option<String>,
+    interfaces: Vec<String>,
+    /// `name` and `descriptor` of every method
+    methods: HashSet<(String, String)>,
+    /// `name` and `descriptor` of every field
+    fields: HashSet<(String, String)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MemberKind {
+    Method,
+    Field,
+}
+
+/// The classes found in the directories and archives of the classpath. Classes are only read
+/// when they are looked up.
+#[derive(Debug, Default)]
+pub struct ClassPath {
+    /// Internal names like `java/lang/String`; the first entry on the classpath wins, like in Java
+    sources: HashMap<String, ClassSource>,
+    archives: Vec<(PathBuf, Vec<u8>)>,
+    classes: RefCell<HashMap<String, Rc<ClassInfo>>>,
+}
+
+impl ClassPath {
+    /// Indexes the classes in `paths`. A directory holds `.class` files in the folders of their
+    /// packages, and the `.jar` and `.jmod` files directly inside of it are read as well, so that
+    /// the `jmods` directory of a JDK can be passed as is. Any other path is read as an archive.
+    pub fn load(paths: &[PathBuf]) -> Result<Self, ClassPathErr> {
+        let mut class_path = Self::default();
+        for path in paths.iter().flat_map(std::env::split_paths) {
+            if path.is_dir() {
+                class_path.add_dir(&path, &path)?;
+                let mut archives = read_dir(&path)?
+                    .into_iter()
+                    .filter(|p| p.is_file() && is_archive(p))
+                    .collect::<Vec<_>>();
+                archives.sort();
+                for archive in archives {
+                    class_path.add_archive(archive)?;
+                }
+            } else {
+                class_path.add_archive(path)?;
+            }
+        }
+        Ok(class_path)
+    }
+
+    fn add_dir(&mut self, root: &Path, dir: &Path) -> Result<(), ClassPathErr> {
+        for path in read_dir(dir)? {
+            if path.is_dir() {
+                self.add_dir(root, &path)?;
+            } else if path.extension().is_some_and(|e| e == "class") {
+                let name = path.strip_prefix(root).unwrap().with_extension("");
+                let name = name.iter().map(|s| s.to_string_lossy()).collect::<Vec<_>>().join("/");
+                self.sources.entry(name).or_insert(ClassSource::File(path));
+            }
+        }
+        Ok(())
+    }
+
+    fn add_archive(&mut self, path: PathBuf) -> Result<(), ClassPathErr> {
+        let bytes = fs::read(&path).map_err(|e| ClassPathErr::Io(path.clone(), e))?;
+        let entries =
+            zip_entries(&bytes).map_err(|e| ClassPathErr::Malformed(path.clone(), e))?;
+        let index = self.archives.len();
+        for entry in entries {
+            // the classes of a jmod are in `classes/`
+            let name = entry.name.strip_prefix("classes/").unwrap_or(&entry.name);
+            let Some(name) = name.strip_suffix(".class") else {
+                continue;
+            };
+            if name.starts_with("META-INF/") || name.ends_with("module-info") {
+                continue;
+            }
+            self.sources.entry(name.into()).or_insert(ClassSource::Entry(index, entry.clone()));
+        }
+        self.archives.push((path, bytes));
+        Ok(())
+    }
+
+    /// The class with the internal name `name`, or `None` if it isn't on the classpath
+    fn class(&self, name: &str) -> Result<Option<Rc<ClassInfo>>, ClassPathErr> {
+        if let Some(class) = self.classes.borrow().get(name) {
+            return Ok(Some(Rc::clone(class)));
+        }
+        let Some(source) = self.sources.get(name) else {
+            return Ok(None);
+        };
+        let (path, class) = match source {
+            ClassSource::File(path) => {
+                let bytes = fs::read(path).map_err(|e| ClassPathErr::Io(path.clone(), e))?;
+                (path, parse_class(&bytes))
+            }
+            ClassSource::Entry(index, entry) => {
+                let (path, archive) = &self.archives[*index];
+                (path, entry.read(archive).and_then(|bytes| parse_class(&bytes)))
+            }
+        };
+        let class = Rc::new(class.map_err(|e| ClassPathErr::Malformed(path.clone(), e))?);
+        self.classes.borrow_mut().insert(name.into(), Rc::clone(&class));
+        Ok(Some(class))
+    }
+
+    /// Whether the class `owner`, one of its superclasses or one of their interfaces declares
+    /// the member, like the JVM resolves it
+    fn has_member(
+        &self,
+        owner: &str,
+        name: &str,
+        descriptor: &str,
+        kind: MemberKind,
+    ) -> Result<bool, ClassPathErr> {
+        let mut todo = vec![owner.to_string()];
+        let mut seen = HashSet::new();
+        while let Some(class_name) = todo.pop() {
+            if !seen.insert(class_name.clone()) {
+                continue;
+            }
+            let Some(class) = self.class(&class_name)? else {
+                continue;
+            };
+            let members = match kind {
+                MemberKind::Method => &class.methods,
+                MemberKind::Field => &class.fields,
+            };
+            if members.contains(&(name.into(), descriptor.into())) {
+                return Ok(true);
+            }
+            todo.extend(class.super_class.iter().chain(&class.interfaces).cloned());
+        }
+        Ok(false)
+    }
+
+    /// Checks every Java class, method and field the analyzed program refers to. Only the arms of
+    /// type switches that are compiled are checked.
+    pub fn check(&self, ast: &AstBase) -> Vec<ClassPathErr> {
+        let mut check = Check {
+            class_path: self,
+            records: ast
+                .nodes
+                .iter()
+                .filter_map(|n| match &n.inner {
+                    AstItem::Record { name, .. } => Some(name.clone()),
+                    _ => None,
+                })
+                .collect(),
+            errs: Vec::new(),
+        };
+        for node in &ast.nodes {
+            check.node(node);
+        }
+        check.errs
+    }
+}
+
+/// The state of [`ClassPath::check`]
+struct Check<'a> {
+    class_path: &'a ClassPath,
+    /// The records of the program are classes, but not on the classpath
+    records: HashSet<String>,
+    errs: Vec<ClassPathErr>,
+}
+
+impl Check<'_> {
+    fn node(&mut self, node: &AstNode) {
+        let span = node.span();
+        match &node.inner {
+            AstItem::ListLiteral(nodes) | AstItem::Block(nodes) => {
+                nodes.iter().for_each(|n| self.node(n));
+            }
+            AstItem::If { head, body, else_body } => {
+                head.iter().chain([body]).chain(else_body).for_each(|n| self.node(n));
+            }
+            AstItem::Switch { arms, default } => {
+                arms.iter().for_each(|(_, n)| self.node(n));
+                self.node(default);
+            }
+            AstItem::While { head, body } => {
+                head.iter().chain([body]).for_each(|n| self.node(n));
+            }
+            AstItem::For { init, condition, modifier, body } => {
+                [init, condition, modifier, body].into_iter().for_each(|n| self.node(n));
+            }
+            AstItem::Try { body, catches, .. } => {
+                self.node(body);
+                for (class, n) in catches {
+                    self.class(class, span);
+                    self.node(n);
+                }
+            }
+            AstItem::Store { initializer: Some(init), .. }
+            | AstItem::SetField { initializer: Some(init), .. } => self.node(init),
+            AstItem::TypeSwitch { arms, chosen_index: Some(i) } => self.node(&arms[*i].1),
+            AstItem::List(ty) => self.ty(ty, span),
+            AstItem::Map(key, value) => {
+                self.ty(key, span);
+                self.ty(value, span);
+            }
+            AstItem::Function { signature, body, .. } => {
+                signature.input.iter().chain(&signature.output).for_each(|t| self.ty(t, span));
+                self.node(body);
+            }
+            AstItem::Record { fields, .. } => fields.iter().for_each(|(_, t)| self.ty(t, span)),
+            AstItem::Extern { kind, target, input, output, .. } => {
+                input.iter().chain(output).for_each(|t| self.ty(&t.ty, span));
+                let method = kind.java_method(target, input, output);
+                self.member(&method, None, MemberKind::Method, span);
+            }
+            AstItem::Jasmin { input, output, body, .. } => {
+                input.iter().for_each(|t| self.in_type(t, span));
+                output.iter().for_each(|t| self.out_type(t, span));
+                self.jasmin(body, span);
+            }
+            _ => {}
+        }
+    }
+
+    /// Checks the instructions of an intrinsic that refer to classes, methods and fields
+    fn jasmin(&mut self, body: &str, span: Span) {
+        for line in body.lines() {
+            let tokens = line.split_whitespace().collect::<Vec<_>>();
+            match tokens.as_slice() {
+                [
+                    "invokestatic" | "invokevirtual" | "invokespecial" | "invokenonvirtual"
+                    | "invokeinterface",
+                    method,
+                    ..
+                ] => self.member(method, None, MemberKind::Method, span),
+                ["getstatic" | "putstatic" | "getfield" | "putfield", field, descriptor, ..] => {
+                    self.member(field, Some(descriptor), MemberKind::Field, span);
+                }
+                // array classes are made up by the JVM
+                ["new" | "checkcast" | "instanceof" | "anewarray", class, ..]
+                    if !class.starts_with('[') =>
+                {
+                    self.class(class, span);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Checks `owner/name(arguments)result` of a method, or `owner/name` of a field with its
+    /// `descriptor`
+    fn member(&mut self, member: &str, descriptor: Option<&str>, kind: MemberKind, span: Span) {
+        let (path, descriptor) = match descriptor {
+            Some(descriptor) => (member, descriptor),
+            None => member.split_at(member.find('(').unwrap_or(member.len())),
+        };
+        let Some((owner, name)) = path.rsplit_once('/') else {
+            return;
+        };
+        if !self.class(owner, span) {
+            return;
+        }
+        match self.class_path.has_member(owner, name, descriptor, kind) {
+            Ok(true) => {}
+            Ok(false) => self.errs.push(match kind {
+                MemberKind::Method => ClassPathErr::UnknownMethod(member.into(), span),
+                MemberKind::Field => {
+                    ClassPathErr::UnknownField(format!("{member} {descriptor}"), span)
+                }
+            }),
+            Err(e) => self.errs.push(e),
+        }
+    }
+
+    /// Checks that the class exists and returns whether it does
+    fn class(&mut self, name: &str, span: Span) -> bool {
+        if self.records.contains(name) {
+            return true;
+        }
+        match self.class_path.class(name) {
+            Ok(Some(_)) => true,
+            Ok(None) => {
+                // an extern may name the same class in its target and its types
+                let reported = self.errs.iter().any(|e| {
+                    matches!(e, ClassPathErr::UnknownClass(n, s) if n == name && *s == span)
+                });
+                if !reported {
+                    self.errs.push(ClassPathErr::UnknownClass(name.into(), span));
+                }
+                false
+            }
+            Err(e) => {
+                self.errs.push(e);
+                false
+            }
+        }
+    }
+
+    fn ty(&mut self, ty: &Type, span: Span) {
+        match ty {
+            Type::List(ty) => self.ty(ty, span),
+            Type::Map(key, value) => {
+                self.ty(key, span);
+                self.ty(value, span);
+            }
+            Type::Object(name) => {
+                self.class(name, span);
+            }
+            _ => {}
+        }
+    }
+
+    fn in_type(&mut self, ty: &MatchInType, span: Span) {
+        match ty {
+            MatchInType::List(ty) => self.in_type(ty, span),
+            MatchInType::Map(key, value) => {
+                self.in_type(key, span);
+                self.in_type(value, span);
+            }
+            MatchInType::Type(ty) => self.ty(ty, span),
+            MatchInType::Any | MatchInType::Generic(_) => {}
+        }
+    }
+
+    fn out_type(&mut self, ty: &MatchOutType, span: Span) {
+        match ty {
+            MatchOutType::List(ty) => self.out_type(ty, span),
+            MatchOutType::Map(key, value) => {
+                self.out_type(key, span);
+                self.out_type(value, span);
+            }
+            MatchOutType::Type(ty) => self.ty(ty, span),
+            MatchOutType::Generic(_) => {}
+        }
+    }
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>, ClassPathErr> {
+    fs::read_dir(dir)
+        .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect())
+        .map_err(|e| ClassPathErr::Io(dir.into(), e))
+}
+
+fn is_archive(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "jar" || e == "jmod" || e == "zip")
+}
+
+/// A file in a zip archive
+#[derive(Clone, Debug)]
+struct ArchiveEntry {
+    name: String,
+    /// 0 if it is stored, 8 if it is deflated
+    method: u16,
+    compressed_size: usize,
+    /// Of the local header, in the archive
+    offset: usize,
+}
+
+impl ArchiveEntry {
+    fn read(&self, archive: &[u8]) -> Result<Vec<u8>, String> {
+        let header = archive.get(self.offset..self.offset + 30).ok_or("truncated archive")?;
+        if u32_at(header, 0) != 0x0403_4b50 {
+            return Err(format!("bad local header of `{}`", self.name));
+        }
+        let start = self.offset
+            + 30
+            + u16_at(header, 26) as usize
+            + u16_at(header, 28) as usize;
+        let data = archive
+            .get(start..start + self.compressed_size)
+            .ok_or("truncated archive")?;
+        match self.method {
+            0 => Ok(data.to_vec()),
+            8 => miniz_oxide::inflate::decompress_to_vec(data)
+                .map_err(|e| format!("cannot inflate `{}`: {e:?}", self.name)),
+            method => Err(format!("unsupported compression method {method} of `{}`", self.name)),
+        }
+    }
+}
+
+/// Reads the central directory of a zip archive. Offsets are corrected for data before the
+/// archive, like the header of a jmod.
+fn zip_entries(archive: &[u8]) -> Result<Vec<ArchiveEntry>, String> {
+    const END_LEN: usize = 22;
+    // the end of central directory record is followed by a comment of at most 64 KiB
+    let end = (archive.len().saturating_sub(END_LEN + 0xffff)..=archive.len().saturating_sub(END_LEN))
+        .rev()
+        .find(|&i| u32_at(archive, i) == 0x0605_4b50)
+        .ok_or("not a zip archive")?;
+    let count = u16_at(archive, end + 10) as usize;
+    let dir_size = u32_at(archive, end + 12) as usize;
+    let dir_offset = u32_at(archive, end + 16) as usize;
+    let base = end.checked_sub(dir_size + dir_offset).ok_or("bad central directory")?;
+
+    let mut entries = Vec::with_capacity(count);
+    let mut pos = base + dir_offset;
+    for _ in 0..count {
+        let header = archive.get(pos..pos + 46).ok_or("truncated central directory")?;
+        if u32_at(header, 0) != 0x0201_4b50 {
+            return Err("bad central directory".into());
+        }
+        let name_len = u16_at(header, 28) as usize;
+        let name = archive.get(pos + 46..pos + 46 + name_len).ok_or("truncated central directory")?;
+        entries.push(ArchiveEntry {
+            name: String::from_utf8_lossy(name).into(),
+            method: u16_at(header, 10),
+            compressed_size: u32_at(header, 20) as usize,
+            offset: base + u32_at(header, 42) as usize,
+        });
+        pos += 46 + name_len + u16_at(header, 30) as usize + u16_at(header, 32) as usize;
+    }
+    Ok(entries)
+}
+
+/// Little endian, as in zip archives; 0 past the end
+fn u16_at(bytes: &[u8], i: usize) -> u16 {
+    bytes.get(i..i + 2).map_or(0, |b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(bytes: &[u8], i: usize) -> u32 {
+    bytes.get(i..i + 4).map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Reads a class file big endian, as the JVM writes it
+struct ClassReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl ClassReader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        let bytes = self.bytes.get(self.pos..self.pos + n).ok_or("truncated class file")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.take(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn skip_attributes(&mut self) -> Result<(), String> {
+        for _ in 0..self.u16()? {
+            self.u16()?;
+            let len = self.u32()? as usize;
+            self.take(len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the names of a class's superclass, interfaces and members
+fn parse_class(bytes: &[u8]) -> Result<ClassInfo, String> {
+    #[derive(Clone)]
+    enum Constant {
+        Utf8(String),
+        Class(u16),
+        Other,
+    }
+
+    let mut reader = ClassReader { bytes, pos: 0 };
+    if reader.u32()? != 0xcafe_babe {
+        return Err("not a class file".into());
+    }
+    reader.take(4)?;
+    let count = reader.u16()? as usize;
+    let mut pool = vec![Constant::Other; count];
+    let mut i = 1;
+    while i < count {
+        let tag = reader.take(1)?[0];
+        pool[i] = match tag {
+            1 => {
+                let len = reader.u16()? as usize;
+                // modified UTF-8 only differs from UTF-8 for characters that names don't use
+                Constant::Utf8(String::from_utf8_lossy(reader.take(len)?).into())
+            }
+            7 => Constant::Class(reader.u16()?),
+            8 | 16 | 19 | 20 => {
+                reader.take(2)?;
+                Constant::Other
+            }
+            15 => {
+                reader.take(3)?;
+                Constant::Other
+            }
+            3 | 4 | 9 | 10 | 11 | 12 | 17 | 18 => {
+                reader.take(4)?;
+                Constant::Other
+            }
+            5 | 6 => {
+                reader.take(8)?;
+                // longs and doubles take up two entries
+                i += 1;
+                Constant::Other
+            }
+            tag => return Err(format!("unknown constant pool tag {tag}")),
+        };
+        i += 1;
+    }
+    let utf8 = |index: u16| match pool.get(index as usize) {
+        Some(Constant::Utf8(s)) => Ok(s.clone()),
+        _ => Err(format!("constant {index} is not a string")),
+    };
+    let class = |index: u16| match pool.get(index as usize) {
+        Some(Constant::Class(name)) => utf8(*name),
+        _ => Err(format!("constant {index} is not a class")),
+    };
+
+    let mut info = ClassInfo::default();
+    // access flags and this class
+    reader.take(4)?;
+    let super_class = reader.u16()?;
+    if super_class != 0 {
+        info.super_class = Some(class(super_class)?);
+    }
+    for _ in 0..reader.u16()? {
+        info.interfaces.push(class(reader.u16()?)?);
+    }
+    for members in [&mut info.fields, &mut info.methods] {
+        for _ in 0..reader.u16()? {
+            reader.u16()?;
+            let name = utf8(reader.u16()?)?;
+            let descriptor = utf8(reader.u16()?)?;
+            members.insert((name, descriptor));
+            reader.skip_attributes()?;
+        }
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        classfile,
+        ir::{self, Instruction, Opcode, Operand},
+    };
+
+    /// A zip archive of `files`, deflated or stored, after `prefix`
+    fn zip(prefix: &[u8], files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut archive = prefix.to_vec();
+        let mut central = Vec::new();
+        for (name, content, deflate) in files {
+            let data = if *deflate {
+                miniz_oxide::deflate::compress_to_vec(content, 6)
+            } else {
+                content.to_vec()
+            };
+            let method: u16 = if *deflate { 8 } else { 0 };
+            // offsets are relative to the start of the zip, not of the prefix
+            let offset = (archive.len() - prefix.len()) as u32;
+            archive.extend(0x0403_4b50u32.to_le_bytes());
+            archive.extend([20, 0, 0, 0]);
+            archive.extend(method.to_le_bytes());
+            // time, date and crc aren't checked
+            archive.extend([0; 8]);
+            archive.extend((data.len() as u32).to_le_bytes());
+            archive.extend((content.len() as u32).to_le_bytes());
+            archive.extend((name.len() as u16).to_le_bytes());
+            // an extra field, which has to be skipped
+            archive.extend(3u16.to_le_bytes());
+            archive.extend(name.as_bytes());
+            archive.extend([0; 3]);
+            archive.extend(&data);
+
+            central.extend(0x0201_4b50u32.to_le_bytes());
+            central.extend([20, 0, 20, 0, 0, 0]);
+            central.extend(method.to_le_bytes());
+            central.extend([0; 8]);
+            central.extend((data.len() as u32).to_le_bytes());
+            central.extend((content.len() as u32).to_le_bytes());
+            central.extend((name.len() as u16).to_le_bytes());
+            // no extra field and comment, disk number, attributes
+            central.extend([0; 12]);
+            central.extend(offset.to_le_bytes());
+            central.extend(name.as_bytes());
+        }
+        let dir_offset = (archive.len() - prefix.len()) as u32;
+        let dir_size = central.len() as u32;
+        archive.extend(central);
+        archive.extend(0x0605_4b50u32.to_le_bytes());
+        archive.extend([0; 4]);
+        archive.extend((files.len() as u16).to_le_bytes());
+        archive.extend((files.len() as u16).to_le_bytes());
+        archive.extend(dir_size.to_le_bytes());
+        archive.extend(dir_offset.to_le_bytes());
+        // a comment, which the end record has to be searched before
+        archive.extend(4u16.to_le_bytes());
+        archive.extend(b"jack");
+        archive
+    }
+
+    #[test]
+    fn reads_stored_and_deflated_entries() {
+        let text = b"hello hello hello hello".as_slice();
+        let archive = zip(&[], &[("a.txt", text, false), ("dir/b.class", text, true)]);
+        let entries = zip_entries(&archive).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| (e.name.as_str(), e.method)).collect::<Vec<_>>(),
+            [("a.txt", 0), ("dir/b.class", 8)]
+        );
+        assert!(entries[1].compressed_size < text.len());
+        for entry in &entries {
+            assert_eq!(entry.read(&archive).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn reads_entries_after_a_prefix() {
+        // jmods start with a header of their own
+        let archive = zip(b"JM\x01\x00", &[("classes/A.class", b"A", true)]);
+        let entries = zip_entries(&archive).unwrap();
+        assert_eq!(entries[0].offset, 4);
+        assert_eq!(entries[0].read(&archive).unwrap(), b"A");
+    }
+
+    #[test]
+    fn rejects_broken_archives() {
+        assert!(zip_entries(b"not a zip at all, just some bytes").is_err());
+        let archive = zip(&[], &[("a", b"a", false)]);
+        let mut entry = zip_entries(&archive).unwrap().remove(0);
+        entry.offset += 1;
+        assert!(entry.read(&archive).is_err());
+        entry.offset -= 1;
+        entry.method = 12;
+        assert!(entry.read(&archive).is_err());
+    }
+
+    #[test]
+    fn parses_assembled_class() {
+        let fields = [ir::Field {
+            name: "n".into(),
+            descriptor: "J".into(),
+        }];
+        let mut method = ir::Method::new("get".into(), "()J".into());
+        method.max_stack = 2;
+        // a long takes up two constant pool entries, which the reader has to skip
+        method.code = vec![
+            Instruction::Op(Opcode::Ldc2W, vec![Operand::Long(42)]),
+            Instruction::op(Opcode::LReturn),
+        ];
+        let class = ir::Class {
+            source: "A.jack",
+            name: "A",
+            extends: "java/lang/Object",
+            fields: &fields,
+            methods: vec![&method],
+        };
+        let info = parse_class(&classfile::assemble(&class).unwrap()).unwrap();
+        assert_eq!(info.super_class.as_deref(), Some("java/lang/Object"));
+        assert!(info.interfaces.is_empty());
+        assert_eq!(info.fields, HashSet::from([("n".into(), "J".into())]));
+        assert_eq!(info.methods, HashSet::from([("get".into(), "()J".into())]));
+    }
+
+    #[test]
+    fn rejects_broken_classes() {
+        assert!(parse_class(b"not a class").is_err());
+        let class = ir::Class {
+            source: "A.jack",
+            name: "A",
+            extends: "java/lang/Object",
+            fields: &[],
+            methods: Vec::new(),
+        };
+        let bytes = classfile::assemble(&class).unwrap();
+        // cut off in the constant pool
+        assert!(parse_class(&bytes[..16]).is_err());
+    }
+}
@@ -4,9 +4,12 @@ use klex::Loc;
 use thiserror::Error;
 
 use crate::{
-    analyzer::{AnalyzerErr, Type, Value},
-    ast::{AstBase, AstItem, AstNode},
-    opcodes, *,
+    analyzer::{AnalyzerErr, LocalVar, Signature, StackElement, Type, Value},
+    ast::{AstBase, AstItem, AstNode, ExternKind, ExternType},
+    classfile::{self, AssemblerErr},
+    ir::{self, Field, Instruction, Label, Method, Opcode, Operand},
+    macr::SourceFile,
+    opcodes, peephole, *,
 };
 
 use self::ast::MatchInType;
@@ -19,15 +22,33 @@ pub struct ClassWriter {
     name: String,
     /// The superclass of this class
     extends: String,
-    /// Gets written first in the class assembly
-    header: String,
-    /// Gets written into the initializer, after calling the superclass constructor
-    init: String,
-    /// The contens of the main method
-    /// If this stays empty, no main method will be generated
-    main: String,
-    /// Gets written last in the class assembly
-    footer: String,
+    /// The fields of records
+    fields: Vec<Field>,
+    /// The initializer; the default constructor, unless this is the class of a record
+    constructor: Method,
+    /// The method that instructions are emitted into; the main method, unless a function is being
+    /// generated
+    main: Method,
+    /// The methods generated from functions, and `toString` in the classes of records
+    methods: Vec<Method>,
+    /// How many labels have been created, see [`ClassWriter::new_label`]
+    labels: usize,
+    /// The classes generated from records, which are written next to this one
+    records: Vec<ClassWriter>,
+    /// Where `continue` and `break` jump to in the loops around the code that is being generated,
+    /// innermost last
+    loops: Vec<LoopLabels>,
+    /// The row of the `#include` in the source file that brought in each file, by `file_index`,
+    /// see [`ClassWriter::line_directive`]
+    include_rows: Vec<Option<usize>>,
+}
+
+/// Where `continue` and `break` jump to in a loop
+struct LoopLabels {
+    continue_label: Label,
+    break_label: Label,
+    /// Whether a `continue` jumped to `continue_label`
+    continued: bool,
 }
 
 impl AstBase {
@@ -38,50 +59,67 @@ impl AstBase {
         max_stack_size: usize,
         max_vars_count: usize,
     ) -> Result<(), CodeGenErr> {
-        class
-            .push_main(opcodes::DIR_STACK_LIMIT)
-            .append_main(&max_stack_size.to_string())
-            .main_endl();
-        class
-            .push_main(opcodes::DIR_LOCALS_LIMIT)
-            .append_main(&max_vars_count.to_string())
-            .main_endl();
+        class.main.max_stack = max_stack_size;
+        class.main.max_locals = max_vars_count;
         let mut n_vars = 0;
         for node in &self.nodes {
             n_vars = n_vars.max(expect_var_info!(node).len());
             node.code_gen(class)?;
         }
+        class.op(Opcode::Return);
+        class.main.remove_dead_code();
         Ok(())
     }
 }
 
 impl AstNode {
     pub fn code_gen(&self, class: &mut ClassWriter) -> Result<(), CodeGenErr> {
-        //class.line_directive(self.loc);
+        if !self.reachable {
+            return Ok(());
+        }
         match &self.inner {
             AstItem::PushInt(i) => class.push_int(i),
+            AstItem::PushBool(b) => class.push_int(&(*b as i32)),
+            AstItem::PushLong(n) => class.push_long(*n),
+            AstItem::PushDouble(d) => class.push_double(*d),
             AstItem::PushString(s) => class.push_string(s),
             AstItem::List(_) => new_list!(self, class),
+            AstItem::Map(..) => new_map!(self, class),
             AstItem::ListLiteral(nodes) => {
-                let is_int_list = matches!(&expect_type_info!(self).last().unwrap().ty, Type::List(a) if **a == Type::Int);
+                let element_type = match &expect_type_info_after!(self).last().unwrap().ty {
+                    Type::List(a) => Some(*a.clone()),
+                    _ => None,
+                };
                 new_list!(self, class);
                 for node in nodes {
                     dup!(class);
                     node.code_gen(class)?;
-                    if is_int_list {
-                        int_to_integer!(self, class);
+                    match element_type {
+                        Some(Type::Int) => {
+                            int_to_integer!(self, class);
+                        }
+                        Some(Type::Bool) => {
+                            bool_to_boolean!(self, class);
+                        }
+                        Some(Type::Long) => {
+                            long_to_boxed!(self, class);
+                        }
+                        Some(Type::Double) => {
+                            double_to_boxed!(self, class);
+                        }
+                        _ => {}
                     }
                     //class.swap();
                     invoke!(
                         generic: self,
                         class,
-                        opcodes::INVOKE_VIRTUAL,
+                        Opcode::InvokeVirtual,
                         "java/util/ArrayList/add",
                         1,
                         opcodes::TYPE_BOOL
                     );
                     // drop the boolean returned by `add`
-                    class.append_main(opcodes::POP).main_endl();
+                    class.op(Opcode::Pop);
                 }
             }
             AstItem::If {
@@ -89,64 +127,79 @@ impl AstNode {
                 body,
                 else_body,
             } => {
-                class.append_main(&line_directive!(self)).main_endl();
+                class.line_directive(self.loc);
                 if let Some(head) = head {
                     head.code_gen(class)?;
                 }
-                let body_label = format!("If{}", class.main.len());
-                let else_body_label = format!("Else{}", class.main.len());
-                let end_if_label = format!("EndIf{}", class.main.len());
-                class
-                    .push_main(opcodes::IF_NE)
-                    .append_main(&body_label)
-                    .main_endl();
-                class
-                    .push_main(opcodes::GOTO)
-                    .append_main(&else_body_label)
-                    .main_endl();
-                class.push_main(&body_label).append_main(":").main_endl();
+                let condition = match head {
+                    Some(head) => constant_condition(expect_type_info_after!(head)),
+                    None => constant_condition(expect_type_info!(self)),
+                };
+                if let Some(condition) = condition {
+                    // only the live branch is emitted
+                    class.op(Opcode::Pop);
+                    if condition {
+                        body.code_gen(class)?;
+                    } else if let Some(else_body) = else_body {
+                        else_body.code_gen(class)?;
+                    }
+                    return Ok(());
+                }
+                let body_label = class.new_label("If");
+                let else_body_label = class.new_label("Else");
+                let end_if_label = class.new_label("EndIf");
+                class.jump(Opcode::IfNe, body_label);
+                class.jump(Opcode::Goto, else_body_label);
+                class.place_label(body_label);
+                class.stack_frame(expect_type_info!(body), expect_var_info!(body));
                 body.code_gen(class)?;
-                class
-                    .push_main(opcodes::GOTO)
-                    .append_main(&end_if_label)
-                    .main_endl();
-                class
-                    .push_main(&else_body_label)
-                    .append_main(":")
-                    .main_endl();
+                class.jump(Opcode::Goto, end_if_label);
+                class.place_label(else_body_label);
+                // both branches start out with the same stack
+                class.stack_frame(expect_type_info!(body), expect_var_info!(body));
                 if let Some(else_body) = else_body {
                     else_body.code_gen(class)?;
                 }
-                class.push_main(&end_if_label).append_main(":").main_endl();
+                class.place_label(end_if_label);
+                class.stack_frame(expect_type_info_after!(self), expect_var_info_after!(self));
             }
             AstItem::Switch {
                 ref arms,
                 ref default,
-            } => class.switch(arms, default)?,
+            } => {
+                class.switch(arms, default)?;
+                class.stack_frame(expect_type_info_after!(self), expect_var_info_after!(self));
+            }
             AstItem::While { head, body } => {
-                class.append_main(&line_directive!(self)).main_endl();
-                let head_label = format!("WhileHead{}", class.main.len());
-                let body_label = format!("While{}", class.main.len());
-                let end_label = format!("EndWhile{}", class.main.len());
-                class.push_main(&head_label).append_main(":").main_endl();
+                class.line_directive(self.loc);
+                let head_label = class.new_label("WhileHead");
+                let body_label = class.new_label("While");
+                let end_label = class.new_label("EndWhile");
+                class.place_label(head_label);
+                class.stack_frame(expect_type_info!(self), expect_var_info!(self));
                 if let Some(head) = head {
                     head.code_gen(class)?;
+                    if let Some(condition) = constant_condition(expect_type_info_after!(head)) {
+                        class.op(Opcode::Pop);
+                        if condition {
+                            // loops forever, only `break` and `exit` get out
+                            class.loop_body(body, head_label, end_label)?;
+                            class.jump(Opcode::Goto, head_label);
+                            // the code after the loop may be dead, but the verifier wants a frame
+                            class.place_label(end_label);
+                            class.stack_frame(expect_type_info_after!(self), expect_var_info_after!(self));
+                        }
+                        return Ok(());
+                    }
                 }
-                class
-                    .push_main(opcodes::IF_NE)
-                    .append_main(&body_label)
-                    .main_endl();
-                class
-                    .push_main(opcodes::GOTO)
-                    .append_main(&end_label)
-                    .main_endl();
-                class.push_main(&body_label).append_main(":").main_endl();
-                body.code_gen(class)?;
-                class
-                    .push_main(opcodes::GOTO)
-                    .append_main(&head_label)
-                    .main_endl();
-                class.push_main(&end_label).append_main(":").main_endl();
+                class.jump(Opcode::IfNe, body_label);
+                class.jump(Opcode::Goto, end_label);
+                class.place_label(body_label);
+                class.stack_frame(expect_type_info!(body), expect_var_info!(body));
+                class.loop_body(body, head_label, end_label)?;
+                class.jump(Opcode::Goto, head_label);
+                class.place_label(end_label);
+                class.stack_frame(expect_type_info_after!(self), expect_var_info_after!(self));
             }
             AstItem::For {
                 init,
@@ -154,32 +207,96 @@ impl AstNode {
                 modifier,
                 body,
             } => {
-                class.append_main(&line_directive!(self)).main_endl();
-                let end_label = format!("ForEnd{}", class.main.len());
-                let body_label = format!("ForBody{}", class.main.len());
-                let condition_label = format!("ForCond{}", class.main.len());
+                class.line_directive(self.loc);
+                let end_label = class.new_label("ForEnd");
+                let body_label = class.new_label("ForBody");
+                let condition_label = class.new_label("ForCond");
+                let modifier_label = class.new_label("ForNext");
                 init.code_gen(class)?;
-                class
-                    .push_main(&condition_label)
-                    .append_main(":")
-                    .main_endl();
+                class.place_label(condition_label);
+                class.stack_frame(expect_type_info!(condition), expect_var_info!(condition));
                 condition.code_gen(class)?;
-                class
-                    .push_main(opcodes::IF_NE)
-                    .append_main(&body_label)
-                    .main_endl();
-                class
-                    .push_main(opcodes::GOTO)
-                    .append_main(&end_label)
-                    .main_endl();
-                class.push_main(&body_label).append_main(":").main_endl();
-                body.code_gen(class)?;
+                class.jump(Opcode::IfNe, body_label);
+                class.jump(Opcode::Goto, end_label);
+                class.place_label(body_label);
+                class.stack_frame(expect_type_info!(body), expect_var_info!(body));
+                if class.loop_body(body, modifier_label, end_label)? {
+                    class.place_label(modifier_label);
+                    class.stack_frame(expect_type_info!(modifier), expect_var_info!(modifier));
+                }
                 modifier.code_gen(class)?;
-                class
-                    .push_main(opcodes::GOTO)
-                    .append_main(&condition_label)
-                    .main_endl();
-                class.push_main(&end_label).append_main(":").main_endl();
+                class.jump(Opcode::Goto, condition_label);
+                class.place_label(end_label);
+                class.stack_frame(expect_type_info_after!(self), expect_var_info_after!(self));
+            }
+            AstItem::Try { body, catches, spill } => {
+                class.line_directive(self.loc);
+                let spill = spill.as_ref().ok_or(CodeGenErr::NotAnalyzedErr(self.loc))?;
+                let stack = expect_type_info!(self);
+                let start_label = class.new_label("Try");
+                let end_label = class.new_label("EndTry");
+                let after_label = class.new_label("AfterTry");
+                // the JVM clears the stack before it runs a handler, so the body and every handler
+                // load it back from local variables
+                for (elem, index) in stack.iter().zip(spill).rev() {
+                    class.op_with(elem.ty.store_opcode(), Operand::Local(*index));
+                }
+                class.place_label(start_label);
+                let body_start = class.main.code.len();
+                class.load_spilled(stack, spill);
+                body.code_gen(class)?;
+                // an empty range is a `ClassFormatError`, and nothing in it could throw anyway
+                let catches = if class.main.code[body_start..].iter().any(Instruction::is_code) {
+                    catches.as_slice()
+                } else {
+                    &[]
+                };
+                class.place_label(end_label);
+                class.jump(Opcode::Goto, after_label);
+                for (exception, catch_body) in catches {
+                    let handler_label = class.new_label("Catch");
+                    class.emit(Instruction::Catch {
+                        class: exception.clone(),
+                        from: start_label,
+                        to: end_label,
+                        handler: handler_label,
+                    });
+                    class.place_label(handler_label);
+                    let thrown = StackElement {
+                        ty: Type::Object(exception.clone()),
+                        value: None,
+                    };
+                    class.stack_frame(&[thrown], expect_var_info!(catch_body));
+                    class.op(Opcode::Pop);
+                    class.load_spilled(stack, spill);
+                    catch_body.code_gen(class)?;
+                    class.jump(Opcode::Goto, after_label);
+                }
+                class.place_label(after_label);
+                class.stack_frame(expect_type_info_after!(self), expect_var_info_after!(self));
+            }
+            AstItem::Throw => {
+                class.line_directive(self.loc);
+                class.op_with(Opcode::New, Operand::Symbol(opcodes::CLASS_RUNTIME_EXCEPTION.into()));
+                class.dupx1();
+                class.swap();
+                class.invoke(
+                    Opcode::InvokeSpecial,
+                    format!("{}/<init>({})V", opcodes::CLASS_RUNTIME_EXCEPTION, opcodes::TYPE_STRING),
+                );
+                class.op(Opcode::AThrow);
+            }
+            AstItem::Break | AstItem::Continue => {
+                class.line_directive(self.loc);
+                let labels = class.loops.last_mut().ok_or(CodeGenErr::NotAnalyzedErr(self.loc))?;
+                let target = match self.inner {
+                    AstItem::Break => labels.break_label,
+                    _ => {
+                        labels.continued = true;
+                        labels.continue_label
+                    }
+                };
+                class.jump(Opcode::Goto, target);
             }
             AstItem::Block(children) => {
                 for c in children {
@@ -190,35 +307,174 @@ impl AstNode {
                 if let Some(init) = initializer {
                     init.code_gen(class)?;
                 }
-                class.append_main(&line_directive!(self)).main_endl();
-                let vars = expect_var_info!(self);
-                class
-                    .push_main(match vars.get(name).unwrap().elem.ty {
-                        Type::Int => opcodes::I_STORE,
-                        Type::String | Type::List(_) | Type::Object(_) => opcodes::A_STORE,
-                    })
-                    .append_main(&vars.get(name).unwrap().index.to_string())
-                    .main_endl();
+                class.line_directive(self.loc);
+                let var = &expect_var_info_after!(self)[name];
+                class.op_with(var.elem.ty.store_opcode(), Operand::Local(var.index));
             }
             AstItem::Load(name) => {
-                class.append_main(&line_directive!(self)).main_endl();
-                let vars = expect_var_info!(self);
-                class
-                    .push_main(match vars.get(name).unwrap().elem.ty {
-                        Type::Int => opcodes::I_LOAD,
-                        Type::String | Type::List(_) | Type::Object(_) => opcodes::A_LOAD,
-                    })
-                    .append_main(&vars.get(name).unwrap().index.to_string())
-                    .main_endl();
+                class.line_directive(self.loc);
+                let var = &expect_var_info!(self)[name];
+                class.op_with(var.elem.ty.load_opcode(), Operand::Local(var.index));
             }
             AstItem::Jasmin { body, .. } => class.jasmin(body),
             AstItem::TypeSwitch { arms, chosen_index } => class.type_switch(arms, chosen_index.as_ref())?,
             AstItem::CmpErr(_) => unreachable!(),
+            AstItem::Function {
+                name,
+                signature,
+                body,
+                limits,
+            } => {
+                let (max_stack_size, max_vars_count) =
+                    limits.ok_or(CodeGenErr::NotAnalyzedErr(self.loc))?;
+                class.function(name, signature, body, max_stack_size, max_vars_count + 1)?;
+            }
+            AstItem::Call { name, signature } => {
+                class.line_directive(self.loc);
+                let signature = signature
+                    .as_ref()
+                    .ok_or(CodeGenErr::NotAnalyzedErr(self.loc))?;
+                class.call(name, signature);
+            }
+            AstItem::Record { name, fields } => class.record(name, fields),
+            AstItem::New { record, fields } => {
+                class.line_directive(self.loc);
+                let fields = fields.as_ref().ok_or(CodeGenErr::NotAnalyzedErr(self.loc))?;
+                class.new_record(record, fields);
+            }
+            AstItem::GetField { name, resolved } => {
+                class.line_directive(self.loc);
+                let (record, ty) = resolved.as_ref().ok_or(CodeGenErr::NotAnalyzedErr(self.loc))?;
+                class.field(Opcode::GetField, record, name, ty);
+            }
+            AstItem::SetField { initializer, name, resolved } => {
+                if let Some(init) = initializer {
+                    init.code_gen(class)?;
+                }
+                class.line_directive(self.loc);
+                let (record, ty) = resolved.as_ref().ok_or(CodeGenErr::NotAnalyzedErr(self.loc))?;
+                class.field(Opcode::PutField, record, name, ty);
+            }
+            AstItem::Extern { name, kind, target, input, output } => {
+                class.extern_method(name, *kind, target, input, output);
+            }
         }
         Ok(())
     }
 }
 
+/// The class that boxes a primitive type, and the method that unboxes it
+fn boxed_class(ty: &Type) -> Option<(&'static str, &'static str)> {
+    match ty {
+        Type::Int => Some((opcodes::CLASS_INTEGER, "intValue")),
+        Type::Bool => Some((opcodes::CLASS_BOOLEAN, "booleanValue")),
+        Type::Long => Some((opcodes::CLASS_BOXED_LONG, "longValue")),
+        Type::Double => Some((opcodes::CLASS_BOXED_DOUBLE, "doubleValue")),
+        _ => None,
+    }
+}
+
+/// The fields go in, the record comes out
+fn record_signature(name: &str, fields: &[(String, Type)]) -> Signature {
+    Signature {
+        input: fields.iter().map(|(_, ty)| ty.clone()).collect(),
+        output: vec![Type::Record(name.into())],
+    }
+}
+
+/// A constructor that calls the superclass constructor and stores its arguments into `fields`
+fn constructor(class: &str, extends: &str, fields: &[(String, Type)]) -> Method {
+    let descriptor = format!(
+        "({}){}",
+        fields.iter().map(|(_, ty)| ty.to_opcode()).collect::<String>(),
+        opcodes::TYPE_VOID
+    );
+    let mut method = Method::instance("<init>".into(), descriptor);
+    let this = || Instruction::Op(Opcode::ALoad, vec![Operand::Local(0)]);
+    method.code.push(this());
+    method.code.push(Instruction::Op(
+        Opcode::InvokeSpecial,
+        vec![Operand::Symbol(format!("{extends}/<init>(){}", opcodes::TYPE_VOID))],
+    ));
+    // the arguments follow `this`
+    let mut index = 1;
+    for (field, ty) in fields {
+        method.code.push(this());
+        method.code.push(Instruction::Op(ty.load_opcode(), vec![Operand::Local(index)]));
+        method.code.push(Instruction::Op(
+            Opcode::PutField,
+            vec![Operand::Symbol(format!("{class}/{field}")), Operand::Symbol(ty.to_opcode())],
+        ));
+        index += ty.slots();
+    }
+    method.code.push(Instruction::op(Opcode::Return));
+    method.max_stack = 1 + fields.iter().map(|(_, ty)| ty.slots()).max().unwrap_or(0);
+    method.max_locals = index;
+    method
+}
+
+/// A `toString` method that prints a record like Java prints its records: `Point[x=1, y=2]`
+fn record_to_string(name: &str, fields: &[(String, Type)]) -> Method {
+    let builder = format!("L{};", opcodes::CLASS_STRING_BUILDER);
+    let append = |argument: &str| {
+        Instruction::Op(
+            Opcode::InvokeVirtual,
+            vec![Operand::Symbol(format!("{}/append({argument}){builder}", opcodes::CLASS_STRING_BUILDER))],
+        )
+    };
+    let ldc = |s: String| Instruction::Op(Opcode::Ldc, vec![Operand::String(s)]);
+    let mut method = Method::instance("toString".into(), format!("(){}", opcodes::TYPE_STRING));
+    method.code.push(Instruction::Op(
+        Opcode::New,
+        vec![Operand::Symbol(opcodes::CLASS_STRING_BUILDER.into())],
+    ));
+    method.code.push(Instruction::op(Opcode::Dup));
+    method.code.push(ldc(format!("{name}[")));
+    method.code.push(Instruction::Op(
+        Opcode::InvokeSpecial,
+        vec![Operand::Symbol(format!(
+            "{}/<init>({}){}",
+            opcodes::CLASS_STRING_BUILDER,
+            opcodes::TYPE_STRING,
+            opcodes::TYPE_VOID
+        ))],
+    ));
+    for (i, (field, ty)) in fields.iter().enumerate() {
+        let separator = if i == 0 { "" } else { ", " };
+        let appended = match ty {
+            Type::Int | Type::Bool | Type::Long | Type::Double | Type::String => ty.to_opcode(),
+            _ => opcodes::TYPE_OBJECT.into(),
+        };
+        method.code.push(ldc(format!("{separator}{field}=")));
+        method.code.push(append(opcodes::TYPE_STRING));
+        method.code.push(Instruction::Op(Opcode::ALoad, vec![Operand::Local(0)]));
+        method.code.push(Instruction::Op(
+            Opcode::GetField,
+            vec![Operand::Symbol(format!("{name}/{field}")), Operand::Symbol(ty.to_opcode())],
+        ));
+        method.code.push(append(&appended));
+    }
+    method.code.push(ldc("]".into()));
+    method.code.push(append(opcodes::TYPE_STRING));
+    method.code.push(Instruction::Op(
+        Opcode::InvokeVirtual,
+        vec![Operand::Symbol(format!("{}/toString(){}", opcodes::CLASS_STRING_BUILDER, opcodes::TYPE_STRING))],
+    ));
+    method.code.push(Instruction::op(Opcode::AReturn));
+    // the builder and a long or double field
+    method.max_stack = 3;
+    method.max_locals = 1;
+    method
+}
+
+/// The value of the condition on top of `stack`, if the analyzer knows it
+fn constant_condition(stack: &[StackElement]) -> Option<bool> {
+    match stack.last()?.value {
+        Some(Value::Bool(b)) => Some(b),
+        _ => None,
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum CodeGenErr {
     #[error("not implemented: {0}")]
@@ -232,106 +488,217 @@ pub enum CodeGenErr {
 impl ClassWriter {
     pub fn new(source: String, name: String, extends: String) -> Self {
         Self {
+            constructor: constructor(&name, &extends, &[]),
             source,
             name,
             extends,
-            header: String::new(),
-            init: String::new(),
-            main: String::new(),
-            footer: String::new(),
+            fields: Vec::new(),
+            main: Method::new("main".into(), "([Ljava/lang/String;)V".into()),
+            methods: Vec::new(),
+            labels: 0,
+            records: Vec::new(),
+            loops: Vec::new(),
+            include_rows: Vec::new(),
         }
     }
 
-    pub fn write(&self) -> String {
-        format!(
-            class_template!(),
-            source = self.source,
-            name = self.name,
-            extends = self.extends,
-            header = self.header,
-            init = self.init,
-            main = self.main,
-            footer = self.footer
-        )
+    /// Lets line numbers of code from `#include`d files point to their `#include`
+    pub fn set_source_files(&mut self, files: &[SourceFile]) {
+        self.include_rows = files.iter().map(|file| file.include_row).collect();
     }
 
-    pub fn push_stmt(&mut self, statement: &[&str]) {
-        for (i, part) in statement.iter().enumerate() {
-            self.main.push_str(part);
-            self.main
-                .push(if i == statement.len() - 1 { '\n' } else { ' ' });
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The classes generated from records
+    pub fn records(&self) -> &[ClassWriter] {
+        &self.records
+    }
+
+    /// The class with all of its methods
+    pub fn class(&self) -> ir::Class<'_> {
+        ir::Class {
+            source: &self.source,
+            name: &self.name,
+            extends: &self.extends,
+            fields: &self.fields,
+            methods: [&self.constructor, &self.main].into_iter().chain(&self.methods).collect(),
         }
     }
 
-    pub fn push_main(&mut self, s: &str) -> &mut Self {
-        self.main.push_str(s);
-        self.main.push_str(" ");
-        self
+    /// The class as Jasmin
+    pub fn write(&self) -> String {
+        self.class().to_string()
+    }
+
+    /// Runs the [peephole optimizations](peephole) over the code of all methods
+    pub fn peephole(&mut self) {
+        peephole::optimize(&mut self.constructor);
+        peephole::optimize(&mut self.main);
+        self.methods.iter_mut().for_each(peephole::optimize);
+        self.records.iter_mut().for_each(ClassWriter::peephole);
+    }
+
+    /// Assembles the class into the bytes of a `.class` file, without Jasmin
+    pub fn assemble(&self) -> Result<Vec<u8>, AssemblerErr> {
+        classfile::assemble(&self.class())
+    }
+
+    pub fn emit(&mut self, instruction: Instruction) {
+        self.main.code.push(instruction);
+    }
+
+    /// Emits an instruction without operands
+    pub fn op(&mut self, opcode: Opcode) {
+        self.emit(Instruction::op(opcode));
+    }
+
+    pub fn op_with(&mut self, opcode: Opcode, operand: Operand) {
+        self.emit(Instruction::Op(opcode, vec![operand]));
+    }
+
+    /// `method` is written like in Jasmin: `class/name(arguments)return`
+    pub fn invoke(&mut self, opcode: Opcode, method: String) {
+        self.op_with(opcode, Operand::Symbol(method));
+    }
+
+    pub fn jump(&mut self, opcode: Opcode, label: Label) {
+        self.op_with(opcode, Operand::Label(label));
     }
 
-    /// Doesn't add a space after
-    pub fn append_main(&mut self, s: &str) -> &mut Self {
-        self.main.push_str(s);
-        self
+    /// A label that is unique within the class; `name` only makes the Jasmin more readable
+    pub fn new_label(&mut self, name: &'static str) -> Label {
+        self.labels += 1;
+        Label {
+            id: self.labels,
+            name,
+        }
     }
 
-    pub fn main_endl(&mut self) {
-        self.main.push('\n');
+    /// Makes `label` point to the next instruction
+    pub fn place_label(&mut self, label: Label) {
+        self.emit(Instruction::Label(label));
     }
 
+    /// `.line` can only refer to the class' own source file, which is always the first file read
+    /// (`file_index` 0), so code from included files gets the line of the `#include` that brought
+    /// it in
     pub fn line_directive(&mut self, loc: Loc) {
-        self.push_stmt(&[opcodes::DIR_LINE, &loc.row.to_string()])
+        let row = match self.include_rows.get(loc.file_index) {
+            Some(Some(include_row)) => *include_row,
+            _ if loc.file_index == 0 => loc.row,
+            _ => return,
+        };
+        self.emit(Instruction::Line(row));
+    }
+
+    /// Declares the stack map frame of the next instruction, which has to be a branch target or
+    /// follow an unconditional jump. Local variables are listed by their index; a double stands
+    /// for both of its slots.
+    pub fn stack_frame(&mut self, stack: &[StackElement], vars: &HashMap<String, LocalVar>) {
+        let by_index = vars
+            .values()
+            .map(|var| (var.index, &var.elem.ty))
+            .collect::<HashMap<_, _>>();
+        let n_slots = vars.values().map(|v| v.index + v.elem.ty.slots()).max().unwrap_or(0);
+        let mut locals = Vec::new();
+        let mut index = 0;
+        while index < n_slots {
+            match by_index.get(&index) {
+                Some(ty) => {
+                    locals.push(ty.verification_type());
+                    index += ty.slots();
+                }
+                None => {
+                    locals.push(opcodes::VERIFICATION_TOP.into());
+                    index += 1;
+                }
+            }
+        }
+        self.emit(Instruction::Frame {
+            locals,
+            stack: stack.iter().map(|e| e.ty.verification_type()).collect(),
+        });
+    }
+
+    /// Generates the body of a loop, in which `continue` jumps to `continue_label` and `break` to
+    /// `break_label`. Returns whether there was a `continue`.
+    pub fn loop_body(&mut self, body: &AstNode, continue_label: Label, break_label: Label) -> Result<bool, CodeGenErr> {
+        self.loops.push(LoopLabels {
+            continue_label,
+            break_label,
+            continued: false,
+        });
+        let result = body.code_gen(self);
+        let labels = self.loops.pop().unwrap();
+        result.map(|()| labels.continued)
+    }
+
+    /// Pushes the stack that `try` kept in the local variables `spill` back onto the stack
+    pub fn load_spilled(&mut self, stack: &[StackElement], spill: &[usize]) {
+        for (elem, index) in stack.iter().zip(spill) {
+            self.op_with(elem.ty.load_opcode(), Operand::Local(*index));
+        }
     }
 
     pub fn push_int(&mut self, n: &i32) {
-        if (-1..=5).contains(n) {
-            self.push_stmt(&[match n {
-                -1 => opcodes::ICONST_M1,
-                0 => opcodes::ICONST_0,
-                1 => opcodes::ICONST_1,
-                2 => opcodes::ICONST_2,
-                3 => opcodes::ICONST_3,
-                4 => opcodes::ICONST_4,
-                5 => opcodes::ICONST_5,
-                _ => unreachable!(),
-            }]);
+        match n {
+            -1 => self.op(Opcode::IConstM1),
+            0 => self.op(Opcode::IConst0),
+            1 => self.op(Opcode::IConst1),
+            2 => self.op(Opcode::IConst2),
+            3 => self.op(Opcode::IConst3),
+            4 => self.op(Opcode::IConst4),
+            5 => self.op(Opcode::IConst5),
+            -128..=127 => self.op_with(Opcode::BiPush, Operand::Int(*n)),
+            -32768..=32767 => self.op_with(Opcode::SiPush, Operand::Int(*n)),
+            _ => self.op_with(Opcode::Ldc, Operand::Int(*n)),
+        }
+    }
+
+    pub fn push_long(&mut self, n: i64) {
+        match n {
+            0 => self.op(Opcode::LConst0),
+            1 => self.op(Opcode::LConst1),
+            _ => self.op_with(Opcode::Ldc2W, Operand::Long(n)),
+        }
+    }
+
+    pub fn push_double(&mut self, d: f64) {
+        // `-0.0 == 0.0`, but only the positive zero has a constant
+        if d.to_bits() == 0 {
+            self.op(Opcode::DConst0);
+        } else if d == 1.0 {
+            self.op(Opcode::DConst1);
         } else {
-            self.push_stmt(&[
-                match n {
-                    -128..=127 => opcodes::BIPUSH,
-                    -32768..=32767 => opcodes::SIPUSH,
-                    _ => opcodes::LDC,
-                },
-                &n.to_string(),
-            ]);
+            self.op_with(Opcode::Ldc2W, Operand::Double(d));
         }
     }
 
     pub fn push_string(&mut self, s: &str) {
-        self.push_stmt(&[opcodes::LDC, &format!("{:?}", s)]);
+        self.op_with(Opcode::Ldc, Operand::String(s.into()));
     }
 
     pub fn dup(&mut self) {
-        self.append_main(opcodes::DUP).main_endl();
+        self.op(Opcode::Dup);
     }
 
     pub fn dupx1(&mut self) {
-        self.append_main(opcodes::DUPX1).main_endl();
+        self.op(Opcode::DupX1);
     }
 
     pub fn swap(&mut self) {
-        self.append_main(opcodes::SWAP).main_endl();
+        self.op(Opcode::Swap);
     }
 
     pub fn new_list(&mut self, node: &AstNode) {
-        self.push_main(opcodes::NEW)
-            .append_main(opcodes::CLASS_ARRAY_LIST)
-            .main_endl();
+        self.op_with(Opcode::New, Operand::Symbol(opcodes::CLASS_ARRAY_LIST.into()));
         self.dup();
         invoke!(
             node,
             self,
-            opcodes::INVOKE_SPECIAL,
+            Opcode::InvokeSpecial,
             "java/util/ArrayList/<init>",
             0,
             opcodes::TYPE_VOID
@@ -340,55 +707,72 @@ impl ClassWriter {
 
     pub fn set(&mut self, node: &AstNode) {
         let e = node.stack.as_ref().unwrap().last().unwrap();
-        if e.ty == Type::Int {
-            int_to_integer!(node, self);
+        match e.ty {
+            Type::Int => {
+                int_to_integer!(node, self);
+            }
+            Type::Bool => {
+                bool_to_boolean!(node, self);
+            }
+            Type::Long => {
+                long_to_boxed!(node, self);
+            }
+            Type::Double => {
+                double_to_boxed!(node, self);
+            }
+            _ => {}
         }
         invoke!(
             node,
             self,
-            opcodes::INVOKE_VIRTUAL,
+            Opcode::InvokeVirtual,
             "java/util/ArrayList/set",
             types: [opcodes::TYPE_INT, opcodes::TYPE_OBJECT],
             opcodes::TYPE_OBJECT
         );
         // pop off the redundant bool
-        self.append_main(opcodes::POP).main_endl();
+        self.op(Opcode::Pop);
     }
 
     pub fn to_char_list(&mut self, node: &AstNode) {
-        self.push_main(opcodes::NEW)
-            .append_main(opcodes::CLASS_ARRAY_LIST)
-            .main_endl();
+        self.op_with(Opcode::New, Operand::Symbol(opcodes::CLASS_ARRAY_LIST.into()));
         self.dupx1();
         self.swap();
         invoke!(
             node,
             self,
-            opcodes::INVOKE_VIRTUAL,
+            Opcode::InvokeVirtual,
             "java/lang/String/codePoints",
             0,
             "Ljava/util/stream/IntStream;"
         );
-        self.append_main(
-            "invokeinterface java/util/stream/IntStream/boxed()Ljava/util/stream/Stream; 1",
-        )
-        .main_endl();
+        self.emit(Instruction::Op(
+            Opcode::InvokeInterface,
+            vec![
+                Operand::Symbol("java/util/stream/IntStream/boxed()Ljava/util/stream/Stream;".into()),
+                Operand::Int(1),
+            ],
+        ));
         invoke!(
             node,
             self,
-            opcodes::INVOKE_STATIC,
+            Opcode::InvokeStatic,
             "java/util/stream/Collectors/toList",
             0,
             "Ljava/util/stream/Collector;"
         );
-        self.append_main("invokeinterface java/util/stream/Stream/collect(Ljava/util/stream/Collector;)Ljava/lang/Object; 2").main_endl();
-        self.push_main(opcodes::CHECK_CAST)
-            .append_main("java/util/Collection")
-            .main_endl();
+        self.emit(Instruction::Op(
+            Opcode::InvokeInterface,
+            vec![
+                Operand::Symbol("java/util/stream/Stream/collect(Ljava/util/stream/Collector;)Ljava/lang/Object;".into()),
+                Operand::Int(2),
+            ],
+        ));
+        self.op_with(Opcode::CheckCast, Operand::Symbol("java/util/Collection".into()));
         invoke!(
             node,
             self,
-            opcodes::INVOKE_SPECIAL,
+            Opcode::InvokeSpecial,
             "java/util/ArrayList/<init>",
             types: ["Ljava/util/Collection;"],
             opcodes::TYPE_VOID
@@ -396,31 +780,186 @@ impl ClassWriter {
     }
 
     pub fn switch(&mut self, arms: &[(i32, AstNode)], default: &AstNode) -> Result<(), CodeGenErr> {
-        self.push_stmt(&[opcodes::LOOKUP_SWITCH]);
-        let label = format!("Switch{}", self.main.len());
         let labels = arms
             .iter()
-            .map(|(n, _)| (*n, format!("{label}{n}")))
-            .collect::<HashMap<i32, String>>();
-        let default_label = &format!("{label}default");
-        let end_label = &format!("End{label}");
-        for (n, _) in arms.iter() {
-            self.push_stmt(&[&n.to_string(), ":", &labels[n]]);
-        }
-        self.push_stmt(&[opcodes::DEFAULT, ":", default_label]);
-        for (n, body) in arms {
-            self.push_stmt(&[&labels[n], ":"]);
+            .map(|(n, _)| (*n, self.new_label("Switch")))
+            .collect::<Vec<_>>();
+        let default_label = self.new_label("SwitchDefault");
+        let end_label = self.new_label("EndSwitch");
+        self.emit(Instruction::LookupSwitch {
+            arms: labels.clone(),
+            default: default_label,
+        });
+        for ((_, body), (_, label)) in arms.iter().zip(labels) {
+            self.place_label(label);
+            self.stack_frame(expect_type_info!(body), expect_var_info!(body));
             body.code_gen(self)?;
-            self.push_stmt(&[opcodes::GOTO, end_label]);
+            self.jump(Opcode::Goto, end_label);
         }
-        self.push_stmt(&[default_label, ":"]);
+        self.place_label(default_label);
+        self.stack_frame(expect_type_info!(default), expect_var_info!(default));
         default.code_gen(self)?;
-        self.push_stmt(&[end_label, ":"]);
+        self.place_label(end_label);
+        Ok(())
+    }
+
+    /// Generates `body` as a static method of the class
+    pub fn function(
+        &mut self,
+        name: &str,
+        signature: &Signature,
+        body: &AstNode,
+        max_stack_size: usize,
+        max_vars_count: usize,
+    ) -> Result<(), CodeGenErr> {
+        // reuse the emitting helpers by generating into a fresh `main` and swapping it back after
+        let mut method = Method::new(name.into(), signature.descriptor());
+        method.max_stack = max_stack_size;
+        method.max_locals = max_vars_count;
+        let main = std::mem::replace(&mut self.main, method);
+        // the JVM passes the arguments as local variables, but the body expects them on the stack
+        for (index, ty) in signature.arguments() {
+            self.op_with(ty.load_opcode(), Operand::Local(index));
+        }
+        let result = body.code_gen(self);
+        self.op(signature.return_opcode());
+        let mut method = std::mem::replace(&mut self.main, main);
+        result?;
+        method.remove_dead_code();
+        self.methods.push(method);
         Ok(())
     }
 
-    pub fn jasmin(&mut self, code: &String) {
-        self.push_main(code).main_endl();
+    pub fn call(&mut self, name: &str, signature: &Signature) {
+        let method = format!("{}/{name}{}", self.name, signature.descriptor());
+        self.invoke(Opcode::InvokeStatic, method);
+    }
+
+    /// Generates the class of a record: a public field per record field, a constructor that takes
+    /// all of them, the static `of` method that constructing a record calls, and `toString`
+    pub fn record(&mut self, name: &str, fields: &[(String, Type)]) {
+        let mut class = ClassWriter::new(self.source.clone(), name.into(), opcodes::CLASS_OBJECT.into());
+        let signature = record_signature(name, fields);
+        class.fields = fields
+            .iter()
+            .map(|(field, ty)| Field {
+                name: field.clone(),
+                descriptor: ty.to_opcode(),
+            })
+            .collect();
+        class.constructor = constructor(name, opcodes::CLASS_OBJECT, fields);
+        let slots = fields.iter().map(|(_, ty)| ty.slots()).sum::<usize>();
+
+        // `new` would have to go below the fields on the stack, so a static method takes them
+        class.main = Method::new("of".into(), signature.descriptor());
+        class.main.max_stack = slots + 2;
+        class.main.max_locals = slots;
+        class.op_with(Opcode::New, Operand::Symbol(name.into()));
+        class.dup();
+        for (index, ty) in signature.arguments() {
+            class.op_with(ty.load_opcode(), Operand::Local(index));
+        }
+        let constructor = format!("{name}/<init>{}", class.constructor.descriptor);
+        class.invoke(Opcode::InvokeSpecial, constructor);
+        class.op(Opcode::AReturn);
+
+        class.methods.push(record_to_string(name, fields));
+        self.records.push(class);
+    }
+
+    pub fn new_record(&mut self, name: &str, fields: &[(String, Type)]) {
+        let descriptor = record_signature(name, fields).descriptor();
+        self.invoke(Opcode::InvokeStatic, format!("{name}/of{descriptor}"));
+    }
+
+    /// `getfield` or `putfield`
+    pub fn field(&mut self, opcode: Opcode, record: &str, name: &str, ty: &Type) {
+        self.emit(Instruction::Op(
+            opcode,
+            vec![
+                Operand::Symbol(format!("{record}/{name}")),
+                Operand::Symbol(ty.to_opcode()),
+            ],
+        ));
+    }
+
+    /// Generates a static method that calls the Java method of an extern with the arguments it is
+    /// passed, boxing and casting the `object[T]`s
+    pub fn extern_method(
+        &mut self,
+        name: &str,
+        kind: ExternKind,
+        target: &str,
+        input: &[ExternType],
+        output: &[ExternType],
+    ) {
+        let signature = Signature::of_extern(input, output);
+        let mut method = Method::new(name.into(), signature.descriptor());
+        method.max_locals = signature.input.iter().map(Type::slots).sum();
+        let main = std::mem::replace(&mut self.main, method);
+
+        // the object under construction and its copy stay below the arguments
+        let mut slots = if kind == ExternKind::Constructor { 2 } else { 0 };
+        let mut max_slots = slots;
+        if kind == ExternKind::Constructor {
+            self.op_with(Opcode::New, Operand::Symbol(target.into()));
+            self.dup();
+        }
+        for ((index, ty), param) in signature.arguments().zip(input) {
+            self.op_with(ty.load_opcode(), Operand::Local(index));
+            slots += ty.slots();
+            max_slots = max_slots.max(slots);
+            if param.erased {
+                self.box_value(ty);
+                slots = slots - ty.slots() + 1;
+            }
+        }
+        let opcode = match kind {
+            ExternKind::Static => Opcode::InvokeStatic,
+            ExternKind::Virtual => Opcode::InvokeVirtual,
+            ExternKind::Constructor => Opcode::InvokeSpecial,
+        };
+        self.invoke(opcode, kind.java_method(target, input, output));
+        if let Some(result) = output.first() {
+            if result.erased {
+                self.unbox_value(&result.ty);
+            }
+            max_slots = max_slots.max(result.ty.slots());
+        }
+        self.op(signature.return_opcode());
+
+        let mut method = std::mem::replace(&mut self.main, main);
+        method.max_stack = max_slots;
+        self.methods.push(method);
+    }
+
+    /// Boxes a primitive where Java expects an `Object`; other values are objects already
+    pub fn box_value(&mut self, ty: &Type) {
+        if let Some((class, _)) = boxed_class(ty) {
+            let primitive = ty.to_opcode();
+            self.invoke(Opcode::InvokeStatic, format!("{class}/valueOf({primitive})L{class};"));
+        }
+    }
+
+    /// Casts an `Object` to `ty`, unboxing primitives
+    pub fn unbox_value(&mut self, ty: &Type) {
+        if let Some((class, method)) = boxed_class(ty) {
+            self.op_with(Opcode::CheckCast, Operand::Symbol(class.into()));
+            self.invoke(Opcode::InvokeVirtual, format!("{class}/{method}(){}", ty.to_opcode()));
+        } else {
+            let descriptor = ty.to_opcode();
+            let class = descriptor
+                .strip_prefix('L')
+                .and_then(|d| d.strip_suffix(';'))
+                .unwrap_or(opcodes::CLASS_OBJECT);
+            self.op_with(Opcode::CheckCast, Operand::Symbol(class.into()));
+        }
+    }
+
+    pub fn jasmin(&mut self, code: &str) {
+        for instruction in ir::parse_jasmin(code) {
+            self.emit(instruction);
+        }
     }
 
     pub fn type_switch(&mut self, arms: &[(Vec<MatchInType>, Box<AstNode>)], chosen_index: Option<&usize>) -> Result<(), CodeGenErr>{
@@ -0,0 +1,206 @@
+//! Renders errors with the source code they point to, either for humans or as JSON for editors.
+
+use std::fmt::{Display, Write};
+
+use clap::ValueEnum;
+use klex::{Loc, RichToken};
+
+use crate::macr::SourceFile;
+
+/// A location in the source code together with the length of what is there
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub loc: Loc,
+    pub len: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+/// Secondary information about a diagnostic, like where else something relevant happened
+#[derive(Clone, Debug, PartialEq)]
+pub struct Note {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<Note>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ErrorFormat {
+    /// Underlines the code in question
+    #[default]
+    Human,
+    /// One JSON object per diagnostic and line
+    Json,
+}
+
+impl Span {
+    pub fn new(loc: Loc, len: usize) -> Self {
+        Self { loc, len }
+    }
+}
+
+impl From<Loc> for Span {
+    fn from(loc: Loc) -> Self {
+        Self::new(loc, 1)
+    }
+}
+
+impl From<&RichToken> for Span {
+    fn from(t: &RichToken) -> Self {
+        Self::new(t.loc, t.len)
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.loc)
+    }
+}
+
+impl Note {
+    pub fn new(message: impl Into<String>, span: Option<Span>) -> Self {
+        Self {
+            message: message.into(),
+            span,
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Option<Span>) -> Self {
+        Self {
+            level: Level::Error,
+            message: message.into(),
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Option<Span>) -> Self {
+        Self {
+            level: Level::Warning,
+            ..Self::error(message, span)
+        }
+    }
+
+    pub fn render(&self, files: &[SourceFile], format: ErrorFormat) -> String {
+        match format {
+            ErrorFormat::Human => self.render_human(files),
+            ErrorFormat::Json => self.render_json(files),
+        }
+    }
+
+    fn render_human(&self, files: &[SourceFile]) -> String {
+        let mut out = format!("{}: {}\n", self.level, self.message);
+        if let Some(span) = self.span {
+            render_snippet(&mut out, span, files);
+        }
+        for note in &self.notes {
+            let _ = writeln!(out, "note: {}", note.message);
+            if let Some(span) = note.span {
+                render_snippet(&mut out, span, files);
+            }
+        }
+        out
+    }
+
+    fn render_json(&self, files: &[SourceFile]) -> String {
+        let notes = self
+            .notes
+            .iter()
+            .map(|note| {
+                format!(
+                    r#"{{"message":{},"span":{}}}"#,
+                    json_string(&note.message),
+                    json_span(note.span, files)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            r#"{{"level":"{}","message":{},"span":{},"notes":[{notes}]}}"#,
+            self.level,
+            json_string(&self.message),
+            json_span(self.span, files)
+        )
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// ```text
+///   --> file.jack:3:5
+///      |
+///    3 | x "a" +
+///      |       ^
+/// ```
+fn render_snippet(out: &mut String, span: Span, files: &[SourceFile]) {
+    let Some(file) = files.get(span.loc.file_index) else {
+        let _ = writeln!(out, "  --> {}", span.loc);
+        return;
+    };
+    let _ = writeln!(out, "  --> {}:{}:{}", file.path.display(), span.loc.row, span.loc.col);
+    let Some(line) = file.src.lines().nth(span.loc.row.saturating_sub(1)) else {
+        return;
+    };
+    let col = span.loc.col.max(1);
+    // don't underline beyond the end of the line, but always at least one character
+    let len = span
+        .len
+        .min(line.chars().count().saturating_sub(col - 1))
+        .max(1);
+    let _ = writeln!(out, "     |");
+    let _ = writeln!(out, "{: >4} | {line}", span.loc.row);
+    let _ = writeln!(out, "     | {}{}", " ".repeat(col - 1), "^".repeat(len));
+}
+
+fn json_span(span: Option<Span>, files: &[SourceFile]) -> String {
+    let Some(span) = span else {
+        return "null".into();
+    };
+    let file = files
+        .get(span.loc.file_index)
+        .map(|f| json_string(&f.path.display().to_string()))
+        .unwrap_or_else(|| "null".into());
+    format!(
+        r#"{{"file":{file},"row":{},"col":{},"len":{}}}"#,
+        span.loc.row, span.loc.col, span.len
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,1010 @@
+//! Executes analyzed ASTs directly, without the JVM. Intrinsics can't be run as Jasmin, so the
+//! ones of the standard library are implemented here by their name; programs that use other
+//! intrinsics have to be compiled.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    io::{BufRead, Write},
+    rc::Rc,
+};
+
+use thiserror::Error;
+
+use crate::{
+    ast::{AstBase, AstItem, AstNode},
+    diagnostics::{Diagnostic, Span},
+    opcodes::CLASS_RUNTIME_EXCEPTION,
+};
+
+const ARITHMETIC_EXCEPTION: &str = "java/lang/ArithmeticException";
+const NUMBER_FORMAT_EXCEPTION: &str = "java/lang/NumberFormatException";
+const INDEX_EXCEPTION: &str = "java/lang/IndexOutOfBoundsException";
+const STRING_INDEX_EXCEPTION: &str = "java/lang/StringIndexOutOfBoundsException";
+const NULL_POINTER_EXCEPTION: &str = "java/lang/NullPointerException";
+
+/// The superclass of every exception class the interpreter throws, up to `Throwable`
+const SUPERCLASSES: [(&str, &str); 9] = [
+    (NUMBER_FORMAT_EXCEPTION, "java/lang/IllegalArgumentException"),
+    (STRING_INDEX_EXCEPTION, INDEX_EXCEPTION),
+    ("java/lang/IllegalArgumentException", CLASS_RUNTIME_EXCEPTION),
+    (INDEX_EXCEPTION, CLASS_RUNTIME_EXCEPTION),
+    (ARITHMETIC_EXCEPTION, CLASS_RUNTIME_EXCEPTION),
+    (NULL_POINTER_EXCEPTION, CLASS_RUNTIME_EXCEPTION),
+    (CLASS_RUNTIME_EXCEPTION, "java/lang/Exception"),
+    ("java/lang/Exception", "java/lang/Throwable"),
+    ("java/lang/Throwable", "java/lang/Object"),
+];
+
+#[derive(Error, Debug)]
+pub enum InterpErr {
+    #[error("cannot interpret intrinsic `{0}`, only those of the standard library are built in")]
+    UnsupportedIntrinsic(String, Span),
+    #[error("cannot interpret extern `{0}`, Java methods can only be called in compiled programs")]
+    UnsupportedExtern(String, Span),
+    #[error("runtime error: {0}")]
+    Runtime(String, Span),
+    /// What the JVM would throw an exception for, with the exception's class and a message;
+    /// `try` catches it
+    #[error("runtime error: {1}")]
+    Exception(String, String, Span),
+    #[error("node at {0} has not been analyzed yet!")]
+    NotAnalyzed(Span),
+    /// Not an error, `exit` unwinds the interpreter with it
+    #[error("exit with status {0}")]
+    Exit(i32),
+    /// Not an error, `break` unwinds to the innermost loop with it
+    #[error("`break` outside of a loop")]
+    Break,
+    /// Not an error, `continue` unwinds to the innermost loop with it
+    #[error("`continue` outside of a loop")]
+    Continue,
+}
+
+/// A value at runtime. Unlike the constant values of the analyzer, lists, maps and records are
+/// shared like the objects they are compiled to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Bool(bool),
+    Long(i64),
+    Double(f64),
+    String(String),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>),
+    Record(Rc<RefCell<Record>>),
+}
+
+/// An instance of a record, with its fields in the order they are declared in
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub fields: Vec<(String, Value)>,
+}
+
+/// A `HashMap`. Its entries are kept in the order they were put in, and `capacity` grows like the
+/// size of the JVM's table, so that iterating visits them in the same order as on the JVM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,
+    capacity: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Interpreter {
+    pub stack: Vec<Value>,
+    pub vars: HashMap<String, Value>,
+    /// All user-defined functions and externs seen so far
+    pub functions: HashMap<String, Rc<AstNode>>,
+    /// Whether everything printed so far ends with a newline
+    pub at_line_start: bool,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            vars: HashMap::new(),
+            functions: HashMap::new(),
+            at_line_start: true,
+        }
+    }
+
+    /// A copy whose lists, maps and records are copies, too, so that running code on one doesn't
+    /// change the other. Values shared within the interpreter stay shared within the copy.
+    pub fn deep_clone(&self) -> Self {
+        let mut copies = HashMap::new();
+        Self {
+            stack: self.stack.iter().map(|v| v.deep_clone(&mut copies)).collect(),
+            vars: self
+                .vars
+                .iter()
+                .map(|(name, v)| (name.clone(), v.deep_clone(&mut copies)))
+                .collect(),
+            functions: self.functions.clone(),
+            at_line_start: self.at_line_start,
+        }
+    }
+
+    /// Runs a whole program; returns the exit status
+    pub fn run(&mut self, ast: &AstBase) -> Result<i32, InterpErr> {
+        let result = match self.exec_all(ast) {
+            Ok(()) => Ok(0),
+            Err(InterpErr::Exit(status)) => Ok(status),
+            Err(e) => Err(e),
+        };
+        flush();
+        result
+    }
+
+    /// Executes the nodes of `ast` on top of the current stack and variables
+    pub fn exec_all(&mut self, ast: &AstBase) -> Result<(), InterpErr> {
+        // functions may be called before their definition
+        for node in &ast.nodes {
+            if let AstItem::Function { name, .. } | AstItem::Extern { name, .. } = &node.inner {
+                self.functions.insert(name.clone(), Rc::new(node.clone()));
+            }
+        }
+        ast.nodes.iter().try_for_each(|node| self.exec(node))
+    }
+
+    pub fn exec(&mut self, node: &AstNode) -> Result<(), InterpErr> {
+        match &node.inner {
+            AstItem::PushInt(n) => self.stack.push(Value::Int(*n)),
+            AstItem::PushBool(b) => self.stack.push(Value::Bool(*b)),
+            AstItem::PushLong(n) => self.stack.push(Value::Long(*n)),
+            AstItem::PushDouble(d) => self.stack.push(Value::Double(*d)),
+            AstItem::PushString(s) => self.stack.push(Value::String(s.clone())),
+            AstItem::List(_) => self.stack.push(Value::new_list(Vec::new())),
+            AstItem::Map(..) => self.stack.push(Value::Map(Rc::default())),
+            AstItem::ListLiteral(nodes) => {
+                let mut elems = Vec::with_capacity(nodes.len());
+                for n in nodes {
+                    self.exec(n)?;
+                    elems.push(self.pop(n)?);
+                }
+                self.stack.push(Value::new_list(elems));
+            }
+            AstItem::If { head, body, else_body } => {
+                if let Some(head) = head {
+                    self.exec(head)?;
+                }
+                if self.pop_bool(node)? {
+                    self.exec(body)?;
+                } else if let Some(else_body) = else_body {
+                    self.exec(else_body)?;
+                }
+            }
+            AstItem::Switch { arms, default } => {
+                let n = self.pop_int(node)?;
+                match arms.iter().find(|(arm, _)| *arm == n) {
+                    Some((_, body)) => self.exec(body)?,
+                    None => self.exec(default)?,
+                }
+            }
+            AstItem::While { head, body } => loop {
+                if let Some(head) = head {
+                    self.exec(head)?;
+                }
+                if !self.pop_bool(node)? || !self.exec_loop_body(body)? {
+                    break;
+                }
+            },
+            AstItem::For { init, condition, modifier, body } => {
+                self.exec(init)?;
+                loop {
+                    self.exec(condition)?;
+                    if !self.pop_bool(condition)? || !self.exec_loop_body(body)? {
+                        break;
+                    }
+                    self.exec(modifier)?;
+                }
+            }
+            AstItem::Try { body, catches, .. } => {
+                let stack = self.stack.clone();
+                match self.exec(body) {
+                    Err(InterpErr::Exception(class, message, span)) => {
+                        let Some((_, catch_body)) =
+                            catches.iter().find(|(caught, _)| is_subclass(&class, caught))
+                        else {
+                            return Err(InterpErr::Exception(class, message, span));
+                        };
+                        // like the JVM, the handler doesn't see what the body left on the stack
+                        self.stack = stack;
+                        self.exec(catch_body)?;
+                    }
+                    result => result?,
+                }
+            }
+            AstItem::Throw => {
+                let message = self.pop_string(node)?;
+                return Err(InterpErr::Exception(CLASS_RUNTIME_EXCEPTION.into(), message, node.span()));
+            }
+            AstItem::Break => return Err(InterpErr::Break),
+            AstItem::Continue => return Err(InterpErr::Continue),
+            AstItem::Block(children) => {
+                for c in children {
+                    self.exec(c)?;
+                }
+            }
+            AstItem::Store { initializer, name } => {
+                if let Some(init) = initializer {
+                    self.exec(init)?;
+                }
+                let value = self.pop(node)?;
+                self.vars.insert(name.clone(), value);
+            }
+            AstItem::Load(name) => {
+                let value = self.vars.get(name).cloned().ok_or_else(|| {
+                    InterpErr::Runtime(format!("unknown variable {name}"), node.span())
+                })?;
+                self.stack.push(value);
+            }
+            AstItem::Jasmin { name, .. } => self.intrinsic(name, node)?,
+            AstItem::TypeSwitch { arms, chosen_index } => {
+                let i = chosen_index.ok_or(InterpErr::NotAnalyzed(node.span()))?;
+                self.exec(&arms[i].1)?;
+            }
+            AstItem::CmpErr(_) => return Err(InterpErr::NotAnalyzed(node.span())),
+            // already registered
+            AstItem::Function { .. } => {}
+            AstItem::Call { name, .. } => self.call(name, node)?,
+            // records only exist for the analyzer and code gen
+            AstItem::Record { .. } => {}
+            // already registered
+            AstItem::Extern { .. } => {}
+            AstItem::New { record, fields } => {
+                let fields = fields.as_ref().ok_or(InterpErr::NotAnalyzed(node.span()))?;
+                if self.stack.len() < fields.len() {
+                    return Err(InterpErr::NotAnalyzed(node.span()));
+                }
+                let values = self.stack.split_off(self.stack.len() - fields.len());
+                let record = Record {
+                    name: record.clone(),
+                    fields: fields.iter().map(|(name, _)| name.clone()).zip(values).collect(),
+                };
+                self.stack.push(Value::Record(Rc::new(RefCell::new(record))));
+            }
+            AstItem::GetField { name, .. } => {
+                let record = self.pop_record(node)?;
+                let value = record.borrow().field(name, node)?.clone();
+                self.stack.push(value);
+            }
+            AstItem::SetField { initializer, name, .. } => {
+                if let Some(init) = initializer {
+                    self.exec(init)?;
+                }
+                let value = self.pop(node)?;
+                let record = self.pop_record(node)?;
+                *record.borrow_mut().field_mut(name, node)? = value;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the body of a loop; returns whether the loop goes on
+    fn exec_loop_body(&mut self, body: &AstNode) -> Result<bool, InterpErr> {
+        match self.exec(body) {
+            Ok(()) | Err(InterpErr::Continue) => Ok(true),
+            Err(InterpErr::Break) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs the function on a fresh stack with fresh variables, like the static method it is
+    /// compiled to
+    fn call(&mut self, name: &str, node: &AstNode) -> Result<(), InterpErr> {
+        let function = self.functions.get(name).cloned().ok_or_else(|| {
+            InterpErr::Runtime(format!("unknown function {name}"), node.span())
+        })?;
+        let (signature, body) = match &function.inner {
+            AstItem::Function { signature, body, .. } => (signature, body),
+            AstItem::Extern { target, .. } => {
+                return Err(InterpErr::UnsupportedExtern(target.clone(), node.span()))
+            }
+            _ => unreachable!(),
+        };
+        if self.stack.len() < signature.input.len() {
+            return Err(InterpErr::NotAnalyzed(node.span()));
+        }
+        let args = self.stack.split_off(self.stack.len() - signature.input.len());
+        let mut callee = Self {
+            vars: args
+                .iter()
+                .enumerate()
+                .map(|(i, arg)| (format!("${i}"), arg.clone()))
+                .collect(),
+            stack: args,
+            functions: self.functions.clone(),
+            at_line_start: self.at_line_start,
+        };
+        let result = callee.exec(body);
+        self.at_line_start = callee.at_line_start;
+        result?;
+        self.stack.extend(callee.stack);
+        Ok(())
+    }
+
+    /// The standard library's intrinsics, by the names they are declared with
+    fn intrinsic(&mut self, name: &str, node: &AstNode) -> Result<(), InterpErr> {
+        let runtime_err = |msg: String| InterpErr::Runtime(msg, node.span());
+        let exception = |class: &'static str| {
+            let span = node.span();
+            move |msg: String| InterpErr::Exception(class.into(), msg, span)
+        };
+        match name {
+            "drop" => {
+                self.pop(node)?;
+            }
+            "dup" => {
+                let a = self.pop(node)?;
+                self.stack.extend([a.clone(), a]);
+            }
+            "swap" => {
+                let b = self.pop(node)?;
+                let a = self.pop(node)?;
+                self.stack.extend([b, a]);
+            }
+            "dupx1" => {
+                let b = self.pop(node)?;
+                let a = self.pop(node)?;
+                self.stack.extend([b.clone(), a, b]);
+            }
+            "+" | "-" | "*" | "/" | "==" | "<" | ">" | "<=" | ">=" => {
+                let b = self.pop(node)?;
+                let a = self.pop(node)?;
+                let result = match (name, &a, &b) {
+                    ("==", _, _) => Value::Bool(a == b),
+                    (_, Value::Int(a), Value::Int(b)) => match name {
+                        "+" => Value::Int(a.wrapping_add(*b)),
+                        "-" => Value::Int(a.wrapping_sub(*b)),
+                        "*" => Value::Int(a.wrapping_mul(*b)),
+                        "/" if *b == 0 => return Err(exception(ARITHMETIC_EXCEPTION)("division by zero".into())),
+                        "/" => Value::Int(a.wrapping_div(*b)),
+                        "<" => Value::Bool(a < b),
+                        ">" => Value::Bool(a > b),
+                        "<=" => Value::Bool(a <= b),
+                        _ => Value::Bool(a >= b),
+                    },
+                    (_, Value::Long(a), Value::Long(b)) => match name {
+                        "+" => Value::Long(a.wrapping_add(*b)),
+                        "-" => Value::Long(a.wrapping_sub(*b)),
+                        "*" => Value::Long(a.wrapping_mul(*b)),
+                        "/" if *b == 0 => return Err(exception(ARITHMETIC_EXCEPTION)("division by zero".into())),
+                        "/" => Value::Long(a.wrapping_div(*b)),
+                        "<" => Value::Bool(a < b),
+                        ">" => Value::Bool(a > b),
+                        "<=" => Value::Bool(a <= b),
+                        _ => Value::Bool(a >= b),
+                    },
+                    (_, Value::Double(a), Value::Double(b)) => match name {
+                        "+" => Value::Double(a + b),
+                        "-" => Value::Double(a - b),
+                        "*" => Value::Double(a * b),
+                        "/" => Value::Double(a / b),
+                        "<" => Value::Bool(a < b),
+                        ">" => Value::Bool(a > b),
+                        "<=" => Value::Bool(a <= b),
+                        _ => Value::Bool(a >= b),
+                    },
+                    _ => return Err(InterpErr::NotAnalyzed(node.span())),
+                };
+                self.stack.push(result);
+            }
+            "not" => {
+                let a = self.pop_bool(node)?;
+                self.stack.push(Value::Bool(!a));
+            }
+            "and" | "or" => {
+                let b = self.pop_bool(node)?;
+                let a = self.pop_bool(node)?;
+                self.stack.push(Value::Bool(if name == "and" { a && b } else { a || b }));
+            }
+            "@int" => {
+                let n = match self.pop(node)? {
+                    Value::String(s) => s
+                        .parse()
+                        .map_err(|_| exception(NUMBER_FORMAT_EXCEPTION)(format!("cannot `@int` {s:?}")))?,
+                    Value::Bool(b) => b as i32,
+                    // keeps the lower 32 bits like `l2i`
+                    Value::Long(n) => n as i32,
+                    // saturates like `d2i`, NaN becomes 0
+                    Value::Double(d) => d as i32,
+                    _ => return Err(InterpErr::NotAnalyzed(node.span())),
+                };
+                self.stack.push(Value::Int(n));
+            }
+            "@long" => {
+                let n = match self.pop(node)? {
+                    Value::String(s) => s
+                        .parse()
+                        .map_err(|_| exception(NUMBER_FORMAT_EXCEPTION)(format!("cannot `@long` {s:?}")))?,
+                    Value::Int(n) => n as i64,
+                    _ => return Err(InterpErr::NotAnalyzed(node.span())),
+                };
+                self.stack.push(Value::Long(n));
+            }
+            "@double" => {
+                let d = match self.pop(node)? {
+                    Value::String(s) => s
+                        .trim()
+                        .parse()
+                        .map_err(|_| exception(NUMBER_FORMAT_EXCEPTION)(format!("cannot `@double` {s:?}")))?,
+                    Value::Int(n) => n as f64,
+                    _ => return Err(InterpErr::NotAnalyzed(node.span())),
+                };
+                self.stack.push(Value::Double(d));
+            }
+            "@bool" => {
+                let n = self.pop_int(node)?;
+                self.stack.push(Value::Bool(n != 0));
+            }
+            "@string" => {
+                let value = self.pop(node)?;
+                self.stack.push(Value::String(value.to_string()));
+            }
+            "@charlist" => {
+                let s = self.pop_string(node)?;
+                // `toCharArray` gives UTF-16 code units, so characters outside the BMP are two
+                self.stack
+                    .push(Value::new_list(s.encode_utf16().map(|c| Value::Int(c as i32)).collect()));
+            }
+            "concat" => {
+                let b = self.pop_string(node)?;
+                let a = self.pop_string(node)?;
+                self.stack.push(Value::String(a + &b));
+            }
+            "strlen" => {
+                let s = self.pop_string(node)?;
+                self.stack.push(Value::Int(java_len(&s)));
+            }
+            "substr" => {
+                let end = self.pop_int(node)?;
+                let begin = self.pop_int(node)?;
+                let s = self.pop_string(node)?;
+                let sub = java_substring(&s, begin, end).map_err(exception(STRING_INDEX_EXCEPTION))?;
+                self.stack.push(Value::String(sub));
+            }
+            "charat" => {
+                let i = self.pop_int(node)?;
+                let s = self.pop_string(node)?;
+                let c = java_char_at(&s, i).map_err(exception(STRING_INDEX_EXCEPTION))?;
+                self.stack.push(Value::Int(c));
+            }
+            "split" => {
+                let separator = self.pop_string(node)?;
+                let s = self.pop_string(node)?;
+                let parts = java_split(&s, &separator).into_iter().map(Value::String).collect();
+                self.stack.push(Value::new_list(parts));
+            }
+            "trim" | "upper" | "lower" => {
+                let s = self.pop_string(node)?;
+                self.stack.push(Value::String(match name {
+                    "trim" => java_trim(&s).into(),
+                    "upper" => s.to_uppercase(),
+                    _ => s.to_lowercase(),
+                }));
+            }
+            "contains" | "startswith" => {
+                let b = self.pop_string(node)?;
+                let a = self.pop_string(node)?;
+                let result = if name == "contains" { a.contains(&b) } else { a.starts_with(&b) };
+                self.stack.push(Value::Bool(result));
+            }
+            "indexof" => {
+                let b = self.pop_string(node)?;
+                let a = self.pop_string(node)?;
+                self.stack.push(Value::Int(java_index_of(&a, &b)));
+            }
+            "print" => {
+                let value = self.pop(node)?.to_string();
+                self.print(&value);
+            }
+            "printc" => {
+                let c = self.pop_int(node)?;
+                self.print(&char::from_u32(c as u32).unwrap_or(char::REPLACEMENT_CHARACTER).to_string());
+            }
+            "readln" => {
+                flush();
+                let mut line = String::new();
+                let read = std::io::stdin()
+                    .lock()
+                    .read_line(&mut line)
+                    .map_err(|e| runtime_err(format!("cannot read from stdin: {e}")))?;
+                if read == 0 {
+                    return Err(runtime_err("`readln` hit the end of the input".into()));
+                }
+                let len = line.trim_end_matches(['\n', '\r']).len();
+                line.truncate(len);
+                self.stack.push(Value::String(line));
+            }
+            "exit" => return Err(InterpErr::Exit(self.pop_int(node)?)),
+            "push" => {
+                let value = self.pop(node)?;
+                let list = self.pop_list(node)?;
+                list.borrow_mut().push(value);
+                // `ArrayList.add` always returns true
+                self.stack.push(Value::Int(1));
+            }
+            "pop" => {
+                let i = self.pop_int(node)?;
+                let list = self.pop_list(node)?;
+                let mut list = list.borrow_mut();
+                if i < 0 || i as usize >= list.len() {
+                    return Err(exception(INDEX_EXCEPTION)("cannot `pop` from an empty list".into()));
+                }
+                let value = list.remove(i as usize);
+                self.stack.push(value);
+            }
+            "len" => {
+                let len = match self.pop(node)? {
+                    Value::List(xs) => xs.borrow().len(),
+                    Value::Map(map) => map.borrow().entries.len(),
+                    _ => return Err(InterpErr::NotAnalyzed(node.span())),
+                };
+                self.stack.push(Value::Int(len as i32));
+            }
+            "get" => {
+                let value = match (self.pop(node)?, self.pop(node)?) {
+                    (Value::Int(i), Value::List(xs)) => {
+                        index(&xs.borrow(), i).map_err(exception(INDEX_EXCEPTION))?.clone()
+                    }
+                    (key, Value::Map(map)) => map
+                        .borrow()
+                        .get(&key)
+                        .cloned()
+                        .ok_or_else(|| exception(NULL_POINTER_EXCEPTION)(format!("no value for the key {key} in the map")))?,
+                    _ => return Err(InterpErr::NotAnalyzed(node.span())),
+                };
+                self.stack.push(value);
+            }
+            "put" => {
+                let value = self.pop(node)?;
+                let key = self.pop(node)?;
+                self.pop_map(node)?.borrow_mut().put(key, value);
+            }
+            "has" => {
+                let key = self.pop(node)?;
+                let has = self.pop_map(node)?.borrow().get(&key).is_some();
+                self.stack.push(Value::Bool(has));
+            }
+            "remove" => {
+                let key = self.pop(node)?;
+                self.pop_map(node)?.borrow_mut().remove(&key);
+            }
+            "keys" => {
+                let map = self.pop_map(node)?;
+                let keys = map.borrow().iter().map(|(key, _)| key.clone()).collect();
+                self.stack.push(Value::new_list(keys));
+            }
+            "set" => {
+                let value = self.pop(node)?;
+                let i = self.pop_int(node)?;
+                let list = self.pop_list(node)?;
+                index(&list.borrow(), i).map_err(exception(INDEX_EXCEPTION))?;
+                list.borrow_mut()[i as usize] = value;
+            }
+            // boxing and casts, which don't change the value
+            "_integer_to_int" | "_int_to_integer" | "_boolean_to_bool" | "_bool_to_boolean"
+            | "_boxed_to_long" | "_long_to_boxed" | "_boxed_to_double" | "_double_to_boxed"
+            | "_obj_to_string" | "_obj_to_map" => {}
+            _ => return Err(InterpErr::UnsupportedIntrinsic(name.into(), node.span())),
+        }
+        Ok(())
+    }
+
+    fn print(&mut self, s: &str) {
+        if let Some(last) = s.chars().last() {
+            self.at_line_start = last == '\n';
+        }
+        print!("{s}");
+    }
+
+    fn pop(&mut self, node: &AstNode) -> Result<Value, InterpErr> {
+        self.stack.pop().ok_or(InterpErr::NotAnalyzed(node.span()))
+    }
+
+    fn pop_int(&mut self, node: &AstNode) -> Result<i32, InterpErr> {
+        match self.pop(node)? {
+            Value::Int(n) => Ok(n),
+            _ => Err(InterpErr::NotAnalyzed(node.span())),
+        }
+    }
+
+    fn pop_bool(&mut self, node: &AstNode) -> Result<bool, InterpErr> {
+        match self.pop(node)? {
+            Value::Bool(b) => Ok(b),
+            _ => Err(InterpErr::NotAnalyzed(node.span())),
+        }
+    }
+
+    fn pop_string(&mut self, node: &AstNode) -> Result<String, InterpErr> {
+        match self.pop(node)? {
+            Value::String(s) => Ok(s),
+            _ => Err(InterpErr::NotAnalyzed(node.span())),
+        }
+    }
+
+    fn pop_list(&mut self, node: &AstNode) -> Result<Rc<RefCell<Vec<Value>>>, InterpErr> {
+        match self.pop(node)? {
+            Value::List(xs) => Ok(xs),
+            _ => Err(InterpErr::NotAnalyzed(node.span())),
+        }
+    }
+
+    fn pop_map(&mut self, node: &AstNode) -> Result<Rc<RefCell<Map>>, InterpErr> {
+        match self.pop(node)? {
+            Value::Map(map) => Ok(map),
+            _ => Err(InterpErr::NotAnalyzed(node.span())),
+        }
+    }
+
+    fn pop_record(&mut self, node: &AstNode) -> Result<Rc<RefCell<Record>>, InterpErr> {
+        match self.pop(node)? {
+            Value::Record(record) => Ok(record),
+            _ => Err(InterpErr::NotAnalyzed(node.span())),
+        }
+    }
+}
+
+impl InterpErr {
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            Self::UnsupportedIntrinsic(_, span)
+            | Self::UnsupportedExtern(_, span)
+            | Self::Runtime(_, span)
+            | Self::Exception(_, _, span)
+            | Self::NotAnalyzed(span) => {
+                Diagnostic::error(self.to_string(), Some(*span))
+            }
+            Self::Exit(_) | Self::Break | Self::Continue => Diagnostic::error(self.to_string(), None),
+        }
+    }
+}
+
+impl Value {
+    pub fn new_list(elems: Vec<Value>) -> Self {
+        Self::List(Rc::new(RefCell::new(elems)))
+    }
+
+    /// See [`Interpreter::deep_clone`]; `copies` maps the objects copied so far to their copy,
+    /// which keeps them shared and lets lists contain themselves
+    fn deep_clone(&self, copies: &mut HashMap<*const (), Value>) -> Self {
+        let ptr = match self {
+            Self::List(xs) => Rc::as_ptr(xs) as *const (),
+            Self::Map(map) => Rc::as_ptr(map) as *const (),
+            Self::Record(record) => Rc::as_ptr(record) as *const (),
+            _ => return self.clone(),
+        };
+        if let Some(copy) = copies.get(&ptr) {
+            return copy.clone();
+        }
+        match self {
+            Self::List(xs) => {
+                let copy = Rc::new(RefCell::new(Vec::new()));
+                copies.insert(ptr, Self::List(copy.clone()));
+                let elems = xs.borrow().iter().map(|x| x.deep_clone(copies)).collect();
+                *copy.borrow_mut() = elems;
+                Self::List(copy)
+            }
+            Self::Map(map) => {
+                let copy = Rc::new(RefCell::new(Map::default()));
+                copies.insert(ptr, Self::Map(copy.clone()));
+                let map = map.borrow();
+                let entries = map
+                    .entries
+                    .iter()
+                    .map(|(k, v)| (k.deep_clone(copies), v.deep_clone(copies)))
+                    .collect();
+                *copy.borrow_mut() = Map {
+                    entries,
+                    capacity: map.capacity,
+                };
+                Self::Map(copy)
+            }
+            Self::Record(record) => {
+                let copy = Rc::new(RefCell::new(Record {
+                    name: record.borrow().name.clone(),
+                    fields: Vec::new(),
+                }));
+                copies.insert(ptr, Self::Record(copy.clone()));
+                let fields = record
+                    .borrow()
+                    .fields
+                    .iter()
+                    .map(|(name, v)| (name.clone(), v.deep_clone(copies)))
+                    .collect();
+                copy.borrow_mut().fields = fields;
+                Self::Record(copy)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Like `hashCode` in Java
+    fn hash_code(&self) -> i32 {
+        match self {
+            Self::Int(n) => *n,
+            Self::Bool(b) => if *b { 1231 } else { 1237 },
+            Self::Long(n) => (n ^ (n >> 32)) as i32,
+            Self::Double(d) => {
+                // `doubleToLongBits` has a single NaN
+                let bits = if d.is_nan() { 0x7ff8000000000000 } else { d.to_bits() };
+                (bits ^ (bits >> 32)) as i32
+            }
+            Self::String(s) => s
+                .encode_utf16()
+                .fold(0, |h: i32, c| h.wrapping_mul(31).wrapping_add(c as i32)),
+            Self::List(xs) => xs
+                .borrow()
+                .iter()
+                .fold(1, |h: i32, x| h.wrapping_mul(31).wrapping_add(x.hash_code())),
+            Self::Map(map) => map
+                .borrow()
+                .entries
+                .iter()
+                .fold(0, |h: i32, (k, v)| h.wrapping_add(k.hash_code() ^ v.hash_code())),
+            // the JVM's identity hash can't be predicted, any constant is as good
+            Self::Record(_) => 0,
+        }
+    }
+}
+
+impl Record {
+    pub fn field(&self, name: &str, node: &AstNode) -> Result<&Value, InterpErr> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+            .ok_or(InterpErr::NotAnalyzed(node.span()))
+    }
+
+    pub fn field_mut(&mut self, name: &str, node: &AstNode) -> Result<&mut Value, InterpErr> {
+        self.fields
+            .iter_mut()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+            .ok_or(InterpErr::NotAnalyzed(node.span()))
+    }
+}
+
+impl Default for Map {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            capacity: 16,
+        }
+    }
+}
+
+impl Map {
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn put(&mut self, key: Value, value: Value) {
+        if let Some(entry) = self.entries.iter_mut().find(|(k, _)| *k == key) {
+            entry.1 = value;
+            return;
+        }
+        self.entries.push((key, value));
+        // the table doubles once it is three quarters full, and never shrinks
+        if self.entries.len() > self.capacity / 4 * 3 {
+            self.capacity *= 2;
+        }
+    }
+
+    pub fn remove(&mut self, key: &Value) {
+        self.entries.retain(|(k, _)| k != key);
+    }
+
+    /// The entries in the order `HashMap` iterates over them: by the bucket of the table their
+    /// hash falls into, and within a bucket in the order they were put in
+    pub fn iter(&self) -> impl Iterator<Item = &(Value, Value)> {
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(key, _)| {
+            let h = key.hash_code() as u32;
+            (h ^ (h >> 16)) as usize & (self.capacity - 1)
+        });
+        entries.into_iter()
+    }
+}
+
+/// Prints like `toString` in Java
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(n) => write!(f, "{n}"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Long(n) => write!(f, "{n}"),
+            Self::Double(d) => write!(f, "{}", format_double(*d)),
+            Self::String(s) => write!(f, "{s}"),
+            Self::List(xs) => {
+                write!(f, "[")?;
+                for (i, x) in xs.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{x}")?;
+                }
+                write!(f, "]")
+            }
+            Self::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{key}={value}")?;
+                }
+                write!(f, "}}")
+            }
+            Self::Record(record) => {
+                let record = record.borrow();
+                write!(f, "{}[", record.name)?;
+                for (i, (name, value)) in record.fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{name}={value}")?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Formats like Java's `Double.toString`: plain between 10^-3 and 10^7, in scientific notation
+/// otherwise, and always with a fractional part
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        return "NaN".into();
+    }
+    if d.is_infinite() {
+        return if d > 0.0 { "Infinity" } else { "-Infinity" }.into();
+    }
+    if d == 0.0 || (1e-3..1e7).contains(&d.abs()) {
+        // Rust prints the same shortest digits, and only switches to an exponent further out
+        return format!("{d:?}");
+    }
+    let scientific = format!("{d:e}");
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    if mantissa.contains('.') {
+        format!("{mantissa}E{exponent}")
+    } else {
+        format!("{mantissa}.0E{exponent}")
+    }
+}
+
+// Java counts the characters of a string in UTF-16 code units, so the string intrinsics below
+// index them like that as well.
+
+/// Like `String.length` in Java
+pub fn java_len(s: &str) -> i32 {
+    s.encode_utf16().count() as i32
+}
+
+/// Like `String.substring` in Java. A surrogate pair that gets split becomes U+FFFD, where Java
+/// keeps the lone surrogate.
+pub fn java_substring(s: &str, begin: i32, end: i32) -> Result<String, String> {
+    java_substring_units(s, begin, end).map(|units| String::from_utf16_lossy(&units))
+}
+
+/// The UTF-16 code units that `String.substring` in Java returns
+pub fn java_substring_units(s: &str, begin: i32, end: i32) -> Result<Vec<u16>, String> {
+    let units = s.encode_utf16().collect::<Vec<_>>();
+    if begin < 0 || end < begin || end as usize > units.len() {
+        return Err(format!("begin {begin}, end {end} out of bounds for length {}", units.len()));
+    }
+    Ok(units[begin as usize..end as usize].to_vec())
+}
+
+/// Like `String.charAt` in Java
+pub fn java_char_at(s: &str, i: i32) -> Result<i32, String> {
+    usize::try_from(i)
+        .ok()
+        .and_then(|i| s.encode_utf16().nth(i))
+        .map(i32::from)
+        .ok_or_else(|| format!("index {i} out of bounds for length {}", java_len(s)))
+}
+
+/// Like `String.indexOf` in Java
+pub fn java_index_of(s: &str, sub: &str) -> i32 {
+    s.find(sub).map_or(-1, |i| java_len(&s[..i]))
+}
+
+/// Like `String.trim` in Java, which strips the space and all control characters before it
+pub fn java_trim(s: &str) -> &str {
+    s.trim_matches(|c| c <= ' ')
+}
+
+/// Like `s.split(Pattern.quote(separator))` in Java: the separator is no regular expression,
+/// and trailing empty strings are dropped
+pub fn java_split(s: &str, separator: &str) -> Vec<String> {
+    if s.is_empty() {
+        return vec![String::new()];
+    }
+    let mut parts = s.split(separator).map(String::from).collect::<Vec<_>>();
+    // Java doesn't split in front of the first character
+    if separator.is_empty() {
+        parts.remove(0);
+    }
+    while parts.last().is_some_and(String::is_empty) {
+        parts.pop();
+    }
+    parts
+}
+
+/// Whether an exception of `class` is caught by `catch "<ancestor>"`, going by the classes of
+/// the exceptions that the interpreter throws
+fn is_subclass(class: &str, ancestor: &str) -> bool {
+    let mut class = Some(class);
+    while let Some(c) = class {
+        if c == ancestor {
+            return true;
+        }
+        class = SUPERCLASSES.iter().find(|(sub, _)| *sub == c).map(|(_, sup)| *sup);
+    }
+    false
+}
+
+fn index(list: &[Value], i: i32) -> Result<&Value, String> {
+    usize::try_from(i)
+        .ok()
+        .and_then(|i| list.get(i))
+        .ok_or_else(|| format!("index {i} out of bounds for length {}", list.len()))
+}
+
+fn flush() {
+    let _ = std::io::stdout().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(map: &Map) -> Vec<Value> {
+        map.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    #[test]
+    fn maps_iterate_like_the_jvm() {
+        // as printed by `java.util.HashMap`
+        let mut map = Map::default();
+        for (i, key) in ["banana", "apple", "cherry", "date"].into_iter().enumerate() {
+            map.put(Value::String(key.into()), Value::Int(i as i32));
+        }
+        let expected = ["banana", "date", "apple", "cherry"].map(|key| Value::String(key.into()));
+        assert_eq!(keys(&map), expected);
+        // the 13th key doubles the table to 32 buckets
+        let mut map = Map::default();
+        for i in 0..14 {
+            map.put(Value::Int(i * 100), Value::Int(i));
+        }
+        let expected = [0, 800, 100, 900, 200, 1000, 300, 1100, 400, 1200, 500, 1300, 600, 700];
+        assert_eq!(keys(&map), expected.map(Value::Int));
+    }
+
+    #[test]
+    fn maps_replace_and_remove() {
+        let mut map = Map::default();
+        map.put(Value::Int(1), Value::Int(10));
+        map.put(Value::Int(1), Value::Int(11));
+        assert_eq!(map.get(&Value::Int(1)), Some(&Value::Int(11)));
+        assert_eq!(keys(&map).len(), 1);
+        map.remove(&Value::Int(1));
+        assert_eq!(map.get(&Value::Int(1)), None);
+        // Java's hash codes of different types collide, their values don't
+        map.put(Value::Int(1231), Value::Int(0));
+        map.put(Value::Bool(true), Value::Int(1));
+        assert_eq!(map.get(&Value::Int(1231)), Some(&Value::Int(0)));
+        assert_eq!(map.get(&Value::Bool(true)), Some(&Value::Int(1)));
+    }
+}
@@ -0,0 +1,532 @@
+//! The instructions that code generation targets. [`ClassWriter`](crate::codegen::ClassWriter)
+//! collects them per method; optimizations work on them, [`classfile`](crate::classfile)
+//! assembles them, and printing them gives the Jasmin for `--jasmin`.
+
+use std::{collections::HashSet, fmt, iter::Peekable, str::Chars};
+
+use crate::{classfile, opcodes};
+
+macro_rules! opcode_enum {
+    ($($variant:ident = $mnemonic:path),* $(,)?) => {
+        /// The JVM instructions that Jack generates, named after the mnemonics in [`opcodes`]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Opcode {
+            $($variant),*
+        }
+
+        impl Opcode {
+            pub const ALL: &'static [Opcode] = &[$(Self::$variant),*];
+
+            pub fn mnemonic(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $mnemonic),*
+                }
+            }
+        }
+    };
+}
+
+opcode_enum! {
+    IConstM1 = opcodes::ICONST_M1,
+    IConst0 = opcodes::ICONST_0,
+    IConst1 = opcodes::ICONST_1,
+    IConst2 = opcodes::ICONST_2,
+    IConst3 = opcodes::ICONST_3,
+    IConst4 = opcodes::ICONST_4,
+    IConst5 = opcodes::ICONST_5,
+    BiPush = opcodes::BIPUSH,
+    SiPush = opcodes::SIPUSH,
+    Ldc = opcodes::LDC,
+    DConst0 = opcodes::DCONST_0,
+    DConst1 = opcodes::DCONST_1,
+    Ldc2W = opcodes::LDC2_W,
+    LConst0 = opcodes::LCONST_0,
+    LConst1 = opcodes::LCONST_1,
+    New = opcodes::NEW,
+    Pop = opcodes::POP,
+    Pop2 = opcodes::POP2,
+    Dup = opcodes::DUP,
+    DupX1 = opcodes::DUPX1,
+    DupX2 = opcodes::DUPX2,
+    Dup2 = opcodes::DUP2,
+    Dup2X1 = opcodes::DUP2X1,
+    Dup2X2 = opcodes::DUP2X2,
+    Swap = opcodes::SWAP,
+    IAdd = opcodes::I_ADD,
+    ISub = opcodes::I_SUB,
+    IMul = opcodes::I_MUL,
+    IDiv = opcodes::I_DIV,
+    INeg = opcodes::I_NEG,
+    IUShr = opcodes::I_USHR,
+    IXor = opcodes::I_XOR,
+    IAnd = opcodes::I_AND,
+    IOr = opcodes::I_OR,
+    DAdd = opcodes::D_ADD,
+    DSub = opcodes::D_SUB,
+    DMul = opcodes::D_MUL,
+    DDiv = opcodes::D_DIV,
+    DCmpL = opcodes::D_CMPL,
+    DCmpG = opcodes::D_CMPG,
+    I2D = opcodes::I2D,
+    D2I = opcodes::D2I,
+    LAdd = opcodes::L_ADD,
+    LSub = opcodes::L_SUB,
+    LMul = opcodes::L_MUL,
+    LDiv = opcodes::L_DIV,
+    LRem = opcodes::L_REM,
+    LCmp = opcodes::L_CMP,
+    I2L = opcodes::I2L,
+    L2I = opcodes::L2I,
+    IfEq = opcodes::IF_EQ,
+    IfNe = opcodes::IF_NE,
+    IfLt = opcodes::IF_LT,
+    IfGe = opcodes::IF_GE,
+    IfGt = opcodes::IF_GT,
+    IfLe = opcodes::IF_LE,
+    IfICmpEq = opcodes::IF_ICMP_EQ,
+    IfICmpNe = opcodes::IF_ICMP_NE,
+    IfICmpLt = opcodes::IF_ICMP_LT,
+    IfICmpGe = opcodes::IF_ICMP_GE,
+    IfICmpGt = opcodes::IF_ICMP_GT,
+    IfICmpLe = opcodes::IF_ICMP_LE,
+    Goto = opcodes::GOTO,
+    InvokeStatic = opcodes::INVOKE_STATIC,
+    InvokeVirtual = opcodes::INVOKE_VIRTUAL,
+    InvokeInterface = opcodes::INVOKE_INTERFACE,
+    InvokeSpecial = opcodes::INVOKE_SPECIAL,
+    IStore = opcodes::I_STORE,
+    DStore = opcodes::D_STORE,
+    LStore = opcodes::L_STORE,
+    AStore = opcodes::A_STORE,
+    ILoad = opcodes::I_LOAD,
+    DLoad = opcodes::D_LOAD,
+    LLoad = opcodes::L_LOAD,
+    ALoad = opcodes::A_LOAD,
+    Return = opcodes::RETURN,
+    IReturn = opcodes::I_RETURN,
+    DReturn = opcodes::D_RETURN,
+    LReturn = opcodes::L_RETURN,
+    AReturn = opcodes::A_RETURN,
+    GetStatic = opcodes::GET_STATIC,
+    GetField = opcodes::GET_FIELD,
+    PutField = opcodes::PUT_FIELD,
+    CheckCast = opcodes::CHECK_CAST,
+    AThrow = opcodes::ATHROW,
+}
+
+impl Opcode {
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|op| op.mnemonic() == mnemonic)
+    }
+
+    /// The conditional jump with the opposite condition
+    pub fn negated(self) -> Option<Self> {
+        use Opcode::*;
+        Some(match self {
+            IfEq => IfNe,
+            IfNe => IfEq,
+            IfLt => IfGe,
+            IfGe => IfLt,
+            IfGt => IfLe,
+            IfLe => IfGt,
+            IfICmpEq => IfICmpNe,
+            IfICmpNe => IfICmpEq,
+            IfICmpLt => IfICmpGe,
+            IfICmpGe => IfICmpLt,
+            IfICmpGt => IfICmpLe,
+            IfICmpLe => IfICmpGt,
+            _ => return None,
+        })
+    }
+
+    /// The conditional jump that compares to 0 instead of to a second value
+    pub fn against_zero(self) -> Option<Self> {
+        use Opcode::*;
+        Some(match self {
+            IfICmpEq => IfEq,
+            IfICmpNe => IfNe,
+            IfICmpLt => IfLt,
+            IfICmpGe => IfGe,
+            IfICmpGt => IfGt,
+            IfICmpLe => IfLe,
+            _ => return None,
+        })
+    }
+
+    /// Whether the instruction's operand is the index of a local variable
+    pub fn takes_local(self) -> bool {
+        matches!(
+            self,
+            Self::IStore
+                | Self::LStore
+                | Self::DStore
+                | Self::AStore
+                | Self::ILoad
+                | Self::LLoad
+                | Self::DLoad
+                | Self::ALoad
+        )
+    }
+}
+
+/// A jump target, unique within its class. The name only makes the Jasmin easier to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label {
+    pub id: usize,
+    pub name: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Int(i32),
+    /// A long constant for `ldc2_w`
+    Long(i64),
+    /// A double constant for `ldc2_w`
+    Double(f64),
+    /// A string constant for `ldc`
+    String(String),
+    /// The index of a local variable
+    Local(usize),
+    Label(Label),
+    /// A class, field, method or type, written like in Jasmin
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Op(Opcode, Vec<Operand>),
+    LookupSwitch {
+        arms: Vec<(i32, Label)>,
+        default: Label,
+    },
+    Label(Label),
+    /// Declares the stack map frame of the next instruction by the verification types of the
+    /// local variables and the stack
+    Frame {
+        locals: Vec<String>,
+        stack: Vec<String>,
+    },
+    /// The line in the source file that the following instructions were generated from
+    Line(usize),
+    /// An entry of the exception table: exceptions of `class` thrown by the instructions from
+    /// `from` up to `to` jump to `handler`
+    Catch {
+        class: String,
+        from: Label,
+        to: Label,
+        handler: Label,
+    },
+    /// Jasmin that isn't understood, like the rare intrinsic with a comment; printed as it is
+    Raw(String),
+}
+
+impl Instruction {
+    pub fn op(opcode: Opcode) -> Self {
+        Self::Op(opcode, Vec::new())
+    }
+
+    pub fn opcode(&self) -> Option<Opcode> {
+        match self {
+            Self::Op(opcode, _) => Some(*opcode),
+            _ => None,
+        }
+    }
+
+    pub fn operands(&self) -> &[Operand] {
+        match self {
+            Self::Op(_, operands) => operands,
+            _ => &[],
+        }
+    }
+
+    /// Whether the instruction ends up as bytecode, unlike labels, frames and the other directives
+    pub fn is_code(&self) -> bool {
+        matches!(self, Self::Op(..) | Self::LookupSwitch { .. } | Self::Raw(_))
+    }
+
+    /// Whether the instruction after this one only runs if something jumps to it
+    pub fn ends_flow(&self) -> bool {
+        use Opcode::*;
+        match self {
+            Self::Op(opcode, _) => {
+                matches!(opcode, Goto | Return | IReturn | LReturn | DReturn | AReturn | AThrow)
+            }
+            Self::LookupSwitch { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// The labels that instructions jump to and exceptions are handled at; `None` if a jump's target
+/// isn't a label of the IR
+fn jump_targets(code: &[Instruction]) -> Option<HashSet<Label>> {
+    let mut targets = HashSet::new();
+    for instruction in code {
+        match instruction {
+            Instruction::Op(opcode, operands) => {
+                for operand in operands {
+                    match operand {
+                        Operand::Label(label) => {
+                            targets.insert(*label);
+                        }
+                        Operand::Symbol(_) if *opcode == Opcode::Goto || opcode.negated().is_some() => return None,
+                        _ => {}
+                    }
+                }
+            }
+            Instruction::LookupSwitch { arms, default } => {
+                targets.extend(arms.iter().map(|(_, label)| *label).chain([*default]));
+            }
+            Instruction::Catch { handler, .. } => {
+                targets.insert(*handler);
+            }
+            Instruction::Raw(text) => {
+                let first = text.split_whitespace().next().unwrap_or_default();
+                if first.ends_with(':') || first.starts_with("if") || first.starts_with("goto") || first.ends_with("switch") {
+                    return None;
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(targets)
+}
+
+/// Parses the Jasmin of an intrinsic into instructions
+pub fn parse_jasmin(code: &str) -> Vec<Instruction> {
+    code.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let tokens = line.split_whitespace().collect::<Vec<_>>();
+            if tokens[0] == opcodes::LDC {
+                let literal = line[tokens[0].len()..].trim_start();
+                if let Some(s) = literal.strip_prefix('"').and_then(string_literal) {
+                    return Instruction::Op(Opcode::Ldc, vec![Operand::String(s)]);
+                }
+            }
+            // comments are left to the assembler
+            let opcode = Opcode::from_mnemonic(tokens[0])
+                .filter(|_| !tokens.iter().any(|t| t.starts_with(['"', ';'])));
+            let Some(opcode) = opcode else {
+                return Instruction::Raw(line.into());
+            };
+            let operands = tokens[1..]
+                .iter()
+                .map(|t| match t.parse() {
+                    Ok(index) if opcode.takes_local() => Operand::Local(index),
+                    _ => t.parse().map_or_else(|_| Operand::Symbol(t.to_string()), Operand::Int),
+                })
+                .collect();
+            Instruction::Op(opcode, operands)
+        })
+        .collect()
+}
+
+/// The rest of a string literal after its opening `"`, unescaped. Nothing but a comment may
+/// follow the closing `"`.
+fn string_literal(rest: &str) -> Option<String> {
+    let mut chars = rest.chars().peekable();
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => break,
+            '\\' => s.push(unescape(&mut chars)?),
+            c => s.push(c),
+        }
+    }
+    let after = chars.collect::<String>();
+    let after = after.trim_start();
+    (after.is_empty() || after.starts_with(';')).then_some(s)
+}
+
+/// Unescapes the character after a `\`. Supports Rust's debug escapes (which string operands are
+/// printed with) as well as Jasmin's `\uXXXX`.
+pub fn unescape(chars: &mut Peekable<Chars>) -> Option<char> {
+    Some(match chars.next()? {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        'u' => {
+            let hex = if chars.next_if_eq(&'{').is_some() {
+                chars.by_ref().take_while(|c| *c != '}').collect::<String>()
+            } else {
+                chars.by_ref().take(4).collect::<String>()
+            };
+            u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)?
+        }
+        c => c,
+    })
+}
+
+/// A method and its code
+#[derive(Debug, Clone)]
+pub struct Method {
+    pub name: String,
+    pub descriptor: String,
+    /// Instance methods get `this` as local variable 0
+    pub is_static: bool,
+    pub max_stack: usize,
+    pub max_locals: usize,
+    pub code: Vec<Instruction>,
+}
+
+impl Method {
+    /// Removes the code that never runs: what follows a jump, return or `athrow` up to the next
+    /// label that is jumped to. The verifier checks dead code, too, against frames that
+    /// `break`, `continue` and `throw` leave meaningless. Intrinsics that jump by offset or to
+    /// labels of their own are left alone.
+    pub fn remove_dead_code(&mut self) {
+        while let Some(targets) = jump_targets(&self.code) {
+            let before = self.code.len();
+            let mut live = true;
+            self.code.retain(|instruction| {
+                match instruction {
+                    Instruction::Label(label) => live |= targets.contains(label),
+                    Instruction::Frame { .. } => return live,
+                    Instruction::Line(_) | Instruction::Catch { .. } => {}
+                    _ if !live => return false,
+                    _ => live = !instruction.ends_flow(),
+                }
+                true
+            });
+            if self.code.len() == before {
+                break;
+            }
+        }
+    }
+
+    pub fn new(name: String, descriptor: String) -> Self {
+        Self {
+            name,
+            descriptor,
+            is_static: true,
+            max_stack: 0,
+            max_locals: 0,
+            code: Vec::new(),
+        }
+    }
+
+    pub fn instance(name: String, descriptor: String) -> Self {
+        Self {
+            is_static: false,
+            ..Self::new(name, descriptor)
+        }
+    }
+}
+
+/// A public instance field
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    pub descriptor: String,
+}
+
+/// A public class, as far as [`classfile::assemble`] and the Jasmin printer need to know it
+#[derive(Debug, Clone)]
+pub struct Class<'a> {
+    /// The name of the source file, without a path
+    pub source: &'a str,
+    pub name: &'a str,
+    pub extends: &'a str,
+    pub fields: &'a [Field],
+    pub methods: Vec<&'a Method>,
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.name, self.id)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Int(n) => write!(f, "{n}"),
+            Self::Long(n) => write!(f, "{n}"),
+            // always with a `.` or an exponent, which tells it apart from a long
+            Self::Double(d) => write!(f, "{d:?}"),
+            Self::String(s) => write!(f, "{s:?}"),
+            Self::Local(index) => write!(f, "{index}"),
+            Self::Label(label) => write!(f, "{label}"),
+            Self::Symbol(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Op(opcode, operands) => {
+                write!(f, "{}", opcode.mnemonic())?;
+                for operand in operands {
+                    write!(f, " {operand}")?;
+                }
+                Ok(())
+            }
+            Self::LookupSwitch { arms, default } => {
+                writeln!(f, "{}", opcodes::LOOKUP_SWITCH)?;
+                for (key, label) in arms {
+                    writeln!(f, "    {key} : {label}")?;
+                }
+                write!(f, "    {} : {default}", opcodes::DEFAULT)
+            }
+            Self::Label(label) => write!(f, "{label}:"),
+            Self::Frame { locals, stack } => {
+                writeln!(f, "{}", opcodes::DIR_STACK)?;
+                for ty in locals {
+                    writeln!(f, "    {} {ty}", opcodes::STACK_LOCALS)?;
+                }
+                for ty in stack {
+                    writeln!(f, "    {} {ty}", opcodes::STACK_STACK)?;
+                }
+                write!(f, "{}", opcodes::DIR_END_STACK)
+            }
+            Self::Line(line) => write!(f, "{} {line}", opcodes::DIR_LINE),
+            Self::Catch { class, from, to, handler } => {
+                write!(f, "{} {class} from {from} to {to} using {handler}", opcodes::DIR_CATCH)
+            }
+            Self::Raw(text) => write!(f, "{text}"),
+        }
+    }
+}
+
+/// Prints the method as Jasmin
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = if self.is_static { "public static" } else { "public" };
+        writeln!(f, ".method {access} {}{}", self.name, self.descriptor)?;
+        writeln!(f, "{} {}", opcodes::DIR_STACK_LIMIT, self.max_stack)?;
+        writeln!(f, "{} {}", opcodes::DIR_LOCALS_LIMIT, self.max_locals)?;
+        for instruction in &self.code {
+            match instruction {
+                Instruction::Label(_)
+                | Instruction::Frame { .. }
+                | Instruction::Line(_)
+                | Instruction::Catch { .. } => {
+                    writeln!(f, "{instruction}")?
+                }
+                _ => writeln!(f, "    {instruction}")?,
+            }
+        }
+        write!(f, ".end method")
+    }
+}
+
+/// Prints the class as Jasmin
+impl fmt::Display for Class<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, ".bytecode {}.{}", classfile::MAJOR_VERSION, classfile::MINOR_VERSION)?;
+        writeln!(f, ".source {}", self.source)?;
+        writeln!(f, ".class public {}", self.name)?;
+        writeln!(f, ".super {}", self.extends)?;
+        for field in self.fields {
+            writeln!(f, "{} public {} {}", opcodes::DIR_FIELD, field.name, field.descriptor)?;
+        }
+        for method in &self.methods {
+            write!(f, "\n{method}\n")?;
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,15 @@
 pub mod analyzer;
 pub mod ast;
+pub mod classfile;
+pub mod classpath;
 pub mod codegen;
+pub mod diagnostics;
+pub mod interp;
+pub mod ir;
 pub mod opcodes;
+pub mod optimizer;
 pub mod parser;
+pub mod peephole;
+pub mod repl;
 pub mod macr;
 mod macros;
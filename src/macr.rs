@@ -1,113 +1,598 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    iter::Peekable,
+    path::{Path, PathBuf},
+};
 
 pub use klex::RichToken;
 use klex::{KlexError, Lexer, Loc, Token};
+use thiserror::Error;
+
+use crate::diagnostics::{Diagnostic, Note, Span};
 
 pub const KW_MACRO: &str = "macro";
 pub const KW_INCLUDE: &str = "#include";
+pub const KW_OUTER: &str = "outer";
+
+#[derive(Error, Debug)]
+pub enum MacroErr {
+    /// The span is that of the `#include`, if the file is an included one
+    #[error("cannot read `{0}`: {1}")]
+    Io(PathBuf, std::io::Error, Option<Span>),
+    #[error("expected a file name after `{KW_INCLUDE}`")]
+    MissingIncludeName(Span),
+    #[error("cannot find included file `{0}`")]
+    IncludeNotFound(String, Vec<PathBuf>, Span),
+    #[error("include cycle: {0}")]
+    IncludeCycle(String, Span),
+    #[error("error while lexing: {0}")]
+    LexerErr(KlexError, Span),
+    #[error("expected key token after `{KW_MACRO}`-keyword")]
+    MissingKey(Span),
+    #[error("expected `(` after `{KW_OUTER}` in definition of macro `{0}`")]
+    MissingOuterParen(String, Span),
+    #[error("expected parameter name in definition of macro `{0}`, found {1:?}")]
+    IllegalParam(String, Token, Span),
+    #[error("hit EOF in parameter list of macro `{0}`")]
+    EOFInParams(String, Span),
+    #[error("macro `{0}` takes arguments but isn't followed by `(`")]
+    MissingArgs(String, Span),
+    #[error("hit EOF in arguments of macro `{0}`")]
+    EOFInArgs(String, Span),
+    #[error("macro `{0}` expects {1} argument(s) but got {2}")]
+    ArgCount(String, usize, usize, Span),
+    #[error("hit macro expansion depth limit!")]
+    DepthLimit(Span),
+}
+
+impl MacroErr {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::Io(_, _, span) => *span,
+            Self::MissingIncludeName(span)
+            | Self::IncludeNotFound(_, _, span)
+            | Self::IncludeCycle(_, span)
+            | Self::LexerErr(_, span)
+            | Self::MissingKey(span)
+            | Self::MissingOuterParen(_, span)
+            | Self::IllegalParam(_, _, span)
+            | Self::EOFInParams(_, span)
+            | Self::MissingArgs(_, span)
+            | Self::EOFInArgs(_, span)
+            | Self::ArgCount(_, _, _, span)
+            | Self::DepthLimit(span) => Some(*span),
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        let mut diagnostic = Diagnostic::error(self.to_string(), self.span());
+        if let Self::IncludeNotFound(_, include_dirs, _) = self {
+            diagnostic.notes.push(Note::new(
+                format!("searched next to the including file and in {include_dirs:?}"),
+                None,
+            ));
+        }
+        diagnostic
+    }
+}
+
+/// A source file that took part in the compilation; its index in [`Preprocessor::files`] is the
+/// `file_index` of the locations of its tokens.
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub src: String,
+    /// The row of the `#include` in the processed source that brought this file in, directly or
+    /// through other included files; `None` for the processed source itself
+    pub include_row: Option<usize>,
+}
+
+/// Resolves `#include`s, splicing in the tokens of every file at most once
+#[derive(Clone, Debug)]
+struct Includes<'a> {
+    include_dirs: &'a [PathBuf],
+    /// All files read so far, indexed by their `file_index`
+    files: Vec<SourceFile>,
+    /// Canonical paths of all files that have been included so far
+    included: HashSet<PathBuf>,
+    /// The files currently being expanded, innermost last; used to detect include cycles
+    chain: Vec<PathBuf>,
+}
 
 #[derive(Clone, Debug)]
 pub struct Macro {
     args: Vec<String>,
+    /// Variables stored to in the body that get a fresh name per expansion
+    locals: Vec<String>,
     body: Vec<RichToken>,
 }
 
-pub fn lex_and_macronize(
-    src: String,
-    file_index: usize,
-    debug: bool,
-) -> Result<(Vec<RichToken>, HashMap<Token, Macro>, String), String> {
-    // TODO: this seems very inefficient
-    let mut expanded_src = String::new();
-    for line in src.lines() {
-        if line.starts_with(KW_INCLUDE) {
-            if let Some((_, file_name)) = line.split_once(" ") {
-                expanded_src
-                    .push_str(&std::fs::read_to_string(file_name).expect("cannot read file"));
+/// Keeps the macros and the included files between sources, like the lines of the REPL
+#[derive(Clone, Debug)]
+pub struct Preprocessor<'a> {
+    includes: Includes<'a>,
+    pub macros: HashMap<Token, Macro>,
+    /// Numbers the expansions, so that the local variables of macros get unique names
+    expansion_count: usize,
+}
+
+impl<'a> Preprocessor<'a> {
+    pub fn new(include_dirs: &'a [PathBuf]) -> Self {
+        Self {
+            includes: Includes::new(include_dirs),
+            macros: HashMap::new(),
+            expansion_count: 0,
+        }
+    }
+
+    /// All files read so far, indexed by their `file_index`
+    pub fn files(&self) -> &[SourceFile] {
+        &self.includes.files
+    }
+
+    pub fn into_files(self) -> Vec<SourceFile> {
+        self.includes.files
+    }
+
+    /// Like [`Preprocessor::process`], but reads the source from the file at `path`
+    pub fn process_file(&mut self, path: &Path, debug: bool) -> Result<Vec<RichToken>, MacroErr> {
+        let path = path
+            .canonicalize()
+            .map_err(|e| MacroErr::Io(path.to_path_buf(), e, None))?;
+        let src = std::fs::read_to_string(&path).map_err(|e| MacroErr::Io(path.clone(), e, None))?;
+        self.process(path, src, debug)
+    }
+
+    /// Lexes `src`, which is read from `path`, resolves its includes, collects its macro
+    /// definitions and expands all macros
+    pub fn process(&mut self, path: PathBuf, src: String, debug: bool) -> Result<Vec<RichToken>, MacroErr> {
+        let tokens = self.includes.expand_src(path, src, None)?;
+        let mut token_iter = tokens.into_iter().peekable();
+        let mut tokens_after_macro_parse = Vec::new();
+        while let Some(t0) = token_iter.next() {
+            if matches!(&t0.inner, Token::Sym(kw) if kw == KW_MACRO) {
+                let Some(key) = token_iter.next() else {
+                    return Err(MacroErr::MissingKey(Span::from(&t0)));
+                };
+                let mut args = Vec::new();
+                if matches!(token_iter.peek().map(|t| &t.inner), Some(Token::LParen)) {
+                    token_iter.next();
+                    args = parse_macro_params(&mut token_iter, &key)?;
+                }
+                // variables listed in `outer(...)` deliberately bind to the caller's scope
+                let mut outer = Vec::new();
+                if let Some(t) = token_iter.next_if(|t| matches!(&t.inner, Token::Sym(s) if s == KW_OUTER)) {
+                    if !matches!(token_iter.next().map(|t| t.inner), Some(Token::LParen)) {
+                        return Err(MacroErr::MissingOuterParen(key.inner.spelling(), Span::from(&t)));
+                    }
+                    outer = parse_macro_params(&mut token_iter, &key)?;
+                }
+                let mut body = Vec::new();
+                while let Some(t) = token_iter.next() {
+                    if matches!(t.inner, Token::SemiSemi) {
+                        break;
+                    } else {
+                        body.push(t.clone());
+                    }
+                }
+                self.macros.insert(key.inner, Macro::new(args, outer, body));
+            } else {
+                tokens_after_macro_parse.push(t0);
             }
-        } else {
-            expanded_src.push_str(line);
-            expanded_src.push('\n');
-        }
-    }
-    let src_to_return = expanded_src.clone();
-
-    let tokens = Lexer::new(&expanded_src, file_index)
-        .lex()
-        .map_err(|e| e.to_string())?;
-    let mut token_iter = tokens.into_iter();
-    let mut macros = HashMap::new();
-    let mut tokens_after_macro_parse = Vec::new();
-    while let Some(t0) = token_iter.next() {
-        if matches!(&t0.inner, Token::Sym(kw) if kw == KW_MACRO) {
-            let Some(key) = token_iter.next().map(|rt| rt.inner) else {
-                return Err(format!("expected key token after `{KW_MACRO}`-keyword at {}", t0.loc));
-            };
-            // TODO: parse args
-            let mut body = Vec::new();
-            while let Some(t) = token_iter.next() {
-                if matches!(t.inner, Token::SemiSemi) {
-                    break;
+        }
+        let mut mod_count = 1;
+        let mut depth = 0;
+        while mod_count != 0 {
+            depth += 1;
+            mod_count = 0;
+            let mut expanded = Vec::with_capacity(tokens_after_macro_parse.len());
+            let mut token_iter = tokens_after_macro_parse.into_iter().peekable();
+            while let Some(rt) = token_iter.next() {
+                if let Some(m) = self.macros.get(&rt.inner) {
+                    if depth > 500 {
+                        return Err(MacroErr::DepthLimit(Span::from(&rt)));
+                    }
+                    mod_count += 1;
+                    let args = if m.args.is_empty() {
+                        Vec::new()
+                    } else {
+                        parse_macro_args(&mut token_iter, &rt)?
+                    };
+                    self.expansion_count += 1;
+                    expanded.extend(m.invoke(args, &rt, self.expansion_count)?);
                 } else {
-                    body.push(t.clone());
+                    expanded.push(rt);
                 }
             }
-            macros.insert(key, Macro::new(Vec::new(), body));
-        } else {
-            tokens_after_macro_parse.push(t0);
+            tokens_after_macro_parse = expanded;
+        }
+        if debug {
+            println!("// DEBUG INFO: macro expansion depth = {depth}");
         }
+        Ok(tokens_after_macro_parse)
     }
-    let mut mod_count = 1;
-    let mut depth = 0;
-    while mod_count != 0 {
-        if depth > 500 {
-            return Err("hit macro expansion depth limit!".into());
+}
+
+impl<'a> Includes<'a> {
+    fn new(include_dirs: &'a [PathBuf]) -> Self {
+        Self {
+            include_dirs,
+            files: Vec::new(),
+            included: HashSet::new(),
+            chain: Vec::new(),
         }
-        depth += 1;
-        mod_count = 0;
-        tokens_after_macro_parse = tokens_after_macro_parse
+    }
+
+    /// Lexes the file at the canonical `path` and recursively splices in the tokens of its
+    /// includes. Every file gets its own `file_index`, so locations point to the right file.
+    fn expand(&mut self, path: PathBuf, include: Span, include_row: usize) -> Result<Vec<RichToken>, MacroErr> {
+        let src = std::fs::read_to_string(&path).map_err(|e| MacroErr::Io(path.clone(), e, Some(include)))?;
+        self.expand_src(path, src, Some(include_row))
+    }
+
+    /// Like [`Includes::expand`], but for the already read source `src`
+    fn expand_src(
+        &mut self,
+        path: PathBuf,
+        src: String,
+        include_row: Option<usize>,
+    ) -> Result<Vec<RichToken>, MacroErr> {
+        let file_index = self.files.len();
+        self.files.push(SourceFile {
+            path: path.clone(),
+            src: src.clone(),
+            include_row,
+        });
+        self.included.insert(path.clone());
+        self.chain.push(path.clone());
+        // `#include` lines are blanked out so that the rows of all other tokens stay correct
+        let mut src_without_includes = String::new();
+        let mut includes = Vec::new();
+        for (i, line) in src.lines().enumerate() {
+            if line.starts_with(KW_INCLUDE) {
+                let span = Span::new(Loc { file_index, row: i + 1, col: 1 }, line.chars().count());
+                let Some((_, file_name)) = line.split_once(' ') else {
+                    return Err(MacroErr::MissingIncludeName(span));
+                };
+                let file_name = file_name.trim().trim_matches('"');
+                let Some(included) = self.resolve(file_name, &path) else {
+                    return Err(MacroErr::IncludeNotFound(file_name.into(), self.include_dirs.to_vec(), span));
+                };
+                if let Some(start) = self.chain.iter().position(|p| *p == included) {
+                    let cycle = self.chain[start..]
+                        .iter()
+                        .chain([&included])
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(" -> ");
+                    return Err(MacroErr::IncludeCycle(cycle, span));
+                }
+                if !self.included.contains(&included) {
+                    let row = include_row.unwrap_or(i + 1);
+                    includes.push((i + 1, self.expand(included, span, row)?));
+                }
+            } else {
+                src_without_includes.push_str(line);
+            }
+            src_without_includes.push('\n');
+        }
+        self.chain.pop();
+
+        // klex's error only says where lexing failed in its message, the span names the file
+        let tokens = Lexer::new(&src_without_includes, file_index)
+            .lex()
+            .map_err(|e| MacroErr::LexerErr(e, Loc::start_of_file(file_index).into()))?;
+        let mut includes = includes.into_iter().peekable();
+        let mut spliced = Vec::with_capacity(tokens.len());
+        for t in tokens {
+            while let Some((_, included)) = includes.next_if(|(row, _)| *row < t.loc.row) {
+                spliced.extend(included);
+            }
+            spliced.push(t);
+        }
+        spliced.extend(includes.flat_map(|(_, included)| included));
+        Ok(spliced)
+    }
+
+    /// Looks for `file_name` next to the including file first, then in the include directories
+    fn resolve(&self, file_name: &str, including_file: &Path) -> Option<PathBuf> {
+        including_file
+            .parent()
             .into_iter()
-            .flat_map(|rt| {
-                if let Some(m) = macros.get(&rt.inner) {
-                    mod_count += 1;
-                    // TODO: args
-                    m.invoke(Vec::new(), rt.loc).expect("macro invoke error")
-                } else {
-                    vec![rt]
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(file_name))
+            .find(|candidate| candidate.is_file())
+            .and_then(|found| found.canonicalize().ok())
+    }
+}
+
+/// Parses the parameter names of a macro definition after the opening `(`:
+/// `macro name(a, b) ... ;;`
+fn parse_macro_params(
+    token_iter: &mut impl Iterator<Item = RichToken>,
+    key: &RichToken,
+) -> Result<Vec<String>, MacroErr> {
+    let mut params = Vec::new();
+    loop {
+        match token_iter.next() {
+            Some(RichToken {
+                inner: Token::Sym(name),
+                ..
+            }) => params.push(name),
+            Some(RichToken {
+                inner: Token::Comma,
+                ..
+            }) => {}
+            Some(RichToken {
+                inner: Token::RParen,
+                ..
+            }) => return Ok(params),
+            Some(t) => {
+                return Err(MacroErr::IllegalParam(key.inner.spelling(), t.inner.clone(), Span::from(&t)))
+            }
+            None => return Err(MacroErr::EOFInParams(key.inner.spelling(), Span::from(key))),
+        }
+    }
+}
+
+/// Collects the arguments of a macro invocation: `name(tok tok, tok)`.
+/// Commas inside of nested brackets don't separate arguments.
+fn parse_macro_args(
+    token_iter: &mut Peekable<impl Iterator<Item = RichToken>>,
+    invocation: &RichToken,
+) -> Result<Vec<Vec<RichToken>>, MacroErr> {
+    if !matches!(token_iter.peek().map(|t| &t.inner), Some(Token::LParen)) {
+        return Err(MacroErr::MissingArgs(invocation.inner.spelling(), Span::from(invocation)));
+    }
+    token_iter.next();
+    let mut args = Vec::new();
+    let mut curr_arg = Vec::new();
+    let mut depth = 0;
+    loop {
+        let Some(t) = token_iter.next() else {
+            return Err(MacroErr::EOFInArgs(invocation.inner.spelling(), Span::from(invocation)));
+        };
+        match t.inner {
+            Token::RParen if depth == 0 => {
+                if !curr_arg.is_empty() || !args.is_empty() {
+                    args.push(curr_arg);
                 }
-            })
-            .collect();
+                return Ok(args);
+            }
+            Token::Comma if depth == 0 => {
+                args.push(std::mem::take(&mut curr_arg));
+                continue;
+            }
+            Token::LParen | Token::LBrack | Token::LBrace => depth += 1,
+            Token::RParen | Token::RBrack | Token::RBrace => depth -= 1,
+            _ => {}
+        }
+        curr_arg.push(t);
     }
-    if debug {
-        println!("// DEBUG INFO: macro expansion depth = {depth}");
+}
+
+/// Names of the variables stored to in `body`, either via `name = ...` or `... := name`
+fn stored_names(body: &[RichToken]) -> Vec<String> {
+    let mut names = Vec::new();
+    for (i, pair) in body.windows(2).enumerate() {
+        if is_field_name(body, i) {
+            continue;
+        }
+        if let [RichToken {
+            inner: Token::Sym(name),
+            ..
+        }, RichToken {
+            inner: Token::Equal,
+            ..
+        }] = pair
+        {
+            names.push(name.clone());
+        }
     }
-    Ok((tokens_after_macro_parse, macros, src_to_return))
+    for triple in body.windows(3) {
+        if let [RichToken {
+            inner: Token::Colon,
+            ..
+        }, RichToken {
+            inner: Token::Equal,
+            ..
+        }, RichToken {
+            inner: Token::Sym(name),
+            ..
+        }] = triple
+        {
+            names.push(name.clone());
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Whether the symbol at `i` names a record field, like in `.name` or `name: type`, rather than a
+/// variable
+fn is_field_name(body: &[RichToken], i: usize) -> bool {
+    let at = |i: usize| body.get(i).map(|t| &t.inner);
+    i.checked_sub(1).and_then(at) == Some(&Token::Dot)
+        || at(i + 1) == Some(&Token::Colon) && at(i + 2) != Some(&Token::Equal)
 }
 
 impl Macro {
-    pub fn new(args: Vec<String>, body: Vec<RichToken>) -> Self {
-        Self { args, body }
+    pub fn new(args: Vec<String>, outer: Vec<String>, body: Vec<RichToken>) -> Self {
+        let locals = stored_names(&body)
+            .into_iter()
+            .filter(|name| !args.contains(name) && !outer.contains(name))
+            .collect();
+        Self { args, locals, body }
     }
 
+    /// `expansion_id` has to be unique per expansion, it is used to rename the macro's local
+    /// variables so that they don't clash with the caller's. The expanded tokens point to the
+    /// `invocation`, so errors in them underline the macro's name.
     // might be ineffective
-    pub fn invoke(&self, args: Vec<Vec<RichToken>>, loc: Loc) -> Result<Vec<RichToken>, ()> {
+    pub fn invoke(&self, args: Vec<Vec<RichToken>>, invocation: &RichToken, expansion_id: usize) -> Result<Vec<RichToken>, MacroErr> {
+        let (loc, len) = (invocation.loc, invocation.len);
         if args.len() != self.args.len() {
-            return Err(());
+            return Err(MacroErr::ArgCount(
+                invocation.inner.spelling(),
+                self.args.len(),
+                args.len(),
+                Span::from(invocation),
+            ));
         }
         Ok(self
             .body
             .iter()
-            .flat_map(|t| {
+            .enumerate()
+            .flat_map(|(i, t)| {
                 if let Token::Sym(s) = &t.inner {
                     if let Some(i) = self.args.iter().position(|arg| arg == s) {
                         args[i].clone()
+                    } else if self.locals.contains(s) && !is_field_name(&self.body, i) {
+                        vec![RichToken::new(Token::Sym(format!("{s}~{expansion_id}")), loc, len)]
                     } else {
-                        vec![RichToken::new(t.inner.clone(), loc, t.len)]
+                        vec![RichToken::new(t.inner.clone(), loc, len)]
                     }
                 } else {
-                    vec![RichToken::new(t.inner.clone(), loc, t.len)]
+                    vec![RichToken::new(t.inner.clone(), loc, len)]
                 }
             })
             .collect())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    /// Expands the macros in `src`, which includes nothing
+    fn expand(src: &str) -> Vec<RichToken> {
+        Preprocessor::new(&[]).process("test.jack".into(), src.into(), false).unwrap()
+    }
+
+    /// The tokens of `src` after expansion, without their locations
+    fn tokens(src: &str) -> Vec<Token> {
+        expand(src).into_iter().map(|t| t.inner).collect()
+    }
+
+    fn expand_err(src: &str) -> MacroErr {
+        Preprocessor::new(&[]).process("test.jack".into(), src.into(), false).unwrap_err()
+    }
+
+    fn analyze(src: &str) {
+        let mut ast = parse(expand(src).into_iter().map(Ok), 0).unwrap();
+        ast.analyze(false).unwrap();
+    }
+
+    #[test]
+    fn locals_are_renamed() {
+        let tokens = expand("macro five x = 5 x ;; five := y");
+        let syms = tokens
+            .iter()
+            .filter_map(|t| match &t.inner {
+                Token::Sym(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(syms.len(), 3);
+        assert!(syms[0].starts_with("x~") && syms[0] == syms[1], "{syms:?}");
+        assert_eq!(syms[2], "y");
+    }
+
+    #[test]
+    fn locals_named_like_fields() {
+        analyze("record P { x: int } macro getx x = 5 x P .x ;; getx := y");
+        // `.x = ...` stores to a field, the `x` it stores is the caller's variable
+        analyze("record P { x: int } macro setx .x = x ;; x = 1 p = { 2 P } p setx");
+        analyze("record P { x: int } macro setx := .x ;; x = 1 p = { 2 P } p x setx");
+    }
+
+    #[test]
+    fn included_files_know_their_include() {
+        let include_dirs = [PathBuf::from(env!("CARGO_MANIFEST_DIR"))];
+        let mut preprocessor = Preprocessor::new(&include_dirs);
+        preprocessor.process("test.jack".into(), "\n#include std.jack\n".into(), false).unwrap();
+        let rows = preprocessor.files().iter().map(|f| f.include_row).collect::<Vec<_>>();
+        assert_eq!(rows, [None, Some(2)]);
+    }
+
+    #[test]
+    fn arguments_are_substituted() {
+        let defs = "macro twice(x) x x ;; macro add(a, b) a b + ;;";
+        assert_eq!(tokens(&format!("{defs} add(40, twice(1) +)")), tokens("40 1 1 + +"));
+        // commas inside brackets don't separate arguments
+        assert_eq!(tokens(&format!("{defs} twice([1, 2])")), tokens("[1, 2] [1, 2]"));
+    }
+
+    #[test]
+    fn arguments_have_to_match_the_parameters() {
+        let defs = "macro add(a, b) a b + ;;";
+        assert!(matches!(expand_err(&format!("{defs} add()")), MacroErr::ArgCount(_, 2, 0, _)));
+        assert!(matches!(expand_err(&format!("{defs} add(1)")), MacroErr::ArgCount(_, 2, 1, _)));
+        assert!(matches!(expand_err(&format!("{defs} add(1, 2, 3)")), MacroErr::ArgCount(_, 2, 3, _)));
+        assert!(matches!(expand_err(&format!("{defs} add 1 2")), MacroErr::MissingArgs(..)));
+        assert!(matches!(expand_err(&format!("{defs} add(1, 2")), MacroErr::EOFInArgs(..)));
+        assert!(matches!(expand_err("macro add(a, 1) a ;;"), MacroErr::IllegalParam(..)));
+        assert!(matches!(expand_err("macro add(a, b"), MacroErr::EOFInParams(..)));
+    }
+
+    /// Writes the `files` into a fresh directory and returns its path
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jack-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (path, src) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, src).unwrap();
+        }
+        dir
+    }
+
+    fn process_file(dir: &Path, include_dirs: &[PathBuf]) -> Result<Vec<Token>, MacroErr> {
+        let tokens = Preprocessor::new(include_dirs).process_file(&dir.join("main.jack"), false)?;
+        Ok(tokens.into_iter().map(|t| t.inner).collect())
+    }
+
+    #[test]
+    fn includes_are_found_next_to_the_including_file_first() {
+        let dir = write_files(
+            "include-dirs",
+            &[
+                ("main.jack", "#include sub/a.jack\n#include c.jack\n1"),
+                ("sub/a.jack", "#include b.jack\n2"),
+                ("sub/b.jack", "3"),
+                ("lib/b.jack", "4"),
+                ("lib/c.jack", "5"),
+            ],
+        );
+        assert_eq!(process_file(&dir, &[dir.join("lib")]).unwrap(), tokens("3 2 5 1"));
+        assert!(matches!(
+            process_file(&dir, &[]),
+            Err(MacroErr::IncludeNotFound(name, _, _)) if name == "c.jack"
+        ));
+    }
+
+    #[test]
+    fn files_are_included_once() {
+        let dir = write_files(
+            "include-once",
+            &[
+                ("main.jack", "#include a.jack\n#include b.jack\n1"),
+                ("a.jack", "#include c.jack\n2"),
+                ("b.jack", "#include c.jack\n3"),
+                ("c.jack", "4"),
+            ],
+        );
+        assert_eq!(process_file(&dir, &[]).unwrap(), tokens("4 2 3 1"));
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let dir = write_files(
+            "include-cycle",
+            &[("main.jack", "#include a.jack"), ("a.jack", "#include b.jack"), ("b.jack", "#include a.jack")],
+        );
+        assert!(matches!(process_file(&dir, &[]), Err(MacroErr::IncludeCycle(..))));
+    }
+}
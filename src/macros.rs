@@ -1,46 +1,16 @@
-#[macro_export]
-macro_rules! class_template {
-    () => {
-        r#".source {source}
-.class public {name}
-.super {extends}
-
-{header}
-
-.method public <init>()V
-    aload_0
-    invokenonvirtual {extends}/<init>()V
-{init}
-    return
-.end method
-
-.method public static main([Ljava/lang/String;)V
-{main}
-    return
-.end method
-{footer}"#
-    };
-}
-
 #[macro_export]
 macro_rules! bool_bi_op {
     ($node:expr, $class:expr, $opcode_i:expr) => {{
-        $class.append_main(&line_directive!($node)).main_endl();
-        $class.append_main(opcodes::I_SUB).main_endl();
-        let true_label = format!("True{}{}", $opcode_i, $class.main.len());
-        let end_label = format!("End{}{}", $opcode_i, $class.main.len());
-        $class
-            .push_main($opcode_i)
-            .append_main(&true_label)
-            .main_endl();
-        $class.append_main(opcodes::ICONST_0).main_endl();
-        $class
-            .push_main(opcodes::GOTO)
-            .append_main(&end_label)
-            .main_endl();
-        $class.push_main(&true_label).append_main(":").main_endl();
-        $class.append_main(opcodes::ICONST_1).main_endl();
-        $class.push_main(&end_label).append_main(":").main_endl();
+        $class.line_directive($node.loc);
+        $class.op(Opcode::ISub);
+        let true_label = $class.new_label("True");
+        let end_label = $class.new_label("End");
+        $class.jump($opcode_i, true_label);
+        $class.op(Opcode::IConst0);
+        $class.jump(Opcode::Goto, end_label);
+        $class.place_label(true_label);
+        $class.op(Opcode::IConst1);
+        $class.place_label(end_label);
     }};
 }
 
@@ -54,6 +24,16 @@ macro_rules! expect_type_info {
     };
 }
 
+#[macro_export]
+macro_rules! expect_type_info_after {
+    ($node:expr) => {
+        $node
+            .stack_after
+            .as_ref()
+            .ok_or_else(|| CodeGenErr::NotAnalyzedErr($node.loc))?
+    };
+}
+
 #[macro_export]
 macro_rules! expect_var_info {
     ($node:expr) => {
@@ -65,70 +45,48 @@ macro_rules! expect_var_info {
 }
 
 #[macro_export]
-macro_rules! line_directive {
+macro_rules! expect_var_info_after {
     ($node:expr) => {
-        format!("{} {}", opcodes::DIR_LINE, $node.loc.row)
+        $node
+            .vars_after
+            .as_ref()
+            .ok_or_else(|| CodeGenErr::NotAnalyzedErr($node.loc))?
     };
 }
 
 #[macro_export]
 macro_rules! invoke {
     ($node:expr, $class:expr, $invoke_opcode:expr, $name:expr, $nargs:expr, $returns:expr) => {
-        $class
-            .push_main($invoke_opcode)
-            .append_main($name)
-            .append_main("(");
         let mut args = String::new();
         let mut stack = $node.stack.as_ref().unwrap().clone();
         for _ in 0..$nargs {
             args.push_str(&stack.pop().unwrap().ty.to_opcode());
         }
-        $class
-            .append_main(&args)
-            .append_main(")")
-            .append_main($returns)
-            .main_endl();
+        $class.invoke($invoke_opcode, format!("{}({}){}", $name, args, $returns));
     };
     (generic: $node:expr, $class:expr, $invoke_opcode:expr, $name:expr, $nargs:expr, $returns:expr) => {
-        $class
-            .push_main($invoke_opcode)
-            .append_main($name)
-            .append_main("(");
         let mut args = String::new();
         for _ in 0..$nargs {
             args.push_str(opcodes::TYPE_OBJECT);
         }
-        $class
-            .append_main(&args)
-            .append_main(")")
-            .append_main($returns)
-            .main_endl();
+        $class.invoke($invoke_opcode, format!("{}({}){}", $name, args, $returns));
     };
     ($node:expr, $class:expr, $invoke_opcode:expr, $name:expr, types: $args:expr, $returns: expr) => {
-        $class
-            .push_main($invoke_opcode)
-            .append_main($name)
-            .append_main("(");
         let mut args = String::new();
         for arg in $args.iter() {
             args.push_str(arg);
         }
-        $class
-            .append_main(&args)
-            .append_main(")")
-            .append_main($returns)
-            .main_endl();
+        $class.invoke($invoke_opcode, format!("{}({}){}", $name, args, $returns));
     };
 }
 
 #[macro_export]
 macro_rules! get_static {
     ($class:expr, $obj:expr, $type:expr) => {
-        $class
-            .push_main(opcodes::GET_STATIC)
-            .push_main($obj)
-            .append_main($type)
-            .main_endl();
+        $class.emit(Instruction::Op(
+            Opcode::GetStatic,
+            vec![Operand::Symbol($obj.into()), Operand::Symbol($type.into())],
+        ));
     };
 }
 
@@ -138,7 +96,7 @@ macro_rules! int_to_integer {
         invoke!(
             $node,
             $class,
-            opcodes::INVOKE_STATIC,
+            Opcode::InvokeStatic,
             "java/lang/Integer/valueOf",
             types: [opcodes::TYPE_INT],
             opcodes::TYPE_INTEGER
@@ -146,14 +104,56 @@ macro_rules! int_to_integer {
     };
 }
 
+#[macro_export]
+macro_rules! bool_to_boolean {
+    ($node:expr, $class:expr) => {
+        invoke!(
+            $node,
+            $class,
+            Opcode::InvokeStatic,
+            "java/lang/Boolean/valueOf",
+            types: [opcodes::TYPE_BOOL],
+            opcodes::TYPE_BOOLEAN
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! long_to_boxed {
+    ($node:expr, $class:expr) => {
+        invoke!(
+            $node,
+            $class,
+            Opcode::InvokeStatic,
+            "java/lang/Long/valueOf",
+            types: [opcodes::TYPE_LONG],
+            opcodes::TYPE_BOXED_LONG
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! double_to_boxed {
+    ($node:expr, $class:expr) => {
+        invoke!(
+            $node,
+            $class,
+            Opcode::InvokeStatic,
+            "java/lang/Double/valueOf",
+            types: [opcodes::TYPE_DOUBLE],
+            opcodes::TYPE_BOXED_DOUBLE
+        )
+    };
+}
+
 #[macro_export]
 macro_rules! integer_to_int {
     ($node:expr, $class:expr) => {{
-        $class.push_main(opcodes::CHECK_CAST).append_main(opcodes::CLASS_INTEGER).main_endl();
+        $class.op_with(Opcode::CheckCast, Operand::Symbol(opcodes::CLASS_INTEGER.into()));
         invoke!(
             $node,
             $class,
-            opcodes::INVOKE_VIRTUAL,
+            Opcode::InvokeVirtual,
             "java/lang/Integer/intValue",
             0,
             opcodes::TYPE_INT
@@ -164,20 +164,14 @@ macro_rules! integer_to_int {
 #[macro_export]
 macro_rules! object_to_string {
     ($class:expr) => {{
-        $class
-            .push_main(opcodes::CHECK_CAST)
-            .append_main(opcodes::CLASS_STRING)
-            .main_endl();
+        $class.op_with(Opcode::CheckCast, Operand::Symbol(opcodes::CLASS_STRING.into()));
     }}
 }
 
 #[macro_export]
 macro_rules! object_to_list {
     ($class:expr) => {{
-        $class
-            .push_main(opcodes::CHECK_CAST)
-            .append_main(opcodes::CLASS_ARRAY_LIST)
-            .main_endl();
+        $class.op_with(Opcode::CheckCast, Operand::Symbol(opcodes::CLASS_ARRAY_LIST.into()));
     }}
 }
 
@@ -185,9 +179,9 @@ macro_rules! object_to_list {
 #[macro_export]
 macro_rules! over {
     ($node:expr, $class:expr) => {
-        $class.append_main(opcodes::DUP2).main_endl();
-        $class.append_main(opcodes::POP).main_endl();
-        $class.append_main(opcodes::SWAP).main_endl();
+        $class.op(Opcode::Dup2);
+        $class.op(Opcode::Pop);
+        $class.op(Opcode::Swap);
     };
 }
 
@@ -197,7 +191,7 @@ macro_rules! list_len {
         invoke!(
             $node,
             $class,
-            opcodes::INVOKE_VIRTUAL,
+            Opcode::InvokeVirtual,
             "java/util/ArrayList/size",
             0,
             opcodes::TYPE_INT
@@ -208,14 +202,14 @@ macro_rules! list_len {
 #[macro_export]
 macro_rules! swap {
     ($class:expr) => {
-        $class.append_main(opcodes::SWAP).main_endl();
+        $class.op(Opcode::Swap);
     };
 }
 
 #[macro_export]
 macro_rules! dup {
     ($class:expr) => {
-        $class.append_main(opcodes::DUP).main_endl();
+        $class.op(Opcode::Dup);
     };
 }
 
@@ -223,18 +217,31 @@ macro_rules! dup {
 #[macro_export]
 macro_rules! new_list {
     ($node:expr, $class:expr) => {{
-        $class
-            .push_main(opcodes::NEW)
-            .append_main(opcodes::CLASS_ARRAY_LIST)
-            .main_endl();
+        $class.op_with(Opcode::New, Operand::Symbol(opcodes::CLASS_ARRAY_LIST.into()));
         dup!($class);
         invoke!(
             $node,
             $class,
-            opcodes::INVOKE_SPECIAL,
+            Opcode::InvokeSpecial,
             "java/util/ArrayList/<init>",
             0,
             opcodes::TYPE_VOID
         );
     }}
 }
+
+#[macro_export]
+macro_rules! new_map {
+    ($node:expr, $class:expr) => {{
+        $class.op_with(Opcode::New, Operand::Symbol(opcodes::CLASS_HASH_MAP.into()));
+        dup!($class);
+        invoke!(
+            $node,
+            $class,
+            Opcode::InvokeSpecial,
+            "java/util/HashMap/<init>",
+            0,
+            opcodes::TYPE_VOID
+        );
+    }}
+}
@@ -1,29 +1,89 @@
 use std::error::Error;
 use std::str;
-use std::{path::PathBuf, process::Command};
+use std::{path::{Path, PathBuf}, process};
 
-use clap::Parser;
-use jack::analyzer::AnalyzerErr;
+use clap::{Parser, Subcommand};
+use jack::analyzer::{AnalyzerErr, AstAnalysis};
+use jack::ast::AstBase;
+use jack::classpath::{ClassPath, ClassPathErr};
 use jack::codegen::ClassWriter;
-use jack::macr::lex_and_macronize;
-use jack::parser::{parse, ParserErr};
-use klex::Loc;
+use jack::diagnostics::{Diagnostic, ErrorFormat};
+use jack::interp::Interpreter;
+use jack::macr::{Preprocessor, SourceFile};
+use jack::parser::parse;
+use jack::repl::Repl;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand, the file is compiled
+    #[command(flatten)]
+    args: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compile the file and run it with `java`
+    Run {
+        /// Execute the program in jack's interpreter instead, without the JVM
+        #[arg(long, action)]
+        interp: bool,
+
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Evaluate Jack interactively, showing the stack after every input
+    Repl {
+        /// Directories to search for `#include`d files
+        #[arg(short = 'I', long = "include")]
+        include_dirs: Vec<PathBuf>,
+
+        /// Print debug info?
+        #[arg(short, long, action)]
+        debug: bool,
+
+        /// How to print errors
+        #[arg(long, value_enum, default_value_t = ErrorFormat::Human)]
+        error_format: ErrorFormat,
+    },
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     /// The file to compile
     #[arg()]
     file: PathBuf,
 
-    /// The path to the Jasmin jar file
+    /// The path to the Jasmin jar file. If given, Jasmin assembles the class instead of jack
     #[arg(short, long)]
-    jasmin: PathBuf,
+    jasmin: Option<PathBuf>,
+
+    /// Additional directories to search for `#include`d files
+    #[arg(short = 'I', long = "include")]
+    include_dirs: Vec<PathBuf>,
+
+    /// Directories, `.jar` and `.jmod` files to check the Java classes, methods and fields the
+    /// program uses against, like a JDK's `jmods` directory
+    #[arg(long)]
+    classpath: Vec<PathBuf>,
 
     /// What file to compile to
     #[arg(short, long)]
     out: Option<PathBuf>,
 
+    /// Skip the peephole optimizations of the generated code?
+    #[arg(long, action)]
+    no_peephole: bool,
+
     /// Emit the post-macro final source?
     #[arg(short, action)]
     macro_emit: bool,
@@ -31,23 +91,61 @@ struct Args {
     /// Print debug info?
     #[arg(short, long, action)]
     debug: bool,
+
+    /// How to print errors: `human` shows the source they point to, `json` prints one JSON
+    /// object per line
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Human)]
+    error_format: ErrorFormat,
 }
 
 fn main() {
-    let args = Args::parse();
-    let out = args.out.unwrap_or_else(|| {
-        let mut path = args.file.clone();
-        path.set_extension("class");
-        path
-    });
-    let jasmin_file = {
-        let mut path = args.file.clone();
-        path.set_extension("j");
-        path
-    };
+    let cli = Cli::parse();
+    match cli.command {
+        None => {
+            // clap requires `file` when there is no subcommand
+            compile(&cli.args.expect("no file given"));
+        }
+        Some(Command::Run { interp: false, args }) => {
+            let out = compile(&args);
+            let dir = out.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            // java can't load classes from jmods, the JDK has them anyway
+            let class_path = [dir.to_path_buf()]
+                .into_iter()
+                .chain(args.classpath.iter().flat_map(std::env::split_paths))
+                .filter(|p| p.extension().map_or(true, |e| e != "jmod"));
+            let status = process::Command::new("java")
+                .arg("-cp")
+                .arg(std::env::join_paths(class_path).expect("invalid classpath"))
+                .arg(out.file_stem().unwrap())
+                .status()
+                .expect("error executing java!");
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Some(Command::Run { interp: true, args }) => {
+            let (ast, _, source_files) = analyze(&args);
+            match Interpreter::new().run(&ast) {
+                Ok(status) => std::process::exit(status),
+                Err(e) => {
+                    report(&e.diagnostic(), &source_files, args.error_format);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Command::Repl { include_dirs, debug, error_format }) => {
+            std::process::exit(Repl::new(&include_dirs, debug, error_format).run());
+        }
+    }
+}
 
-    let jack_source = std::fs::read_to_string(args.file.clone()).expect("err reading file");
-    let (tokens, _, jack_source) = lex_and_macronize(jack_source, 0, args.debug).expect("error lexing");
+/// Lexes, parses and analyzes the file, reporting errors
+fn analyze(args: &Args) -> (AstBase, AstAnalysis, Vec<SourceFile>) {
+    let format = args.error_format;
+    let mut preprocessor = Preprocessor::new(&args.include_dirs);
+    let tokens = preprocessor.process_file(&args.file, args.debug).unwrap_or_else(|e| {
+        report(&e.diagnostic(), preprocessor.files(), format);
+        process::exit(1);
+    });
+    let source_files = preprocessor.into_files();
     if args.macro_emit {
         let out = {
             let mut path = args.file.clone();
@@ -56,59 +154,111 @@ fn main() {
         };
         std::fs::write(out, tokens.iter().map(|rt| rt.inner.spelling()).collect::<String>()).expect("cannot write");
     }
+
+    let mut ast = parse(tokens.into_iter().map(|t| Ok(t)), 0).unwrap_or_else(|e| {
+        report(&e.diagnostic(), &source_files, format);
+        process::exit(1);
+    });
+    let analyzer = ast.analyze(args.debug).unwrap_or_else(|errs| {
+        report_analyzer_errs(&errs, &source_files, format);
+        process::exit(1);
+    });
+    for warning in analyzer.warnings.take() {
+        report(&warning, &source_files, format);
+    }
+    (ast, analyzer, source_files)
+}
+
+/// Compiles the file to a class file and returns its path
+fn compile(args: &Args) -> PathBuf {
+    let format = args.error_format;
+    let out = args.out.clone().unwrap_or_else(|| {
+        let mut path = args.file.clone();
+        path.set_extension("class");
+        path
+    });
+    let jasmin_file = {
+        let mut path = args.file.clone();
+        path.set_extension("j");
+        path
+    };
     let class_name = out.file_stem().unwrap().to_str().unwrap();
 
-    let mut ast = parse(tokens.into_iter().map(|t| Ok(t)), 0)
-        .map_err(|e| report_parser_err(e, &jack_source))
-        .expect("parsing err");
-    let analyzer = ast.analyze(args.debug)
-        .map_err(|e| report_analyzer_err(e, &jack_source))
-        .expect("analyzer err");
+    let (mut ast, analyzer, source_files) = analyze(args);
+    if !args.classpath.is_empty() {
+        let errs = ClassPath::load(&args.classpath)
+            .map(|class_path| class_path.check(&ast))
+            .unwrap_or_else(|e| vec![e]);
+        if !errs.is_empty() {
+            report_classpath_errs(&errs, &source_files, format);
+            process::exit(1);
+        }
+    }
+    ast.fold_constants();
+    ast.fuse_concats();
     let mut class = ClassWriter::new(
         args.file.file_name().unwrap().to_str().unwrap().into(),
         class_name.into(),
         "java/lang/Object".into(),
     );
-    ast.code_gen(&mut class, analyzer.max_stack_size, analyzer.max_vars_count + 1)
-        .map_err(|e| println!("{e}"))
-        .expect("code gen err");
-
-    std::fs::write(&jasmin_file, class.write()).expect("error writing assmbly!");
-    let jasmin_cmd_out = Command::new("java")
-        .arg("-jar")
-        .arg(args.jasmin)
-        .arg(jasmin_file)
-        .output()
-        .expect("error executing jasmin!");
-    println!("{}", str::from_utf8(&jasmin_cmd_out.stdout).unwrap());
-    println!("{}", str::from_utf8(&jasmin_cmd_out.stderr).unwrap());
-}
+    class.set_source_files(&source_files);
+    if let Err(e) = ast.code_gen(&mut class, analyzer.max_stack_size, analyzer.max_vars_count + 1) {
+        report(&Diagnostic::error(e.to_string(), None), &source_files, format);
+        process::exit(1);
+    }
+    if !args.no_peephole {
+        class.peephole();
+    }
 
-fn report_parser_err(e: ParserErr, src: &str) {
-    println!("{e}");
-    if let Some(loc) = e.loc() {
-        print_err_loc(loc, src);
+    write_class(args, &class, &jasmin_file, &out, &source_files);
+    // records are classes of their own, next to the main class
+    for record in class.records() {
+        let out = out.with_file_name(record.name()).with_extension("class");
+        let jasmin_file = jasmin_file.with_file_name(record.name()).with_extension("j");
+        write_class(args, record, &jasmin_file, &out, &source_files);
     }
+    out
 }
 
-fn report_analyzer_err(e: AnalyzerErr, src: &str) {
-    println!("{e}");
-    if let Some(loc) = e.loc() {
-        print_err_loc(loc, src);
+/// Assembles the class to `out`, with Jasmin if its path is given
+fn write_class(args: &Args, class: &ClassWriter, jasmin_file: &Path, out: &Path, source_files: &[SourceFile]) {
+    if let Some(jasmin) = &args.jasmin {
+        std::fs::write(jasmin_file, class.write()).expect("error writing assmbly!");
+        let jasmin_cmd_out = process::Command::new("java")
+            .arg("-jar")
+            .arg(jasmin)
+            .arg(jasmin_file)
+            .output()
+            .expect("error executing jasmin!");
+        println!("{}", str::from_utf8(&jasmin_cmd_out.stdout).unwrap());
+        println!("{}", str::from_utf8(&jasmin_cmd_out.stderr).unwrap());
+    } else {
+        let bytes = class.assemble().unwrap_or_else(|e| {
+            report(&Diagnostic::error(e.to_string(), None), source_files, args.error_format);
+            process::exit(1);
+        });
+        std::fs::write(out, bytes).expect("error writing class file!");
     }
 }
 
-fn print_err_loc(loc: Loc, src: &str) {
-    let line_above = if loc.row < 2 {
-        String::new()
-    } else {
-        src.lines().nth(loc.row - 2).map(String::from).unwrap_or_else(|| String::new())
-    };
-    if let Some(line) = src.lines().nth(loc.row - 1) {
-        println!("     | {line_above}");
-        println!("{: >4} | {line}", loc.row);
-        print!("{}", "-".repeat(loc.col + 6));
-        println!("^");
+fn report(d: &Diagnostic, files: &[SourceFile], format: ErrorFormat) {
+    println!("{}", d.render(files, format));
+}
+
+fn report_analyzer_errs(errs: &[AnalyzerErr], files: &[SourceFile], format: ErrorFormat) {
+    for e in errs {
+        report(&e.diagnostic(), files, format);
+    }
+    if format == ErrorFormat::Human {
+        println!("analysis failed with {} error(s)", errs.len());
     }
 }
 
+fn report_classpath_errs(errs: &[ClassPathErr], files: &[SourceFile], format: ErrorFormat) {
+    for e in errs {
+        report(&e.diagnostic(), files, format);
+    }
+    if format == ErrorFormat::Human {
+        println!("classpath check failed with {} error(s)", errs.len());
+    }
+}
@@ -9,17 +9,47 @@ pub const ICONST_5: &str = "iconst_5";
 pub const BIPUSH: &str = "bipush";
 pub const SIPUSH: &str = "sipush";
 pub const LDC: &str = "ldc";
+pub const DCONST_0: &str = "dconst_0";
+pub const DCONST_1: &str = "dconst_1";
+pub const LDC2_W: &str = "ldc2_w";
+pub const LCONST_0: &str = "lconst_0";
+pub const LCONST_1: &str = "lconst_1";
 
 pub const NEW: &str = "new";
 pub const POP: &str = "pop";
+pub const POP2: &str = "pop2";
 pub const DUP: &str = "dup";
 pub const DUPX1: &str = "dup_x1";
+pub const DUPX2: &str = "dup_x2";
 pub const DUP2: &str = "dup2";
+pub const DUP2X1: &str = "dup2_x1";
+pub const DUP2X2: &str = "dup2_x2";
 pub const SWAP: &str = "swap";
 pub const I_ADD: &str = "iadd";
 pub const I_SUB: &str = "isub";
 pub const I_MUL: &str = "imul";
 pub const I_DIV: &str = "idiv";
+pub const I_NEG: &str = "ineg";
+pub const I_USHR: &str = "iushr";
+pub const I_XOR: &str = "ixor";
+pub const I_AND: &str = "iand";
+pub const I_OR: &str = "ior";
+pub const D_ADD: &str = "dadd";
+pub const D_SUB: &str = "dsub";
+pub const D_MUL: &str = "dmul";
+pub const D_DIV: &str = "ddiv";
+pub const D_CMPL: &str = "dcmpl";
+pub const D_CMPG: &str = "dcmpg";
+pub const I2D: &str = "i2d";
+pub const D2I: &str = "d2i";
+pub const L_ADD: &str = "ladd";
+pub const L_SUB: &str = "lsub";
+pub const L_MUL: &str = "lmul";
+pub const L_DIV: &str = "ldiv";
+pub const L_REM: &str = "lrem";
+pub const L_CMP: &str = "lcmp";
+pub const I2L: &str = "i2l";
+pub const L2I: &str = "l2i";
 
 pub const IF_NE: &str = "ifne";
 pub const IF_EQ: &str = "ifeq";
@@ -27,6 +57,12 @@ pub const IF_LT: &str = "iflt";
 pub const IF_LE: &str = "ifle";
 pub const IF_GT: &str = "ifgt";
 pub const IF_GE: &str = "ifge";
+pub const IF_ICMP_EQ: &str = "if_icmpeq";
+pub const IF_ICMP_NE: &str = "if_icmpne";
+pub const IF_ICMP_LT: &str = "if_icmplt";
+pub const IF_ICMP_GE: &str = "if_icmpge";
+pub const IF_ICMP_GT: &str = "if_icmpgt";
+pub const IF_ICMP_LE: &str = "if_icmple";
 pub const GOTO: &str = "goto";
 
 pub const LOOKUP_SWITCH: &str = "lookupswitch";
@@ -38,16 +74,35 @@ pub const INVOKE_INTERFACE: &str = "invokeinterface";
 pub const INVOKE_SPECIAL: &str = "invokespecial";
 
 pub const I_STORE: &str = "istore";
+pub const D_STORE: &str = "dstore";
+pub const L_STORE: &str = "lstore";
 pub const A_STORE: &str = "astore";
 pub const I_LOAD: &str = "iload";
+pub const D_LOAD: &str = "dload";
+pub const L_LOAD: &str = "lload";
 pub const A_LOAD: &str = "aload";
 
+pub const RETURN: &str = "return";
+pub const I_RETURN: &str = "ireturn";
+pub const D_RETURN: &str = "dreturn";
+pub const L_RETURN: &str = "lreturn";
+pub const A_RETURN: &str = "areturn";
+
 pub const GET_STATIC: &str = "getstatic";
+pub const GET_FIELD: &str = "getfield";
+pub const PUT_FIELD: &str = "putfield";
 
 pub const DIR_STACK_LIMIT: &str = ".limit stack";
 pub const DIR_LOCALS_LIMIT: &str = ".limit locals";
 pub const DIR_SOURCE_FILE: &str = ".source";
+pub const DIR_FIELD: &str = ".field";
 pub const DIR_LINE: &str = ".line";
+pub const DIR_STACK: &str = ".stack";
+pub const DIR_END_STACK: &str = ".end stack";
+pub const DIR_CATCH: &str = ".catch";
+pub const STACK_LOCALS: &str = "locals";
+pub const STACK_STACK: &str = "stack";
+pub const VERIFICATION_TOP: &str = "Top";
 
 pub const TYPE_PRINT_STREAM: &str = "Ljava/io/PrintStream;";
 pub const TYPE_CONSOLE: &str = "Ljava/io/Console;";
@@ -55,15 +110,29 @@ pub const TYPE_INT: &str = "I";
 pub const TYPE_OBJECT: &str = "Ljava/lang/Object;";
 pub const TYPE_STRING: &str = "Ljava/lang/String;";
 pub const TYPE_INTEGER: &str = "Ljava/lang/Integer;";
+pub const TYPE_BOOLEAN: &str = "Ljava/lang/Boolean;";
+pub const TYPE_BOXED_DOUBLE: &str = "Ljava/lang/Double;";
+pub const TYPE_BOXED_LONG: &str = "Ljava/lang/Long;";
+pub const TYPE_ARRAY_LIST: &str = "Ljava/util/ArrayList;";
+pub const TYPE_HASH_MAP: &str = "Ljava/util/HashMap;";
 pub const TYPE_VOID: &str = "V";
 pub const TYPE_BOOL: &str = "Z";
+pub const TYPE_DOUBLE: &str = "D";
+pub const TYPE_LONG: &str = "J";
 
 pub const CHECK_CAST: &str = "checkcast";
+pub const ATHROW: &str = "athrow";
 
 pub const CLASS_OBJECT: &str = "java/lang/Object";
 pub const CLASS_STRING: &str = "java/lang/String";
+pub const CLASS_STRING_BUILDER: &str = "java/lang/StringBuilder";
 pub const CLASS_INTEGER: &str = "java/lang/Integer";
+pub const CLASS_BOOLEAN: &str = "java/lang/Boolean";
+pub const CLASS_BOXED_LONG: &str = "java/lang/Long";
+pub const CLASS_BOXED_DOUBLE: &str = "java/lang/Double";
 pub const CLASS_ARRAY_LIST: &str = "java/util/ArrayList";
+pub const CLASS_HASH_MAP: &str = "java/util/HashMap";
+pub const CLASS_RUNTIME_EXCEPTION: &str = "java/lang/RuntimeException";
 
 pub const OBJ_SYSTEM_OUT: &str = "java/lang/System/out";
 pub const OBJ_SYSTEM_CONSOLE: &str = "java/lang/System/console";
@@ -0,0 +1,471 @@
+//! Optimizations of the analyzed AST. They rely on what the analyzer found out about the stack,
+//! so they run between analysis and code generation.
+
+use crate::{
+    analyzer::Value,
+    ast::{AstBase, AstItem, AstNode},
+    interp::{java_char_at, java_index_of, java_len, java_substring_units, java_trim},
+    opcodes::CLASS_STRING_BUILDER,
+};
+
+/// Intrinsics that box, unbox or cast the value on top of the stack. The analyzer sees no
+/// difference, but the JVM does, so a boxed constant must not be replaced by a plain push.
+const CASTS: [&str; 10] = [
+    "_int_to_integer", "_integer_to_int", "_bool_to_boolean", "_boolean_to_bool",
+    "_long_to_boxed", "_boxed_to_long", "_double_to_boxed", "_boxed_to_double", "_obj_to_string",
+    "_obj_to_map",
+];
+
+/// The casts of [`CASTS`] that box their value
+const BOXING: [&str; 4] =
+    ["_int_to_integer", "_bool_to_boolean", "_long_to_boxed", "_double_to_boxed"];
+
+/// Intrinsics of the standard library without side effects, see [`fold_intrinsic`]
+const PURE: [&str; 31] = [
+    "+", "-", "*", "/", "==", "<", ">", "<=", ">=", "not", "and", "or", "@int", "@bool",
+    "@long", "@double", "@string", "dup", "swap", "dupx1", "drop", "concat", "strlen", "substr",
+    "charat", "trim", "upper", "lower", "contains", "startswith", "indexof",
+];
+
+impl AstBase {
+    /// Replaces pure computations on constants, like `40 2 +`, with pushes of their results
+    pub fn fold_constants(&mut self) {
+        fold_list(&mut self.nodes);
+    }
+
+    /// Builds the string of chained `concat`s, like `a b concat c concat`, in one
+    /// `StringBuilder` instead of copying the string so far for every `concat`
+    pub fn fuse_concats(&mut self) {
+        fuse_list(&mut self.nodes);
+    }
+}
+
+/// The results of the intrinsic of the standard library called `name` on constant `inputs`, if
+/// it is pure and its results can be computed at compile time
+pub(crate) fn fold_intrinsic(name: &str, inputs: &[Value]) -> Option<Vec<Value>> {
+    use Value::{Bool, Double, Int, Long, String};
+
+    Some(match (name, inputs) {
+        ("+", [Int(a), Int(b)]) => vec![Int(a.wrapping_add(*b))],
+        ("-", [Int(a), Int(b)]) => vec![Int(a.wrapping_sub(*b))],
+        ("*", [Int(a), Int(b)]) => vec![Int(a.wrapping_mul(*b))],
+        // division by zero throws at runtime
+        ("/", [Int(a), Int(b)]) if *b != 0 => vec![Int(a.wrapping_div(*b))],
+        ("+", [Long(a), Long(b)]) => vec![Long(a.wrapping_add(*b))],
+        ("-", [Long(a), Long(b)]) => vec![Long(a.wrapping_sub(*b))],
+        ("*", [Long(a), Long(b)]) => vec![Long(a.wrapping_mul(*b))],
+        ("/", [Long(a), Long(b)]) if *b != 0 => vec![Long(a.wrapping_div(*b))],
+        ("+", [Double(a), Double(b)]) => vec![Double(a + b)],
+        ("-", [Double(a), Double(b)]) => vec![Double(a - b)],
+        ("*", [Double(a), Double(b)]) => vec![Double(a * b)],
+        ("/", [Double(a), Double(b)]) => vec![Double(a / b)],
+        ("==", [Int(a), Int(b)]) => vec![Bool(a == b)],
+        ("==", [Long(a), Long(b)]) => vec![Bool(a == b)],
+        ("==", [Double(a), Double(b)]) => vec![Bool(a == b)],
+        ("==", [Bool(a), Bool(b)]) => vec![Bool(a == b)],
+        ("==", [String(a), String(b)]) => vec![Bool(a == b)],
+        ("<", [Int(a), Int(b)]) => vec![Bool(a < b)],
+        (">", [Int(a), Int(b)]) => vec![Bool(a > b)],
+        ("<=", [Int(a), Int(b)]) => vec![Bool(a <= b)],
+        (">=", [Int(a), Int(b)]) => vec![Bool(a >= b)],
+        ("<", [Long(a), Long(b)]) => vec![Bool(a < b)],
+        (">", [Long(a), Long(b)]) => vec![Bool(a > b)],
+        ("<=", [Long(a), Long(b)]) => vec![Bool(a <= b)],
+        (">=", [Long(a), Long(b)]) => vec![Bool(a >= b)],
+        ("<", [Double(a), Double(b)]) => vec![Bool(a < b)],
+        (">", [Double(a), Double(b)]) => vec![Bool(a > b)],
+        ("<=", [Double(a), Double(b)]) => vec![Bool(a <= b)],
+        (">=", [Double(a), Double(b)]) => vec![Bool(a >= b)],
+        ("not", [Bool(a)]) => vec![Bool(!a)],
+        ("and", [Bool(a), Bool(b)]) => vec![Bool(*a && *b)],
+        ("or", [Bool(a), Bool(b)]) => vec![Bool(*a || *b)],
+        ("@int", [String(s)]) => vec![Int(s.parse().ok()?)],
+        ("@int", [Bool(b)]) => vec![Int(*b as i32)],
+        ("@int", [Long(n)]) => vec![Int(*n as i32)],
+        ("@int", [Double(d)]) => vec![Int(*d as i32)],
+        ("@long", [String(s)]) => vec![Long(s.parse().ok()?)],
+        ("@long", [Int(n)]) => vec![Long(*n as i64)],
+        // Rust reads some spellings, like `inf`, that Java doesn't
+        ("@double", [String(s)]) => {
+            vec![Double(java_trim(s).parse().ok().filter(|d: &f64| d.is_finite())?)]
+        }
+        ("@double", [Int(n)]) => vec![Double(*n as f64)],
+        ("@bool", [Int(n)]) => vec![Bool(*n != 0)],
+        ("@string", [Int(n)]) => vec![String(n.to_string())],
+        ("@string", [Bool(b)]) => vec![String(b.to_string())],
+        ("@string", [Long(n)]) => vec![String(n.to_string())],
+        // `Double.toString` doesn't always print the shortest digits that read back the same, like
+        // Rust does, so its output is left to runtime
+        ("@string", [String(s)]) => vec![String(s.clone())],
+        ("concat", [String(a), String(b)]) => vec![String(format!("{a}{b}"))],
+        ("strlen", [String(s)]) => vec![Int(java_len(s))],
+        // out of bounds indices throw at runtime, and a split surrogate pair can't be a constant
+        ("substr", [String(s), Int(begin), Int(end)]) => {
+            let units = java_substring_units(s, *begin, *end).ok()?;
+            vec![String(std::string::String::from_utf16(&units).ok()?)]
+        }
+        ("charat", [String(s), Int(i)]) => vec![Int(java_char_at(s, *i).ok()?)],
+        ("trim", [String(s)]) => vec![String(java_trim(s).into())],
+        ("upper", [String(s)]) => vec![String(s.to_uppercase())],
+        ("lower", [String(s)]) => vec![String(s.to_lowercase())],
+        ("contains", [String(a), String(b)]) => vec![Bool(a.contains(b.as_str()))],
+        ("startswith", [String(a), String(b)]) => vec![Bool(a.starts_with(b.as_str()))],
+        ("indexof", [String(a), String(b)]) => vec![Int(java_index_of(a, b))],
+        ("dup", [a]) => vec![a.clone(), a.clone()],
+        ("swap", [a, b]) => vec![b.clone(), a.clone()],
+        ("dupx1", [a, b]) => vec![b.clone(), a.clone(), b.clone()],
+        ("drop", [_]) => vec![],
+        (name, []) if CASTS.contains(&name) => vec![],
+        _ => return None,
+    })
+}
+
+fn fold_list(nodes: &mut Vec<AstNode>) {
+    for node in nodes.iter_mut() {
+        fold_children(node);
+    }
+    let mut folded: Vec<AstNode> = Vec::with_capacity(nodes.len());
+    // how many of the last nodes in `folded` push a constant
+    let mut constants = 0;
+    for node in nodes.drain(..) {
+        if matches!(
+            node.inner,
+            AstItem::PushInt(_)
+                | AstItem::PushBool(_)
+                | AstItem::PushLong(_)
+                | AstItem::PushDouble(_)
+                | AstItem::PushString(_)
+        ) {
+            folded.push(node);
+            constants += 1;
+            continue;
+        }
+        let literals = stack_effect(&node)
+            .filter(|(pops, _)| *pops <= constants)
+            .and_then(|(pops, pushes)| Some((pops, literals(&node, pushes)?)));
+        if let Some((pops, literals)) = literals {
+            folded.truncate(folded.len() - pops);
+            constants = constants - pops + literals.len();
+            folded.extend(literals);
+        } else {
+            folded.push(node);
+            constants = 0;
+        }
+    }
+    *nodes = folded;
+}
+
+fn fold_children(node: &mut AstNode) {
+    match &mut node.inner {
+        AstItem::ListLiteral(nodes) | AstItem::Block(nodes) => fold_list(nodes),
+        AstItem::If { head, body, else_body } => {
+            head.iter_mut()
+                .chain([body])
+                .chain(else_body.iter_mut())
+                .for_each(|n| fold_children(n));
+        }
+        AstItem::Switch { arms, default } => {
+            arms.iter_mut().for_each(|(_, n)| fold_children(n));
+            fold_children(default);
+        }
+        AstItem::While { head, body } => {
+            head.iter_mut().chain([body]).for_each(|n| fold_children(n));
+        }
+        AstItem::For { init, condition, modifier, body } => {
+            [init, condition, modifier, body]
+                .into_iter()
+                .for_each(|n| fold_children(n));
+        }
+        AstItem::Try { body, catches, .. } => {
+            fold_children(body);
+            catches.iter_mut().for_each(|(_, n)| fold_children(n));
+        }
+        AstItem::Store { initializer: Some(init), .. }
+        | AstItem::SetField { initializer: Some(init), .. } => fold_children(init),
+        AstItem::TypeSwitch { arms, chosen_index: Some(i) } => fold_children(&mut arms[*i].1),
+        AstItem::Function { body, .. } => fold_children(body),
+        _ => {}
+    }
+}
+
+fn fuse_list(nodes: &mut [AstNode]) {
+    for node in nodes.iter_mut() {
+        fuse_children(node);
+    }
+    // the indices of the `concat`s of the current chain
+    let mut chain = Vec::new();
+    // how many values the nodes since the last `concat` pushed, as long as they leave the values
+    // below alone and don't branch
+    let mut pushed: Option<usize> = Some(0);
+    for i in 0..nodes.len() {
+        if matches!(&nodes[i].inner, AstItem::Jasmin { name, .. } if name == "concat") {
+            // the next part of the chain has to be on top of the string so far
+            if pushed != Some(1) {
+                fuse_chain(nodes, &chain);
+                chain.clear();
+            }
+            chain.push(i);
+            pushed = Some(0);
+        } else {
+            pushed = pushed.and_then(|pushed| {
+                let (pops, pushes) = straight_effect(&nodes[i])?;
+                pushed.checked_sub(pops).map(|pushed| pushed + pushes)
+            });
+        }
+    }
+    fuse_chain(nodes, &chain);
+}
+
+/// Like [`fold_children`], but the elements of list literals are compiled one by one, so the
+/// nodes of a literal don't form a chain
+fn fuse_children(node: &mut AstNode) {
+    match &mut node.inner {
+        AstItem::ListLiteral(nodes) => nodes.iter_mut().for_each(fuse_children),
+        AstItem::Block(nodes) => fuse_list(nodes),
+        AstItem::If { head, body, else_body } => {
+            head.iter_mut()
+                .chain([body])
+                .chain(else_body.iter_mut())
+                .for_each(|n| fuse_children(n));
+        }
+        AstItem::Switch { arms, default } => {
+            arms.iter_mut().for_each(|(_, n)| fuse_children(n));
+            fuse_children(default);
+        }
+        AstItem::While { head, body } => {
+            head.iter_mut().chain([body]).for_each(|n| fuse_children(n));
+        }
+        AstItem::For { init, condition, modifier, body } => {
+            [init, condition, modifier, body]
+                .into_iter()
+                .for_each(|n| fuse_children(n));
+        }
+        AstItem::Try { body, catches, .. } => {
+            fuse_children(body);
+            catches.iter_mut().for_each(|(_, n)| fuse_children(n));
+        }
+        AstItem::Store { initializer: Some(init), .. }
+        | AstItem::SetField { initializer: Some(init), .. } => fuse_children(init),
+        AstItem::TypeSwitch { arms, chosen_index: Some(i) } => fuse_children(&mut arms[*i].1),
+        AstItem::Function { body, .. } => fuse_children(body),
+        _ => {}
+    }
+}
+
+/// Replaces the `concat`s at `chain` in `nodes`: the first one creates a `StringBuilder` with
+/// both strings, which stays on the stack in place of the string so far, the ones in the middle
+/// append to it and the last one turns it back into a string. The standard library reserves the
+/// stack the first one needs.
+fn fuse_chain(nodes: &mut [AstNode], chain: &[usize]) {
+    let [first, middle @ .., last] = chain else {
+        return;
+    };
+    let append = format!(
+        "invokevirtual {CLASS_STRING_BUILDER}/append(Ljava/lang/String;)L{CLASS_STRING_BUILDER};"
+    );
+    let bodies = [
+        (*first, format!(
+            "swap
+            new {CLASS_STRING_BUILDER}
+            dup_x1
+            swap
+            invokespecial {CLASS_STRING_BUILDER}/<init>(Ljava/lang/String;)V
+            swap
+            {append}"
+        )),
+        (*last, format!("{append}\ninvokevirtual {CLASS_STRING_BUILDER}/toString()Ljava/lang/String;")),
+    ];
+    for (i, code) in middle.iter().map(|i| (*i, append.clone())).chain(bodies) {
+        if let AstItem::Jasmin { body, .. } = &mut nodes[i].inner {
+            *body = code;
+        }
+    }
+}
+
+/// Like [`stack_effect`], but also for the nodes that only read values without being pure
+fn straight_effect(node: &AstNode) -> Option<(usize, usize)> {
+    match &node.inner {
+        AstItem::Load(_) => Some((0, 1)),
+        AstItem::GetField { .. } => Some((1, 1)),
+        _ => stack_effect(node),
+    }
+}
+
+/// How many values a pure node pops and pushes, or `None` if it isn't pure or leaves a boxed
+/// value behind
+fn stack_effect(node: &AstNode) -> Option<(usize, usize)> {
+    let mut leaves = Vec::new();
+    pure_leaves(node, &mut leaves)?;
+    // whether the values the node pushed so far are boxed
+    let mut boxed = Vec::new();
+    let mut pops = 0;
+    let mut pop = |boxed: &mut Vec<bool>| {
+        boxed.pop().unwrap_or_else(|| {
+            pops += 1;
+            false
+        })
+    };
+    for leaf in leaves {
+        match &leaf.inner {
+            AstItem::PushInt(_)
+            | AstItem::PushBool(_)
+            | AstItem::PushLong(_)
+            | AstItem::PushDouble(_)
+            | AstItem::PushString(_) => boxed.push(false),
+            AstItem::Jasmin { name, .. } if CASTS.contains(&name.as_str()) => {
+                pop(&mut boxed);
+                boxed.push(BOXING.contains(&name.as_str()));
+            }
+            AstItem::Jasmin { name, input, output, .. } => {
+                let inputs = input.iter().map(|_| pop(&mut boxed)).collect::<Vec<_>>();
+                match name.as_str() {
+                    // stack manipulation keeps the values as they are
+                    "dup" => boxed.extend([inputs[0], inputs[0]]),
+                    "swap" => boxed.extend([inputs[0], inputs[1]]),
+                    "dupx1" => boxed.extend([inputs[0], inputs[1], inputs[0]]),
+                    _ => boxed.extend(output.iter().map(|_| false)),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    if boxed.contains(&true) {
+        return None;
+    }
+    Some((pops, boxed.len()))
+}
+
+/// The literals and intrinsics that `node` consists of, if it is pure
+fn pure_leaves<'a>(node: &'a AstNode, leaves: &mut Vec<&'a AstNode>) -> Option<()> {
+    match &node.inner {
+        AstItem::PushInt(_)
+        | AstItem::PushBool(_)
+        | AstItem::PushLong(_)
+        | AstItem::PushDouble(_)
+        | AstItem::PushString(_) => leaves.push(node),
+        AstItem::Jasmin { name, .. } if PURE.contains(&name.as_str()) || CASTS.contains(&name.as_str()) => {
+            leaves.push(node)
+        }
+        AstItem::TypeSwitch { arms, chosen_index: Some(i) } => pure_leaves(&arms[*i].1, leaves)?,
+        AstItem::Block(nodes) => {
+            for n in nodes {
+                pure_leaves(n, leaves)?;
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Literals that push the top `count` values on the stack after `node`, if they are known
+fn literals(node: &AstNode, count: usize) -> Option<Vec<AstNode>> {
+    let after = node.stack_after.as_ref()?;
+    let base = after.len().checked_sub(count)?;
+    (base..after.len())
+        .map(|i| {
+            let inner = match after[i].value.as_ref()? {
+                Value::Int(n) => AstItem::PushInt(*n),
+                Value::Bool(b) => AstItem::PushBool(*b),
+                Value::Long(n) => AstItem::PushLong(*n),
+                // Jasmin has no literals for infinity and NaN
+                Value::Double(d) if d.is_finite() => AstItem::PushDouble(*d),
+                Value::Double(_) => return None,
+                Value::String(s) => AstItem::PushString(s.clone()),
+                Value::List(_) => return None,
+            };
+            let mut literal = AstNode::new(inner, node.span());
+            literal.stack = Some(after[..i].to_vec());
+            literal.stack_after = Some(after[..=i].to_vec());
+            literal.vars = node.vars_after.clone();
+            literal.vars_after = node.vars_after.clone();
+            Some(literal)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold_intrinsic as fold;
+    use crate::analyzer::Value::{Bool, Double, Int, Long, String};
+
+    #[test]
+    fn arithmetic_wraps_like_java() {
+        assert_eq!(fold("+", &[Int(i32::MAX), Int(1)]), Some(vec![Int(i32::MIN)]));
+        assert_eq!(fold("-", &[Int(i32::MIN), Int(1)]), Some(vec![Int(i32::MAX)]));
+        assert_eq!(fold("*", &[Int(0x10000), Int(0x10000)]), Some(vec![Int(0)]));
+        // the one division that overflows
+        assert_eq!(fold("/", &[Int(i32::MIN), Int(-1)]), Some(vec![Int(i32::MIN)]));
+        assert_eq!(fold("/", &[Int(-7), Int(2)]), Some(vec![Int(-3)]));
+        assert_eq!(fold("+", &[Long(i64::MAX), Long(1)]), Some(vec![Long(i64::MIN)]));
+        assert_eq!(fold("/", &[Long(i64::MIN), Long(-1)]), Some(vec![Long(i64::MIN)]));
+    }
+
+    #[test]
+    fn division_by_zero_is_left_to_runtime() {
+        assert_eq!(fold("/", &[Int(1), Int(0)]), None);
+        assert_eq!(fold("/", &[Long(1), Long(0)]), None);
+        // doubles don't throw
+        assert_eq!(fold("/", &[Double(1.0), Double(0.0)]), Some(vec![Double(f64::INFINITY)]));
+    }
+
+    #[test]
+    fn int_parsing_like_java() {
+        assert_eq!(fold("@int", &[String("42".into())]), Some(vec![Int(42)]));
+        assert_eq!(fold("@int", &[String("+7".into())]), Some(vec![Int(7)]));
+        assert_eq!(fold("@int", &[String("-2147483648".into())]), Some(vec![Int(i32::MIN)]));
+        // `Integer.parseInt` throws for these
+        for s in ["2147483648", " 1", "1.0", "", "0x10"] {
+            assert_eq!(fold("@int", &[String(s.into())]), None, "{s:?}");
+        }
+        // casts saturate and NaN becomes 0
+        assert_eq!(fold("@int", &[Double(1e20)]), Some(vec![Int(i32::MAX)]));
+        assert_eq!(fold("@int", &[Double(f64::NAN)]), Some(vec![Int(0)]));
+        assert_eq!(fold("@int", &[Long(1 << 32 | 5)]), Some(vec![Int(5)]));
+        assert_eq!(fold("@int", &[Bool(true)]), Some(vec![Int(1)]));
+    }
+
+    #[test]
+    fn substrings_of_surrogate_pairs() {
+        let s = || String("a😀".into());
+        assert_eq!(fold("substr", &[s(), Int(1), Int(3)]), Some(vec![String("😀".into())]));
+        assert_eq!(fold("substr", &[s(), Int(1), Int(2)]), None);
+        assert_eq!(fold("substr", &[s(), Int(2), Int(4)]), None);
+    }
+
+    #[test]
+    fn doubles_like_java() {
+        assert_eq!(fold("@double", &[String(" 1.5\n".into())]), Some(vec![Double(1.5)]));
+        // `String.trim` only strips control characters and spaces
+        assert_eq!(fold("@double", &[String("\u{2003}1.5".into())]), None);
+        assert_eq!(fold("@double", &[String("inf".into())]), None);
+        // `Double.toString` prints 9.999999999999999E22 and 2.82879384806159008E17
+        assert_eq!(fold("@string", &[Double(1e23)]), None);
+        assert_eq!(fold("@string", &[Double(2.82879384806159E17)]), None);
+    }
+
+    #[test]
+    fn long_and_double_arithmetic() {
+        assert_eq!(fold("*", &[Long(1 << 40), Long(1 << 30)]), Some(vec![Long(0)]));
+        assert_eq!(fold("-", &[Long(i64::MIN), Long(1)]), Some(vec![Long(i64::MAX)]));
+        assert_eq!(fold("/", &[Long(-7), Long(2)]), Some(vec![Long(-3)]));
+        assert_eq!(fold("+", &[Double(0.1), Double(0.2)]), Some(vec![Double(0.30000000000000004)]));
+        assert_eq!(fold("/", &[Double(-1.0), Double(0.0)]), Some(vec![Double(f64::NEG_INFINITY)]));
+        // no folding across types, the analyzer rejects these
+        assert_eq!(fold("+", &[Int(1), Long(2)]), None);
+        assert_eq!(fold("+", &[Long(1), Double(2.0)]), None);
+    }
+
+    #[test]
+    fn long_and_double_comparisons() {
+        assert_eq!(fold("<", &[Long(i64::MIN), Long(i64::MAX)]), Some(vec![Bool(true)]));
+        assert_eq!(fold(">=", &[Long(1 << 32), Long(1)]), Some(vec![Bool(true)]));
+        assert_eq!(fold("==", &[Long(1 << 32), Long(0)]), Some(vec![Bool(false)]));
+        assert_eq!(fold("<=", &[Double(-0.0), Double(0.0)]), Some(vec![Bool(true)]));
+        assert_eq!(fold("==", &[Double(-0.0), Double(0.0)]), Some(vec![Bool(true)]));
+        // like `dcmpg` and `dcmpl`, every comparison with NaN is false
+        for op in ["==", "<", ">", "<=", ">="] {
+            assert_eq!(fold(op, &[Double(f64::NAN), Double(1.0)]), Some(vec![Bool(false)]), "{op}");
+            assert_eq!(fold(op, &[Double(f64::NAN), Double(f64::NAN)]), Some(vec![Bool(false)]), "{op}");
+        }
+    }
+}
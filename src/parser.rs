@@ -1,14 +1,20 @@
-use std::{fmt::Debug, iter::Peekable, num::ParseIntError};
+use std::{
+    fmt::Debug,
+    iter::Peekable,
+    num::{ParseFloatError, ParseIntError},
+};
 
 use klex::{KlexError, Loc, RichToken, Token};
 use thiserror::Error;
 
 use crate::{
-    analyzer::Type,
-    ast::{AstBase, AstItem, AstNode, MatchInType, MatchOutType},
+    analyzer::{Signature, Type},
+    ast::{AstBase, AstItem, AstNode, ExternKind, ExternType, MatchInType, MatchOutType},
+    diagnostics::{Diagnostic, Span},
 };
 
 pub const KW_LIST: &str = "list";
+pub const KW_MAP: &str = "map";
 pub const KW_GET: &str = "get";
 pub const KW_SET: &str = "set";
 pub const KW_PRINT: &str = "print";
@@ -24,11 +30,28 @@ pub const KW_TO_INT: &str = "@int";
 pub const KW_TO_STRING: &str = "@string";
 pub const KW_TO_CHAR_LIST: &str = "@charlist";
 pub const KW_CMP_ERR: &str = "cmperr";
+pub const KW_TRY: &str = "try";
+pub const KW_CATCH: &str = "catch";
+pub const KW_THROW: &str = "throw";
+pub const KW_BREAK: &str = "break";
+pub const KW_CONTINUE: &str = "continue";
+pub const KW_FN: &str = "fn";
+pub const KW_RECORD: &str = "record";
+pub const KW_EXTERN: &str = "extern";
+pub const KW_VIRTUAL: &str = "virtual";
+pub const KW_NEW: &str = "new";
+pub const KW_AS: &str = "as";
+pub const KW_TRUE: &str = "true";
+pub const KW_FALSE: &str = "false";
 
 pub const TYPE_NAME_INT: &str = "int";
+pub const TYPE_NAME_BOOL: &str = "bool";
+pub const TYPE_NAME_LONG: &str = "long";
+pub const TYPE_NAME_DOUBLE: &str = "double";
 pub const TYPE_NAME_STRING: &str = "string";
 pub const TYPE_NAME_ANY: &str = "any";
 pub const TYPE_NAME_LIST: &str = "list";
+pub const TYPE_NAME_MAP: &str = "map";
 pub const TYPE_NAME_OBJECT: &str = "object";
 
 #[derive(Error, Debug)]
@@ -41,6 +64,10 @@ pub enum ParserErr {
     IllegalStartOfItem(RichToken),
     #[error("error parsing an int literal at {1}: {0}")]
     IntParseError(ParseIntError, Loc),
+    #[error("error parsing a long literal at {1}: {0}")]
+    LongParseError(ParseIntError, Loc),
+    #[error("error parsing a double literal at {1}: {0}")]
+    DoubleParseError(ParseFloatError, Loc),
     #[error("unknown keyword: '{0}' at {1}")]
     UnknownKeyword(String, Loc),
     #[error("unexpected token: {0}, found {1:?} at {2}")]
@@ -62,8 +89,20 @@ where
         loc: Loc::start_of_file(0),
     };
 
-    while tokens.peek_skip_comments()?.is_some() {
-        base.nodes.push(next_node(&mut tokens)?);
+    while let Some(t) = tokens.peek_skip_comments()? {
+        // functions, records and externs may only be defined at the top level
+        if matches!(&t.inner, Token::Sym(s) if s == KW_FN) {
+            let span = Span::from(&tokens.next()?);
+            base.nodes.push(AstNode::new(parse_fn(&mut tokens)?, span));
+        } else if matches!(&t.inner, Token::Sym(s) if s == KW_RECORD) {
+            let span = Span::from(&tokens.next()?);
+            base.nodes.push(AstNode::new(parse_record(&mut tokens)?, span));
+        } else if matches!(&t.inner, Token::Sym(s) if s == KW_EXTERN) {
+            let span = Span::from(&tokens.next()?);
+            base.nodes.push(AstNode::new(parse_extern(&mut tokens)?, span));
+        } else {
+            base.nodes.push(next_node(&mut tokens)?);
+        }
     }
 
     Ok(base)
@@ -84,12 +123,20 @@ where
     I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
 {
     let t0 = tokens.next_skip_comments()?;
-    let loc = t0.loc;
+    let span = Span::from(&t0);
 
     let item = match t0.inner {
         Token::Num(ref n) => {
-            if n.contains(".") {
-                AstItem::PushString(n.to_owned())
+            if let Some(n) = n.strip_suffix(['L', 'l']) {
+                AstItem::PushLong(
+                    n.parse()
+                        .map_err(|e| ParserErr::LongParseError(e, tokens.loc))?,
+                )
+            } else if n.contains(".") {
+                AstItem::PushDouble(
+                    n.parse()
+                        .map_err(|e| ParserErr::DoubleParseError(e, tokens.loc))?,
+                )
             } else {
                 AstItem::PushInt(
                     n.parse()
@@ -101,12 +148,13 @@ where
         Token::Chr(c) => AstItem::PushInt(c as u32 as i32),
         Token::Sym(ref sym) => parse_symbol(tokens, sym)?,
         Token::Colon => parse_no_init_store(tokens)?,
+        Token::Dot => parse_field(tokens)?,
         Token::LBrace => parse_block(tokens, Token::RBrace)?,
         Token::LBrack => parse_list_lit(tokens)?,
         Token::Dollar => parse_jasmin(tokens)?,
         _ => return Err(ParserErr::IllegalStartOfItem(t0)),
     };
-    Ok(AstNode::new(item, loc))
+    Ok(AstNode::new(item, span))
 }
 
 fn parse_jasmin<I>(tokens: &mut Tokens<I>) -> Result<AstItem, ParserErr>
@@ -181,6 +229,197 @@ where
     })
 }
 
+/// Parses a function definition after the `fn` keyword:
+/// name [input types] -> [output types] body
+fn parse_fn<I>(tokens: &mut Tokens<I>) -> Result<AstItem, ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    let token = tokens.next_skip_comments()?;
+    let Token::Sym(name) = token.inner else {
+        return Err(ParserErr::UnexpectedToken("expected function name after `fn`".into(), token.inner, token.loc));
+    };
+    let input = expect_type_list(tokens, "function input type list")?;
+    expect_token(
+        tokens,
+        Token::Arrow,
+        "expected `->` after function input list",
+    )?;
+    let output = expect_type_list(tokens, "function output type list")?;
+    if output.len() > 1 {
+        return Err(ParserErr::Error(
+            format!("function `{name}` may return at most one value"),
+            tokens.loc,
+        ));
+    }
+    let body = next_node(tokens)?;
+    Ok(AstItem::Function {
+        name,
+        signature: Signature { input, output },
+        body: Box::new(body),
+        limits: None,
+    })
+}
+
+/// Parses a record definition after the `record` keyword:
+/// Name { field: type, ... }
+fn parse_record<I>(tokens: &mut Tokens<I>) -> Result<AstItem, ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    let token = tokens.next_skip_comments()?;
+    let Token::Sym(name) = token.inner else {
+        return Err(ParserErr::UnexpectedToken("expected record name after `record`".into(), token.inner, token.loc));
+    };
+    expect_token(tokens, Token::LBrace, "expected `{` after record name")?;
+    let mut fields = Vec::new();
+    loop {
+        let token = tokens.next_skip_comments()?;
+        let field = match token.inner {
+            Token::RBrace => break,
+            Token::Sym(field) => field,
+            t => return Err(ParserErr::UnexpectedToken("expected a field name or `}`".into(), t, token.loc)),
+        };
+        expect_token(tokens, Token::Colon, "expected `:` between field name and type")?;
+        fields.push((field, parse_type(tokens)?));
+        if let Some(Token::Comma) = tokens.peek_skip_comments()?.map(|t| &t.inner) {
+            tokens.next_skip_comments()?;
+        }
+    }
+    Ok(AstItem::Record { name, fields })
+}
+
+/// Parses an extern declaration after the `extern` keyword:
+/// (virtual | new)? "target" [input types] -> [output types] (as name)?
+/// Without `as`, the name is the last part of the target.
+fn parse_extern<I>(tokens: &mut Tokens<I>) -> Result<AstItem, ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    let mut token = tokens.next_skip_comments()?;
+    let kind = match &token.inner {
+        Token::Sym(s) if s == KW_VIRTUAL => ExternKind::Virtual,
+        Token::Sym(s) if s == KW_NEW => ExternKind::Constructor,
+        _ => ExternKind::Static,
+    };
+    if kind != ExternKind::Static {
+        token = tokens.next_skip_comments()?;
+    }
+    let Token::Str(target) = token.inner else {
+        return Err(ParserErr::UnexpectedToken(
+            "expected the Java method as a string like \"java/lang/Math/max\" after `extern`".into(),
+            token.inner,
+            token.loc,
+        ));
+    };
+    let input = expect_extern_type_list(tokens, "extern input type list")?;
+    expect_token(tokens, Token::Arrow, "expected `->` after extern input list")?;
+    let output = expect_extern_type_list(tokens, "extern output type list")?;
+    if output.len() > 1 {
+        return Err(ParserErr::Error(
+            format!("extern `{target}` may return at most one value"),
+            tokens.loc,
+        ));
+    }
+    let name = if matches!(tokens.peek_skip_comments()?.map(|t| &t.inner), Some(Token::Sym(s)) if s == KW_AS) {
+        tokens.next()?;
+        let token = tokens.next_skip_comments()?;
+        let Token::Sym(name) = token.inner else {
+            return Err(ParserErr::UnexpectedToken("expected a name after `as`".into(), token.inner, token.loc));
+        };
+        name
+    } else {
+        target.rsplit('/').next().unwrap_or(&target).to_owned()
+    };
+    Ok(AstItem::Extern {
+        name,
+        kind,
+        target,
+        input,
+        output,
+    })
+}
+
+fn expect_extern_type_list<I>(tokens: &mut Tokens<I>, reason: &str) -> Result<Vec<ExternType>, ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    expect_token(tokens, Token::LBrack, format!("`[` expected as start of type list: {reason}"))?;
+    let mut types = Vec::new();
+    loop {
+        if let Some(t) = tokens.peek_skip_comments()? {
+            if t.inner == Token::RBrack {
+                tokens.next()?;
+                break;
+            }
+            types.push(parse_extern_type(tokens)?);
+            if let Some(Token::Comma) = tokens.peek_skip_comments()?.map(|t| &t.inner) {
+                tokens.next_skip_comments()?;
+            }
+        } else {
+            return Err(ParserErr::UnexpectedEOF(format!("hit EOF while parsing type list: {reason}"), tokens.loc));
+        }
+    }
+    Ok(types)
+}
+
+/// Parses a type, or `object[type]` for a type that the JVM sees as an `Object`
+fn parse_extern_type<I>(tokens: &mut Tokens<I>) -> Result<ExternType, ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    if matches!(tokens.peek_skip_comments()?.map(|t| &t.inner), Some(Token::Sym(s)) if s == TYPE_NAME_OBJECT) {
+        tokens.next()?;
+        if let Some(Token::LBrack) = tokens.peek_skip_comments()?.map(|t| &t.inner) {
+            return Ok(ExternType {
+                ty: parse_type_in_angles(tokens)?,
+                erased: true,
+            });
+        }
+        return Ok(ExternType {
+            ty: parse_object_after_kw(tokens)?,
+            erased: false,
+        });
+    }
+    Ok(ExternType {
+        ty: parse_type(tokens)?,
+        erased: false,
+    })
+}
+
+/// Parses a field access after the `.`:
+/// name -> get; name = initializer -> set
+fn parse_field<I>(tokens: &mut Tokens<I>) -> Result<AstItem, ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    let name = expect_field_name(tokens)?;
+    if let Some(Token::Equal) = tokens.peek_skip_comments()?.map(|t| &t.inner) {
+        tokens.next()?;
+        Ok(AstItem::SetField {
+            initializer: Some(Box::new(next_node(tokens)?)),
+            name,
+            resolved: None,
+        })
+    } else {
+        Ok(AstItem::GetField {
+            name,
+            resolved: None,
+        })
+    }
+}
+
+fn expect_field_name<I>(tokens: &mut Tokens<I>) -> Result<String, ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    let token = tokens.next_skip_comments()?;
+    let Token::Sym(name) = token.inner else {
+        return Err(ParserErr::UnexpectedToken("expected field name after `.`".into(), token.inner, token.loc));
+    };
+    Ok(name)
+}
+
 fn parse_list_lit<I>(tokens: &mut Tokens<I>) -> Result<AstItem, ParserErr>
 where
     I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
@@ -190,12 +429,12 @@ where
     while let Some(t) = tokens.peek_skip_comments()? {
         if t.inner == Token::RBrack {
             tokens.next()?;
-            items.push(AstNode::new(AstItem::Block(curr_item), tokens.loc));
+            items.push(AstNode::new(AstItem::Block(curr_item), tokens.loc.into()));
             break;
         }
         if t.inner == Token::Comma {
             tokens.next()?;
-            items.push(AstNode::new(AstItem::Block(curr_item), tokens.loc));
+            items.push(AstNode::new(AstItem::Block(curr_item), tokens.loc.into()));
             curr_item = Vec::new();
         }
         curr_item.push(next_node(tokens)?);
@@ -213,6 +452,13 @@ where
         "var collect: `... := name`; var init: `name = ...`",
     )?;
     let token = tokens.next_skip_comments()?;
+    if token.inner == Token::Dot {
+        return Ok(AstItem::SetField {
+            initializer: None,
+            name: expect_field_name(tokens)?,
+            resolved: None,
+        });
+    }
     let Token::Sym(name) = token.inner else {
         return Err(ParserErr::UnexpectedToken("expected variable name after `=`".into(), token.inner, tokens.loc));
     };
@@ -261,11 +507,14 @@ where
     while let Some(t) = tokens.peek_skip_comments()? {
         if t.inner == closing {
             tokens.next()?;
-            break;
+            return Ok(AstItem::Block(nodes));
         }
         nodes.push(next_node(tokens)?);
     }
-    Ok(AstItem::Block(nodes))
+    Err(ParserErr::UnexpectedEOF(
+        format!("hit EOF while waiting for closing `{}`", closing.spelling()),
+        tokens.loc,
+    ))
 }
 
 fn parse_symbol<I>(tokens: &mut Tokens<I>, sym: &str) -> Result<AstItem, ParserErr>
@@ -274,6 +523,12 @@ where
 {
     Ok(match sym {
         KW_LIST => AstItem::List(parse_type_in_angles(tokens)?),
+        KW_MAP => {
+            let (key, value) = parse_pair_in_angles(tokens, parse_type)?;
+            AstItem::Map(key, value)
+        }
+        KW_TRUE => AstItem::PushBool(true),
+        KW_FALSE => AstItem::PushBool(false),
         KW_IF => {
             let head = if matches!(
                 tokens.peek_skip_comments(),
@@ -282,10 +537,10 @@ where
                     ..
                 }))
             ) {
-                let loc = tokens.next()?.loc;
+                let span = Span::from(&tokens.next()?);
                 Some(Box::new(AstNode::new(
                     parse_block(tokens, Token::RParen)?,
-                    loc,
+                    span,
                 )))
             } else {
                 None
@@ -373,10 +628,10 @@ where
                     ..
                 }))
             ) {
-                let loc = tokens.next()?.loc;
+                let span = Span::from(&tokens.next()?);
                 Some(Box::new(AstNode::new(
                     parse_block(tokens, Token::RParen)?,
-                    loc,
+                    span,
                 )))
             } else {
                 None
@@ -388,20 +643,48 @@ where
             };
             if sym == KW_DOWHILE {
                 AstItem::Block(vec![
-                    AstNode::new(AstItem::PushInt(1), tokens.loc),
-                    AstNode::new(while_item, tokens.loc),
+                    AstNode::new(AstItem::PushBool(true), tokens.loc.into()),
+                    AstNode::new(while_item, tokens.loc.into()),
                 ])
             } else {
                 while_item
             }
         }
         KW_FOR => parse_for(tokens)?,
+        KW_TRY => parse_try(tokens)?,
+        KW_THROW => AstItem::Throw,
+        KW_BREAK => AstItem::Break,
+        KW_CONTINUE => AstItem::Continue,
+        KW_CATCH => {
+            return Err(ParserErr::UnknownKeyword(
+                "catch without try!".into(),
+                tokens.loc,
+            ))
+        }
         KW_ELSE => {
             return Err(ParserErr::UnknownKeyword(
                 "else without if!".into(),
                 tokens.loc,
             ))
         }
+        KW_FN => {
+            return Err(ParserErr::Error(
+                "functions may only be defined at the top level".into(),
+                tokens.loc,
+            ))
+        }
+        KW_RECORD => {
+            return Err(ParserErr::Error(
+                "records may only be defined at the top level".into(),
+                tokens.loc,
+            ))
+        }
+        KW_EXTERN => {
+            return Err(ParserErr::Error(
+                "externs may only be declared at the top level".into(),
+                tokens.loc,
+            ))
+        }
         KW_CMP_ERR => {
             if let Token::Str(msg) = tokens.next_skip_comments()?.inner {
                 AstItem::CmpErr(msg.to_owned())
@@ -427,6 +710,34 @@ where
     })
 }
 
+fn parse_try<I>(tokens: &mut Tokens<I>) -> Result<AstItem, ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    let body = next_node(tokens)?;
+    let mut catches = Vec::new();
+    while matches!(tokens.peek_skip_comments()?.map(|t| &t.inner), Some(Token::Sym(s)) if s == KW_CATCH) {
+        tokens.next()?;
+        let token = tokens.next_skip_comments()?;
+        let Token::Str(class) = token.inner else {
+            return Err(ParserErr::UnexpectedToken(
+                "expected the caught exception class as a string like \"java/lang/Exception\" after `catch`".into(),
+                token.inner,
+                token.loc,
+            ));
+        };
+        catches.push((class, next_node(tokens)?));
+    }
+    if catches.is_empty() {
+        return Err(ParserErr::Error("`try` without `catch`".into(), tokens.loc));
+    }
+    Ok(AstItem::Try {
+        body: Box::new(body),
+        catches,
+        spill: None,
+    })
+}
+
 fn parse_for<I>(tokens: &mut Tokens<I>) -> Result<AstItem, ParserErr>
 where
     I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
@@ -460,9 +771,9 @@ where
     let body = next_node(tokens)?;
 
     Ok(AstItem::For {
-        init: Box::new(AstNode::new(AstItem::Block(init_nodes), tokens.loc)),
-        condition: Box::new(AstNode::new(AstItem::Block(cond_nodes), tokens.loc)),
-        modifier: Box::new(AstNode::new(AstItem::Block(mod_nodes), tokens.loc)),
+        init: Box::new(AstNode::new(AstItem::Block(init_nodes), tokens.loc.into())),
+        condition: Box::new(AstNode::new(AstItem::Block(cond_nodes), tokens.loc.into())),
+        modifier: Box::new(AstNode::new(AstItem::Block(mod_nodes), tokens.loc.into())),
         body: Box::new(body),
     })
 }
@@ -503,6 +814,22 @@ where
     Ok(ty)
 }
 
+/// Parses the key and value type of a map: `[key, value]`
+fn parse_pair_in_angles<I, T>(
+    tokens: &mut Tokens<I>,
+    parse: fn(&mut Tokens<I>) -> Result<T, ParserErr>,
+) -> Result<(T, T), ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    expect_token(tokens, Token::LBrack, "expected `[` followed by a key type, `,`, a value type and `]`")?;
+    let key = parse(tokens)?;
+    expect_token(tokens, Token::Comma, "expected `,` between the key and the value type")?;
+    let value = parse(tokens)?;
+    expect_token(tokens, Token::RBrack, "expected `]` after the value type")?;
+    Ok((key, value))
+}
+
 fn parse_match_in_type_in_angles<I>(tokens: &mut Tokens<I>) -> Result<MatchInType, ParserErr>
 where
     I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
@@ -543,16 +870,18 @@ where
     if let Token::Sym(ref name) = type_name.inner {
         Ok(match name.as_str() {
             TYPE_NAME_INT => Type::Int,
+            TYPE_NAME_BOOL => Type::Bool,
+            TYPE_NAME_LONG => Type::Long,
+            TYPE_NAME_DOUBLE => Type::Double,
             TYPE_NAME_STRING => Type::String,
             TYPE_NAME_OBJECT => parse_object_after_kw(tokens)?,
             TYPE_NAME_LIST => Type::List(Box::new(parse_type_in_angles(tokens)?)),
-            _ => {
-                return Err(ParserErr::UnexpectedToken(
-                    "not a type!".into(),
-                    type_name.inner,
-                    type_name.loc,
-                ))
+            TYPE_NAME_MAP => {
+                let (key, value) = parse_pair_in_angles(tokens, parse_type)?;
+                Type::Map(Box::new(key), Box::new(value))
             }
+            // whether the record exists is checked by the analyzer
+            record => Type::Record(record.to_owned()),
         })
     } else {
         Err(ParserErr::UnexpectedToken(
@@ -587,6 +916,30 @@ where
     Ok(types)
 }
 
+fn expect_type_list<I>(tokens: &mut Tokens<I>, reason: &str) -> Result<Vec<Type>, ParserErr>
+where
+    I: Iterator<Item = Result<RichToken, KlexError>> + Debug + Clone,
+{
+    expect_token(tokens, Token::LBrack, format!("`[` expected as start of type list: {reason}"))?;
+    let mut types = Vec::new();
+    loop {
+        if let Some(t) = tokens.peek_skip_comments()? {
+            if t.inner == Token::RBrack {
+                tokens.next()?;
+                break;
+            } else {
+                types.push(parse_type(tokens)?);
+                if let Some(Token::Comma) = tokens.peek_skip_comments()?.map(|t| &t.inner) {
+                    tokens.next_skip_comments()?;
+                }
+            }
+        } else {
+            return Err(ParserErr::UnexpectedEOF(format!("hit EOF while parsing type list: {reason}"), tokens.loc));
+        }
+    }
+    Ok(types)
+}
+
 /// Parses a Type::Object(name) after the object keyword:
 /// ("name") -> Type::Object(name)
 fn parse_object_after_kw<I>(tokens: &mut Tokens<I>) -> Result<Type, ParserErr>
@@ -610,9 +963,16 @@ where
     if let Token::Sym(sym) = &type_name.inner {
         Ok(match sym.as_str() {
             TYPE_NAME_INT => MatchInType::Type(Type::Int),
+            TYPE_NAME_BOOL => MatchInType::Type(Type::Bool),
+            TYPE_NAME_LONG => MatchInType::Type(Type::Long),
+            TYPE_NAME_DOUBLE => MatchInType::Type(Type::Double),
             TYPE_NAME_STRING => MatchInType::Type(Type::String),
             TYPE_NAME_OBJECT => MatchInType::Type(parse_object_after_kw(tokens)?),
             TYPE_NAME_LIST => MatchInType::List(Box::new(parse_match_in_type_in_angles(tokens)?)),
+            TYPE_NAME_MAP => {
+                let (key, value) = parse_pair_in_angles(tokens, parse_match_in_type)?;
+                MatchInType::Map(Box::new(key), Box::new(value))
+            }
             TYPE_NAME_ANY => MatchInType::Any,
             generic => MatchInType::Generic(generic.to_owned()),
         })
@@ -633,9 +993,16 @@ where
     if let Token::Sym(sym) = &type_name.inner {
         Ok(match sym.as_str() {
             TYPE_NAME_INT => MatchOutType::Type(Type::Int),
+            TYPE_NAME_BOOL => MatchOutType::Type(Type::Bool),
+            TYPE_NAME_LONG => MatchOutType::Type(Type::Long),
+            TYPE_NAME_DOUBLE => MatchOutType::Type(Type::Double),
             TYPE_NAME_STRING => MatchOutType::Type(Type::String),
             TYPE_NAME_OBJECT => MatchOutType::Type(parse_object_after_kw(tokens)?),
             TYPE_NAME_LIST => MatchOutType::List(Box::new(parse_match_out_type_in_angles(tokens)?)),
+            TYPE_NAME_MAP => {
+                let (key, value) = parse_pair_in_angles(tokens, parse_match_out_type)?;
+                MatchOutType::Map(Box::new(key), Box::new(value))
+            }
             TYPE_NAME_ANY => return Err(ParserErr::UnexpectedToken("type `any` not allowed here!".into(), type_name.inner.clone(), type_name.loc)),
             generic => MatchOutType::Generic(generic.to_owned()),
         })
@@ -699,10 +1066,20 @@ impl ParserErr {
             Self::LexerErr(_, loc)
             | Self::UnexpectedEOF(_, loc)
             | Self::IntParseError(_, loc)
+            | Self::LongParseError(_, loc)
+            | Self::DoubleParseError(_, loc)
             | Self::UnknownKeyword(_, loc)
             | Self::UnexpectedToken(_, _, loc)
             | Self::Error(_, loc) => *loc,
             Self::IllegalStartOfItem(_) => return None,
         })
     }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        let span = match self {
+            Self::IllegalStartOfItem(t) => Some(Span::from(t)),
+            _ => self.loc().map(Span::from),
+        };
+        Diagnostic::error(self.to_string(), span)
+    }
 }
@@ -0,0 +1,404 @@
+//! Peephole optimizations of the generated code. Each rule looks at a few neighbouring
+//! instructions and replaces them with fewer or cheaper ones; the rules are applied until none
+//! matches anymore. Labels, stack map frames and everything else that isn't a plain instruction
+//! ends a window, so the stack at every jump target stays what the frames declare.
+
+use crate::ir::{Instruction, Label, Method, Opcode, Operand};
+
+/// An instruction and its integer operand, if it has one
+type Pattern = (Opcode, Option<i32>);
+
+/// The code that comparison intrinsics of `std.jack` emit after `Integer.compare`, `lcmp` or
+/// `dcmp<op>`, and the `if_icmp<cond>` that jumps exactly if they leave 1
+const COMPARISONS: [(&[Pattern], Opcode); 5] = {
+    use Opcode::*;
+    [
+        (&[(Dup, None), (IMul, None), (IConst1, None), (IXor, None)], IfICmpEq),
+        (&[(BiPush, Some(31)), (IUShr, None)], IfICmpLt),
+        (&[(INeg, None), (BiPush, Some(31)), (IUShr, None)], IfICmpGt),
+        (&[(IConst1, None), (ISub, None), (BiPush, Some(31)), (IUShr, None)], IfICmpLe),
+        (&[(IConstM1, None), (IXor, None), (BiPush, Some(31)), (IUShr, None)], IfICmpGe),
+    ]
+};
+
+const INTEGER_COMPARE: &str = "java/lang/Integer/compare(II)I";
+
+/// Instructions that push one slot without side effects
+const PUSHES: [Opcode; 13] = {
+    use Opcode::*;
+    [
+        IConstM1, IConst0, IConst1, IConst2, IConst3, IConst4, IConst5, BiPush, SiPush, Ldc,
+        ILoad, ALoad, Dup,
+    ]
+};
+
+/// Instructions that push two slots without side effects
+const WIDE_PUSHES: [Opcode; 8] = {
+    use Opcode::*;
+    [LConst0, LConst1, DConst0, DConst1, Ldc2W, LLoad, DLoad, Dup2]
+};
+
+/// Loads and the stores to the same kind of local variable, and how to duplicate its value
+const LOADS_STORES: [(Opcode, Opcode, Opcode); 4] = {
+    use Opcode::*;
+    [(ILoad, IStore, Dup), (LLoad, LStore, Dup2), (DLoad, DStore, Dup2), (ALoad, AStore, Dup)]
+};
+
+/// Optimizes the code of `method`
+pub fn optimize(method: &mut Method) {
+    let mut grown = 0;
+    while rewrite(&mut method.code, &mut grown) {}
+    remove_empty_catches(&mut method.code);
+    method.max_stack += grown;
+}
+
+/// Removes the exception table entries whose range the rules left without instructions, which
+/// the JVM rejects. Their handlers stay as dead code, which still has its frame.
+fn remove_empty_catches(code: &mut Vec<Instruction>) {
+    let position = |code: &[Instruction], label: Label| {
+        code.iter().position(|instruction| matches!(instruction, Instruction::Label(l) if *l == label))
+    };
+    let empty = code
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Catch { from, to, .. } => Some((*from, *to)),
+            _ => None,
+        })
+        .filter(|&(from, to)| match (position(code, from), position(code, to)) {
+            (Some(from), Some(to)) => !code[from.min(to)..to.max(from)].iter().any(Instruction::is_code),
+            _ => false,
+        })
+        .collect::<Vec<_>>();
+    code.retain(|instruction| match instruction {
+        Instruction::Catch { from, to, .. } => !empty.contains(&(*from, *to)),
+        _ => true,
+    });
+}
+
+/// Applies every rule once at every instruction; returns whether anything changed. `grown` is
+/// raised to the number of stack slots the method needs in addition.
+fn rewrite(code: &mut Vec<Instruction>, grown: &mut usize) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < code.len() {
+        if matches!(code[i], Instruction::Op(..))
+            && (fuse_comparison(code, i)
+                || invert_jump(code, i)
+                || remove_jump_to_next(code, i)
+                || remove_pair(code, i, grown))
+        {
+            changed = true;
+        } else {
+            i += 1;
+        }
+    }
+    changed
+}
+
+/// The indices of the `n` instructions starting at `start`, if nothing but `.line` directives lie
+/// between them
+fn window(code: &[Instruction], start: usize, n: usize) -> Option<Vec<usize>> {
+    let mut indices = Vec::with_capacity(n);
+    let mut i = start;
+    while indices.len() < n {
+        match code.get(i)? {
+            Instruction::Op(..) => indices.push(i),
+            Instruction::Line(_) => {}
+            _ => return None,
+        }
+        i += 1;
+    }
+    Some(indices)
+}
+
+/// Removes the instructions at `indices`, which have to be sorted
+fn remove(code: &mut Vec<Instruction>, indices: &[usize]) {
+    for &i in indices.iter().rev() {
+        code.remove(i);
+    }
+}
+
+fn label_operand(instruction: &Instruction) -> Option<Operand> {
+    match instruction.operands() {
+        [label @ Operand::Label(_)] => Some(label.clone()),
+        _ => None,
+    }
+}
+
+/// `Integer.compare`, the bit tricks of a comparison intrinsic and `ifne`/`ifeq` become a single
+/// `if_icmp<cond>`. After `lcmp` or `dcmp<op>`, they become the `if<cond>` that compares its result to 0.
+fn fuse_comparison(code: &mut Vec<Instruction>, i: usize) -> bool {
+    let integers = code[i].opcode() == Some(Opcode::InvokeStatic)
+        && code[i].operands() == [Operand::Symbol(INTEGER_COMPARE.into())];
+    let wide = matches!(code[i].opcode(), Some(Opcode::LCmp | Opcode::DCmpL | Opcode::DCmpG));
+    if !integers && !wide {
+        return false;
+    }
+    for (tail, fused) in COMPARISONS {
+        let Some(indices) = window(code, i, tail.len() + 2) else {
+            continue;
+        };
+        let matches_tail = tail.iter().zip(&indices[1..]).all(|((opcode, operand), &j)| {
+            code[j].opcode() == Some(*opcode)
+                && code[j].operands() == operand.map(Operand::Int).as_slice()
+        });
+        let jump = &code[*indices.last().unwrap()];
+        let fused = match jump.opcode() {
+            Some(Opcode::IfNe) => fused,
+            Some(Opcode::IfEq) => fused.negated().unwrap(),
+            _ => continue,
+        };
+        let Some(target) = label_operand(jump).filter(|_| matches_tail) else {
+            continue;
+        };
+        if wide {
+            // `lcmp` or `dcmp<op>` stays, its result is compared to 0
+            let last = *indices.last().unwrap();
+            code[last] = Instruction::Op(fused.against_zero().unwrap(), vec![target]);
+            remove(code, &indices[1..indices.len() - 1]);
+        } else {
+            code[i] = Instruction::Op(fused, vec![target]);
+            remove(code, &indices[1..]);
+        }
+        return true;
+    }
+    false
+}
+
+/// `if<cond> A`, `goto B`, `A:` becomes `if<!cond> B`, `A:`
+fn invert_jump(code: &mut Vec<Instruction>, i: usize) -> bool {
+    let Some(indices) = window(code, i, 2) else {
+        return false;
+    };
+    let (Some(negated), Some(Operand::Label(target)), Some(Opcode::Goto), Some(other_target)) = (
+        code[i].opcode().and_then(Opcode::negated),
+        label_operand(&code[i]),
+        code[indices[1]].opcode(),
+        label_operand(&code[indices[1]]),
+    ) else {
+        return false;
+    };
+    if !next_labels(code, indices[1] + 1).contains(&target) {
+        return false;
+    }
+    code[i] = Instruction::Op(negated, vec![other_target]);
+    remove(code, &indices[1..]);
+    true
+}
+
+/// `goto A` right before `A:` is removed
+fn remove_jump_to_next(code: &mut Vec<Instruction>, i: usize) -> bool {
+    match (code[i].opcode(), label_operand(&code[i])) {
+        (Some(Opcode::Goto), Some(Operand::Label(target)))
+            if next_labels(code, i + 1).contains(&target) =>
+        {
+            code.remove(i);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The labels of the instruction that follows `start`
+fn next_labels(code: &[Instruction], start: usize) -> Vec<Label> {
+    let mut labels = Vec::new();
+    for instruction in &code[start.min(code.len())..] {
+        match instruction {
+            Instruction::Label(label) => labels.push(*label),
+            Instruction::Frame { .. } | Instruction::Line(_) => {}
+            _ => break,
+        }
+    }
+    labels
+}
+
+/// Pairs of instructions that undo each other are removed; a store followed by a load of the same
+/// variable keeps the value on the stack with `dup` instead, which needs as many more stack slots
+/// as the value takes up
+fn remove_pair(code: &mut Vec<Instruction>, i: usize, grown: &mut usize) -> bool {
+    let Some(indices) = window(code, i, 2) else {
+        return false;
+    };
+    let (first, second) = (&code[i], &code[indices[1]]);
+    let (Some(a), Some(b)) = (first.opcode(), second.opcode()) else {
+        return false;
+    };
+    let same_var = first.operands() == second.operands();
+    let removable = (PUSHES.contains(&a) && b == Opcode::Pop)
+        || (WIDE_PUSHES.contains(&a) && b == Opcode::Pop2)
+        || (a == Opcode::Swap && b == Opcode::Swap)
+        || (same_var && LOADS_STORES.iter().any(|&(load, store, _)| (a, b) == (load, store)));
+    if removable {
+        remove(code, &indices);
+        return true;
+    }
+    let dup = LOADS_STORES
+        .iter()
+        .find(|&&(load, store, _)| (a, b) == (store, load))
+        .map(|&(_, _, dup)| dup);
+    if let Some(dup) = dup.filter(|_| same_var) {
+        code.remove(indices[1]);
+        code.insert(i, Instruction::op(dup));
+        *grown = (*grown).max(if dup == Opcode::Dup2 { 2 } else { 1 });
+        return true;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Opcode::*;
+
+    fn label(id: usize) -> Label {
+        Label { id, name: "L" }
+    }
+
+    fn op(opcode: Opcode) -> Instruction {
+        Instruction::op(opcode)
+    }
+
+    fn op_with(opcode: Opcode, operand: Operand) -> Instruction {
+        Instruction::Op(opcode, vec![operand])
+    }
+
+    fn jump(opcode: Opcode, id: usize) -> Instruction {
+        op_with(opcode, Operand::Label(label(id)))
+    }
+
+    /// Optimizes `code` in a method that needs one stack slot; the code and the new `max_stack`
+    fn optimized(code: Vec<Instruction>) -> (Vec<Instruction>, usize) {
+        let mut method = Method::new("main".into(), "()V".into());
+        method.max_stack = 1;
+        method.code = code;
+        optimize(&mut method);
+        (method.code, method.max_stack)
+    }
+
+    #[test]
+    fn comparisons_are_fused() {
+        let compare = op_with(InvokeStatic, Operand::Symbol(INTEGER_COMPARE.into()));
+        // `<` leaves the sign bit of the comparison, `ifeq` jumps if it isn't set
+        let (code, _) = optimized(vec![
+            compare,
+            op_with(BiPush, Operand::Int(31)),
+            op(IUShr),
+            jump(IfEq, 1),
+            Instruction::Label(label(1)),
+        ]);
+        assert_eq!(code, [jump(IfICmpGe, 1), Instruction::Label(label(1))]);
+        // after `lcmp`, the result is compared to 0
+        let (code, _) = optimized(vec![
+            op(LCmp),
+            op(INeg),
+            op_with(BiPush, Operand::Int(31)),
+            op(IUShr),
+            jump(IfNe, 1),
+            Instruction::Label(label(1)),
+        ]);
+        assert_eq!(code, [op(LCmp), jump(IfGt, 1), Instruction::Label(label(1))]);
+    }
+
+    #[test]
+    fn jumps_over_gotos_are_inverted() {
+        let (code, _) = optimized(vec![
+            jump(IfNe, 1),
+            jump(Goto, 2),
+            Instruction::Label(label(1)),
+            op(Return),
+            Instruction::Label(label(2)),
+        ]);
+        assert_eq!(
+            code,
+            [jump(IfEq, 2), Instruction::Label(label(1)), op(Return), Instruction::Label(label(2))]
+        );
+    }
+
+    #[test]
+    fn jumps_to_the_next_instruction_are_removed() {
+        let frame = Instruction::Frame { locals: Vec::new(), stack: Vec::new() };
+        let (code, _) = optimized(vec![jump(Goto, 1), Instruction::Label(label(1)), frame.clone(), op(Return)]);
+        assert_eq!(code, [Instruction::Label(label(1)), frame, op(Return)]);
+    }
+
+    #[test]
+    fn pairs_that_undo_each_other_are_removed() {
+        let (code, max_stack) = optimized(vec![
+            op(Dup),
+            op(Pop),
+            op(DConst1),
+            op(Pop2),
+            op(Swap),
+            Instruction::Line(3),
+            op(Swap),
+            op_with(ILoad, Operand::Local(1)),
+            op_with(IStore, Operand::Local(1)),
+            op(Return),
+        ]);
+        assert_eq!(code, [Instruction::Line(3), op(Return)]);
+        assert_eq!(max_stack, 1);
+        // a load stored into another variable stays
+        let copy = vec![op_with(ILoad, Operand::Local(1)), op_with(IStore, Operand::Local(2))];
+        assert_eq!(optimized(copy.clone()).0, copy);
+    }
+
+    #[test]
+    fn store_and_load_become_dup() {
+        let (code, max_stack) = optimized(vec![
+            op_with(IStore, Operand::Local(1)),
+            op_with(ILoad, Operand::Local(1)),
+            op(Return),
+        ]);
+        assert_eq!(code, [op(Dup), op_with(IStore, Operand::Local(1)), op(Return)]);
+        assert_eq!(max_stack, 2);
+        // a double takes two more slots, however many stores are rewritten
+        let (code, max_stack) = optimized(vec![
+            op_with(DStore, Operand::Local(1)),
+            op_with(DLoad, Operand::Local(1)),
+            op_with(IStore, Operand::Local(3)),
+            op_with(ILoad, Operand::Local(3)),
+        ]);
+        assert_eq!(
+            code,
+            [
+                op(Dup2),
+                op_with(DStore, Operand::Local(1)),
+                op(Dup),
+                op_with(IStore, Operand::Local(3)),
+            ]
+        );
+        assert_eq!(max_stack, 3);
+        // a label in between may be jumped to with another value
+        let code = vec![
+            op_with(IStore, Operand::Local(1)),
+            Instruction::Label(label(1)),
+            op_with(ILoad, Operand::Local(1)),
+        ];
+        assert_eq!(optimized(code.clone()), (code, 1));
+    }
+
+    #[test]
+    fn catches_of_removed_code_are_removed() {
+        let catch = |from, to| Instruction::Catch {
+            class: "java/lang/Exception".into(),
+            from: label(from),
+            to: label(to),
+            handler: label(5),
+        };
+        let (code, _) = optimized(vec![
+            catch(1, 2),
+            catch(3, 4),
+            Instruction::Label(label(1)),
+            op(Dup),
+            op(Pop),
+            Instruction::Label(label(2)),
+            Instruction::Label(label(3)),
+            op(Return),
+            Instruction::Label(label(4)),
+            Instruction::Label(label(5)),
+            op(Return),
+        ]);
+        assert!(!code.contains(&catch(1, 2)), "{code:?}");
+        assert!(code.contains(&catch(3, 4)), "{code:?}");
+    }
+}
@@ -0,0 +1,190 @@
+//! `jack repl`: analyzes and interprets one input after the other, keeping the stack, variables,
+//! functions and macros in between, and shows the typed stack after every input.
+
+use std::{
+    io::Write,
+    path::PathBuf,
+};
+
+use crate::{
+    analyzer::AstAnalysis,
+    diagnostics::{Diagnostic, ErrorFormat},
+    interp::{InterpErr, Interpreter, Map, Record, Value},
+    macr::Preprocessor,
+    parser::{parse, ParserErr},
+};
+
+/// The path that inputs are read from as far as locations and includes are concerned
+const INPUT_PATH: &str = "<repl>";
+
+pub struct Repl<'a> {
+    preprocessor: Preprocessor<'a>,
+    analysis: AstAnalysis,
+    interpreter: Interpreter,
+    debug: bool,
+    format: ErrorFormat,
+}
+
+/// What became of an input
+enum Outcome {
+    Done,
+    /// The input ended in the middle of something, like a block
+    Incomplete,
+    /// The errors have been reported
+    Failed,
+    Exit(i32),
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(include_dirs: &'a [PathBuf], debug: bool, format: ErrorFormat) -> Self {
+        Self {
+            preprocessor: Preprocessor::new(include_dirs),
+            analysis: AstAnalysis::new(),
+            interpreter: Interpreter::new(),
+            debug,
+            format,
+        }
+    }
+
+    /// Reads inputs from stdin until EOF or `exit`; returns the exit status. Inputs that end in
+    /// the middle of a block continue on the next line.
+    pub fn run(&mut self) -> i32 {
+        let mut input = String::new();
+        loop {
+            print!("{}", if input.is_empty() { "> " } else { ". " });
+            let _ = std::io::stdout().flush();
+            let mut line = String::new();
+            // not locked for longer, `readln` reads from stdin too
+            match std::io::stdin().read_line(&mut line) {
+                Ok(0) => return 0,
+                Ok(_) => input.push_str(&line),
+                Err(e) => {
+                    eprintln!("cannot read from stdin: {e}");
+                    return 1;
+                }
+            }
+            if input.trim().is_empty() {
+                input.clear();
+                continue;
+            }
+            match self.eval(&input) {
+                Outcome::Done | Outcome::Failed => input.clear(),
+                Outcome::Incomplete => {}
+                Outcome::Exit(status) => return status,
+            }
+        }
+    }
+
+    /// Runs the input and shows the stack afterwards. Inputs with errors don't change anything,
+    /// not even the lists, maps and records they modified before failing.
+    fn eval(&mut self, input: &str) -> Outcome {
+        let snapshot = (
+            self.preprocessor.clone(),
+            self.analysis.clone(),
+            self.interpreter.deep_clone(),
+        );
+        let outcome = self.try_eval(input).unwrap_or_else(|diagnostics| {
+            for d in &diagnostics {
+                self.report(d);
+            }
+            Outcome::Failed
+        });
+        if !matches!(outcome, Outcome::Done) {
+            (self.preprocessor, self.analysis, self.interpreter) = snapshot;
+        }
+        outcome
+    }
+
+    fn try_eval(&mut self, input: &str) -> Result<Outcome, Vec<Diagnostic>> {
+        let file_index = self.preprocessor.files().len();
+        let tokens = self
+            .preprocessor
+            .process(INPUT_PATH.into(), input.into(), self.debug)
+            .map_err(|e| vec![e.diagnostic()])?;
+        let mut ast = match parse(tokens.into_iter().map(Ok), file_index) {
+            Ok(ast) => ast,
+            Err(ParserErr::UnexpectedEOF(..)) => return Ok(Outcome::Incomplete),
+            Err(e) => return Err(vec![e.diagnostic()]),
+        };
+        let analyzed = ast.analyze_with(&mut self.analysis, self.debug);
+        for warning in self.analysis.warnings.take() {
+            self.report(&warning);
+        }
+        analyzed.map_err(|errs| errs.iter().map(|e| e.diagnostic()).collect::<Vec<_>>())?;
+        match self.interpreter.exec_all(&ast) {
+            Ok(()) => {}
+            Err(InterpErr::Exit(status)) => return Ok(Outcome::Exit(status)),
+            Err(e) => return Err(vec![e.diagnostic()]),
+        }
+        self.end_output_line();
+        println!("types:  {:?}", self.analysis.stack);
+        println!("values: {}", repr_list(&self.interpreter.stack));
+        Ok(Outcome::Done)
+    }
+
+    fn report(&mut self, d: &Diagnostic) {
+        self.end_output_line();
+        println!("{}", d.render(self.preprocessor.files(), self.format));
+    }
+
+    /// Moves what comes next to its own line if the program printed half a line
+    fn end_output_line(&mut self) {
+        if !self.interpreter.at_line_start {
+            println!();
+            self.interpreter.at_line_start = true;
+        }
+    }
+}
+
+/// Like the value is written in Jack
+fn repr(value: &Value) -> String {
+    match value {
+        Value::Int(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Long(n) => format!("{n}L"),
+        Value::Double(d) => format!("{d:?}"),
+        Value::String(s) => format!("{s:?}"),
+        Value::List(xs) => repr_list(&xs.borrow()),
+        Value::Map(map) => repr_map(&map.borrow()),
+        Value::Record(record) => repr_record(&record.borrow()),
+    }
+}
+
+fn repr_list(values: &[Value]) -> String {
+    format!("[{}]", values.iter().map(repr).collect::<Vec<_>>().join(", "))
+}
+
+/// Maps have no literals, they are written like Java prints them
+fn repr_map(map: &Map) -> String {
+    let entries = map.iter().map(|(key, value)| format!("{}={}", repr(key), repr(value)));
+    format!("{{{}}}", entries.collect::<Vec<_>>().join(", "))
+}
+
+/// Records are written like Java prints them, too
+fn repr_record(record: &Record) -> String {
+    let fields = record.fields.iter().map(|(name, value)| format!("{name}={}", repr(value)));
+    format!("{}[{}]", record.name, fields.collect::<Vec<_>>().join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn failed_inputs_leave_lists_alone() {
+        let include_dirs = [PathBuf::from(env!("CARGO_MANIFEST_DIR"))];
+        let mut repl = Repl::new(&include_dirs, false, ErrorFormat::Human);
+        assert!(matches!(repl.eval("#include std.jack"), Outcome::Done));
+        assert!(matches!(repl.eval("l = [1] m = l"), Outcome::Done));
+        assert!(matches!(repl.eval("l 2 push \"boom\" throw"), Outcome::Failed));
+        let vars = &repl.interpreter.vars;
+        let (Some(Value::List(l)), Some(Value::List(m))) = (vars.get("l"), vars.get("m")) else {
+            panic!("{vars:?}");
+        };
+        assert_eq!(*l.borrow(), [Value::Int(1)]);
+        // still the same list
+        assert!(Rc::ptr_eq(l, m));
+    }
+}